@@ -0,0 +1,353 @@
+use std::cmp;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+
+use crypto::aead::AeadDecryptor;
+use crypto::aead::AeadEncryptor;
+use crypto::chacha20poly1305::ChaCha20Poly1305;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::scrypt;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+
+/// Encrypted stream: magic, random salt, then chunks of ciphertext each followed by its tag
+/// Each stream is encrypted with its own key HMAC(encryption key, salt), the nonce of a chunk is its number
+/// The last chunk is authenticated as the last one, so a truncated stream is detected
+const MAGIC: &'static [u8] = b"YENC1";
+const SALT_LENGTH: usize = 24;
+const CHUNK_LENGTH: usize = 64 * 1024;
+const TAG_LENGTH: usize = 16;
+
+const CHUNK_AAD: &'static [u8] = &[0];
+const LAST_CHUNK_AAD: &'static [u8] = &[1];
+
+/// Master key of an encrypted repository
+#[derive(Clone)]
+pub struct Key {
+	/// Used to encrypt the objects and the metadata
+	encryption_key: Vec<u8>,
+	/// Used to name the objects, so the remote cannot recognize a known file
+	name_key: Vec<u8>
+}
+
+pub const KEY_LENGTH: usize = 64;
+
+impl Key {
+	pub fn from_bytes(bytes: &[u8]) -> Key {
+		assert!(bytes.len() == KEY_LENGTH);
+		Key {encryption_key: bytes[..32].to_vec(), name_key: bytes[32..].to_vec()}
+	}
+	pub fn generate() -> Key {
+		Key::from_bytes(&random_bytes(KEY_LENGTH))
+	}
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut bytes = self.encryption_key.clone();
+		bytes.extend_from_slice(&self.name_key);
+		bytes
+	}
+	pub fn get_name_key(&self) -> &[u8] {
+		&self.name_key
+	}
+}
+
+pub fn random_bytes(length: usize) -> Vec<u8> {
+	let mut bytes = vec![0; length];
+	File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut bytes)).expect("Cannot read random bytes");
+	bytes
+}
+
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+	let mut hmac = Hmac::new(Sha256::new(), key);
+	hmac.input(data);
+	hmac.result().code().to_vec()
+}
+
+/// Memory-hard derivation of a 32 bytes key from a passphrase
+pub fn derive_key(passphrase: &str, salt: &[u8], log_n: u8, r: u32, p: u32) -> Vec<u8> {
+	let mut derived_key = vec![0; 32];
+	scrypt::scrypt(passphrase.as_bytes(), salt, &scrypt::ScryptParams::new(log_n, r, p), &mut derived_key);
+	derived_key
+}
+
+/// Encrypt a small secret in one block (nonce, ciphertext, tag)
+pub fn seal(key: &[u8], plaintext: &[u8]) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+	let nonce = random_bytes(8);
+	let mut ciphertext = vec![0; plaintext.len()];
+	let mut tag = vec![0; TAG_LENGTH];
+	ChaCha20Poly1305::new(key, &nonce, &[]).encrypt(plaintext, &mut ciphertext, &mut tag);
+	(nonce, ciphertext, tag)
+}
+
+/// Decrypt a secret sealed with seal, None if the key is wrong or the data altered
+pub fn open(key: &[u8], nonce: &[u8], ciphertext: &[u8], tag: &[u8]) -> Option<Vec<u8>> {
+	let mut plaintext = vec![0; ciphertext.len()];
+	if ChaCha20Poly1305::new(key, nonce, &[]).decrypt(ciphertext, &mut plaintext, tag) {
+		Some(plaintext)
+	} else {
+		None
+	}
+}
+
+fn get_stream_key(key: &Key, salt: &[u8]) -> Vec<u8> {
+	hmac_sha256(&key.encryption_key, salt)
+}
+
+fn get_chunk_nonce(chunk_number: u64) -> [u8; 8] {
+	let mut nonce = [0; 8];
+	for i in 0..8 {
+		nonce[i] = (chunk_number >> (56 - 8 * i)) as u8;
+	}
+	nonce
+}
+
+/// Encrypt everything written, the stream is completed by finish
+pub struct EncryptWriter<W: Write> {
+	writer: W,
+	stream_key: Vec<u8>,
+	buffer: Vec<u8>,
+	chunk_number: u64
+}
+
+impl <W: Write> EncryptWriter<W> {
+	pub fn new(mut writer: W, key: &Key) -> io::Result<EncryptWriter<W>> {
+		let salt = random_bytes(SALT_LENGTH);
+		writer.write_all(MAGIC)?;
+		writer.write_all(&salt)?;
+		Ok(EncryptWriter {writer: writer, stream_key: get_stream_key(key, &salt), buffer: Vec::with_capacity(CHUNK_LENGTH), chunk_number: 0})
+	}
+
+	fn write_chunk(&mut self, last: bool) -> io::Result<()> {
+		let aad = if last { LAST_CHUNK_AAD } else { CHUNK_AAD };
+		let mut ciphertext = vec![0; self.buffer.len()];
+		let mut tag = [0; TAG_LENGTH];
+		ChaCha20Poly1305::new(&self.stream_key, &get_chunk_nonce(self.chunk_number), aad).encrypt(&self.buffer, &mut ciphertext, &mut tag);
+
+		self.writer.write_all(&ciphertext)?;
+		self.writer.write_all(&tag)?;
+
+		self.buffer.clear();
+		self.chunk_number = self.chunk_number + 1;
+		Ok(())
+	}
+
+	/// Write the last chunk and return the inner writer
+	/// A stream dropped without finish has no last chunk, so it is rejected as truncated when read
+	pub fn finish(mut self) -> io::Result<W> {
+		self.write_chunk(true)?;
+		self.writer.flush()?;
+		Ok(self.writer)
+	}
+}
+
+impl <W: Write> Write for EncryptWriter<W> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		if self.buffer.len() == CHUNK_LENGTH {
+			self.write_chunk(false)?;
+		}
+		let length = cmp::min(buf.len(), CHUNK_LENGTH - self.buffer.len());
+		self.buffer.extend_from_slice(&buf[..length]);
+		Ok(length)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.writer.flush()
+	}
+}
+
+/// Decrypt and authenticate a stream written by EncryptWriter
+/// A read fails with InvalidData if the stream has been altered or truncated
+pub struct DecryptReader<R: Read> {
+	reader: R,
+	stream_key: Vec<u8>,
+	/// Bytes read after the current chunk, they belong to the next one
+	pending: Vec<u8>,
+	chunk: Vec<u8>,
+	position: usize,
+	chunk_number: u64,
+	finished: bool
+}
+
+impl <R: Read> DecryptReader<R> {
+	pub fn new(mut reader: R, key: &Key) -> io::Result<DecryptReader<R>> {
+		let mut header = vec![0; MAGIC.len() + SALT_LENGTH];
+		reader.read_exact(&mut header).map_err(|_| invalid_data("Not an encrypted stream"))?;
+		if !fixed_time_eq(&header[..MAGIC.len()], MAGIC) {
+			return Err(invalid_data("Not an encrypted stream"));
+		}
+
+		let stream_key = get_stream_key(key, &header[MAGIC.len()..]);
+		Ok(DecryptReader {reader: reader, stream_key: stream_key, pending: Vec::new(), chunk: Vec::new(), position: 0, chunk_number: 0, finished: false})
+	}
+
+	/// Read and decrypt the next chunk (a full chunk and its tag, or less for the last one)
+	fn read_chunk(&mut self) -> io::Result<()> {
+		let full_length = CHUNK_LENGTH + TAG_LENGTH;
+
+		//one byte more than a full chunk tells that another chunk follows
+		let mut data = self.pending.clone();
+		data.resize(full_length + 1, 0);
+		let mut length = self.pending.len();
+		while length < data.len() {
+			let n = self.reader.read(&mut data[length..])?;
+			if n == 0 {
+				break;
+			}
+			length = length + n;
+		}
+
+		let last = length <= full_length;
+		let chunk_length = if last { length } else { full_length };
+		if chunk_length < TAG_LENGTH {
+			return Err(invalid_data("Truncated encrypted stream"));
+		}
+		self.pending = data[chunk_length..length].to_vec();
+
+		let aad = if last { LAST_CHUNK_AAD } else { CHUNK_AAD };
+		let (ciphertext, tag) = data[..chunk_length].split_at(chunk_length - TAG_LENGTH);
+		let mut plaintext = vec![0; ciphertext.len()];
+		if !ChaCha20Poly1305::new(&self.stream_key, &get_chunk_nonce(self.chunk_number), aad).decrypt(ciphertext, &mut plaintext, tag) {
+			return Err(invalid_data("Encrypted stream altered, truncated or wrong key"));
+		}
+
+		self.chunk = plaintext;
+		self.position = 0;
+		self.chunk_number = self.chunk_number + 1;
+		self.finished = last;
+		Ok(())
+	}
+}
+
+impl <R: Read> Read for DecryptReader<R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		while self.position == self.chunk.len() {
+			if self.finished {
+				return Ok(0);
+			}
+			self.read_chunk()?;
+		}
+
+		let length = cmp::min(buf.len(), self.chunk.len() - self.position);
+		buf[..length].copy_from_slice(&self.chunk[self.position..self.position + length]);
+		self.position = self.position + length;
+		Ok(length)
+	}
+}
+
+/// Reader on the plaintext of a stream, encrypted only when there is a key
+pub fn decrypt_reader<'a, R: Read + 'a>(reader: R, key: Option<&Key>) -> io::Result<Box<dyn Read + 'a>> {
+	match key {
+		Some(key) => Ok(Box::new(DecryptReader::new(reader, key)?)),
+		None => Ok(Box::new(reader))
+	}
+}
+
+/// Writer encrypting when there is a key, finish completes the stream
+pub enum CipherWriter<W: Write> {
+	Plain(W),
+	Encrypted(EncryptWriter<W>)
+}
+
+impl <W: Write> CipherWriter<W> {
+	pub fn finish(self) -> io::Result<W> {
+		match self {
+			CipherWriter::Plain(mut writer) => {
+				writer.flush()?;
+				Ok(writer)
+			},
+			CipherWriter::Encrypted(writer) => writer.finish()
+		}
+	}
+}
+
+impl <W: Write> Write for CipherWriter<W> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		match *self {
+			CipherWriter::Plain(ref mut writer) => writer.write(buf),
+			CipherWriter::Encrypted(ref mut writer) => writer.write(buf)
+		}
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		match *self {
+			CipherWriter::Plain(ref mut writer) => writer.flush(),
+			CipherWriter::Encrypted(ref mut writer) => writer.flush()
+		}
+	}
+}
+
+pub fn encrypt_writer<W: Write>(writer: W, key: Option<&Key>) -> io::Result<CipherWriter<W>> {
+	match key {
+		Some(key) => Ok(CipherWriter::Encrypted(EncryptWriter::new(writer, key)?)),
+		None => Ok(CipherWriter::Plain(writer))
+	}
+}
+
+fn invalid_data(message: &str) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Read;
+	use std::io::Write;
+
+	use super::CHUNK_LENGTH;
+	use super::Key;
+	use super::decrypt_reader;
+	use super::encrypt_writer;
+	use super::open;
+	use super::random_bytes;
+	use super::seal;
+
+	fn encrypt(content: &[u8], key: Option<&Key>) -> Vec<u8> {
+		let mut writer = encrypt_writer(Vec::new(), key).unwrap();
+		writer.write_all(content).unwrap();
+		writer.finish().unwrap()
+	}
+
+	fn decrypt(stream: &[u8], key: Option<&Key>) -> ::std::io::Result<Vec<u8>> {
+		let mut content = Vec::new();
+		decrypt_reader(stream, key)?.read_to_end(&mut content)?;
+		Ok(content)
+	}
+
+	#[test]
+	fn streams_round_trip_across_chunks() {
+		let key = Key::generate();
+		for &length in [0, 1, CHUNK_LENGTH, CHUNK_LENGTH + 1, 2 * CHUNK_LENGTH + 17].iter() {
+			let content : Vec<u8> = (0..length).map(|i| (i % 251) as u8).collect();
+			let stream = encrypt(&content, Some(&key));
+			assert_eq!(decrypt(&stream, Some(&key)).unwrap(), content);
+		}
+		assert_eq!(encrypt(b"plain", None), b"plain");
+	}
+
+	#[test]
+	fn altered_truncated_or_wrongly_keyed_streams_are_refused() {
+		let key = Key::generate();
+		let content : Vec<u8> = (0..CHUNK_LENGTH + 100).map(|i| i as u8).collect();
+		let stream = encrypt(&content, Some(&key));
+
+		assert!(decrypt(&stream, Some(&Key::generate())).is_err());
+
+		let mut altered = stream.clone();
+		let middle = altered.len() / 2;
+		altered[middle] ^= 1;
+		assert!(decrypt(&altered, Some(&key)).is_err());
+
+		//a stream cut at a chunk boundary has no last chunk
+		assert!(decrypt(&stream[..stream.len() - 116], Some(&key)).is_err());
+		assert!(decrypt(&stream[..stream.len() - 1], Some(&key)).is_err());
+	}
+
+	#[test]
+	fn sealed_data_opens_only_with_its_key() {
+		let key = random_bytes(32);
+		let (nonce, ciphertext, tag) = seal(&key, b"master key");
+		assert_eq!(open(&key, &nonce, &ciphertext, &tag), Some(b"master key".to_vec()));
+		assert_eq!(open(&random_bytes(32), &nonce, &ciphertext, &tag), None);
+	}
+}
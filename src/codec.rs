@@ -0,0 +1,209 @@
+use std::fs::File;
+use std::io;
+use std::io::Cursor;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+
+use flate2;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use zstd;
+
+use config::Codec;
+use config::Config;
+
+/// An object starts with "YC" and the id of its codec (encrypted with the object when there is a key)
+/// The objects written before this header are plain gzip, recognized by the gzip magic
+const HEADER_MAGIC: &'static [u8] = b"YC";
+const GZIP_MAGIC: &'static [u8] = &[0x1f, 0x8b];
+
+/// The trial compresses the beginning of the file, the file is stored raw if it saves less than 5%
+const TRIAL_LENGTH: u64 = 64 * 1024;
+const TRIAL_RATIO: f64 = 0.95;
+
+#[derive(Debug, Clone, Copy)]
+pub struct CodecSettings {
+	codec: Codec,
+	level: i32
+}
+
+impl CodecSettings {
+	pub fn new(codec: Codec, level: Option<i32>) -> CodecSettings {
+		CodecSettings {codec: codec, level: level.unwrap_or(get_default_level(codec))}
+	}
+}
+
+fn get_default_level(codec: Codec) -> i32 {
+	match codec {
+		Codec::RAW => 0,
+		Codec::GZIP => 6,
+		Codec::ZSTD => zstd::DEFAULT_COMPRESSION_LEVEL
+	}
+}
+
+fn get_codec_id(codec: Codec) -> u8 {
+	match codec {
+		Codec::RAW => 0,
+		Codec::GZIP => 1,
+		Codec::ZSTD => 2
+	}
+}
+
+/// flate2 only knows a few levels
+fn get_gzip_compression(level: i32) -> flate2::Compression {
+	match level {
+		1..=3 => flate2::Compression::Fast,
+		4..=6 => flate2::Compression::Default,
+		l if l <= 0 => flate2::Compression::None,
+		_ => flate2::Compression::Best
+	}
+}
+
+/// Codec for a file: the first rule matching its extension, else the codec of the config
+/// unless the trial compression shows that the content does not compress
+pub fn choose_codec(config: &Config, path: &Path) -> CodecSettings {
+	let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+	if let Some(rule) = config.get_compression_rules().iter().find(|rule| rule.matches(extension)) {
+		return CodecSettings::new(rule.get_codec(), rule.get_level());
+	}
+
+	let settings = CodecSettings::new(config.get_codec(), config.get_compression_level());
+	if settings.codec != Codec::RAW && config.get_trial_compression() && !compresses_well(path, settings) {
+		return CodecSettings::new(Codec::RAW, None);
+	}
+	settings
+}
+
+fn compresses_well(path: &Path, settings: CodecSettings) -> bool {
+	let mut sample = Vec::new();
+	if File::open(path).and_then(|f| f.take(TRIAL_LENGTH).read_to_end(&mut sample)).is_err() || sample.is_empty() {
+		return true;
+	}
+
+	match compress(&sample, settings) {
+		Ok(compressed) => (compressed.len() as f64) < (sample.len() as f64) * TRIAL_RATIO,
+		Err(_) => true
+	}
+}
+
+fn compress(content: &[u8], settings: CodecSettings) -> io::Result<Vec<u8>> {
+	let mut writer = CodecWriter::new(Vec::new(), settings)?;
+	writer.write_all(content)?;
+	writer.finish()
+}
+
+/// Write the codec header then the compressed content, the stream is completed by finish
+pub enum CodecWriter<W: Write> {
+	Raw(W),
+	Gzip(GzEncoder<W>),
+	Zstd(zstd::Encoder<W>)
+}
+
+impl <W: Write> CodecWriter<W> {
+	pub fn new(mut writer: W, settings: CodecSettings) -> io::Result<CodecWriter<W>> {
+		writer.write_all(HEADER_MAGIC)?;
+		writer.write_all(&[get_codec_id(settings.codec)])?;
+		Ok(match settings.codec {
+			Codec::RAW => CodecWriter::Raw(writer),
+			Codec::GZIP => CodecWriter::Gzip(GzEncoder::new(writer, get_gzip_compression(settings.level))),
+			Codec::ZSTD => CodecWriter::Zstd(zstd::Encoder::new(writer, settings.level)?)
+		})
+	}
+
+	pub fn finish(self) -> io::Result<W> {
+		match self {
+			CodecWriter::Raw(writer) => Ok(writer),
+			CodecWriter::Gzip(encoder) => encoder.finish(),
+			CodecWriter::Zstd(encoder) => encoder.finish()
+		}
+	}
+}
+
+impl <W: Write> Write for CodecWriter<W> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		match *self {
+			CodecWriter::Raw(ref mut writer) => writer.write(buf),
+			CodecWriter::Gzip(ref mut encoder) => encoder.write(buf),
+			CodecWriter::Zstd(ref mut encoder) => encoder.write(buf)
+		}
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		match *self {
+			CodecWriter::Raw(ref mut writer) => writer.flush(),
+			CodecWriter::Gzip(ref mut encoder) => encoder.flush(),
+			CodecWriter::Zstd(ref mut encoder) => encoder.flush()
+		}
+	}
+}
+
+/// Reader on the uncompressed content of an object, whatever its codec
+pub fn decoder<'a, R: Read + 'a>(mut reader: R) -> io::Result<Box<dyn Read + 'a>> {
+	let mut header = vec![0; HEADER_MAGIC.len() + 1];
+	reader.read_exact(&mut header)?;
+
+	if &header[..2] == GZIP_MAGIC {
+		return Ok(Box::new(GzDecoder::new(Cursor::new(header).chain(reader))?));
+	}
+	if &header[..2] != HEADER_MAGIC {
+		return Err(io::Error::new(io::ErrorKind::InvalidData, "Unknown object format"));
+	}
+
+	match header[2] {
+		0 => Ok(Box::new(reader)),
+		1 => Ok(Box::new(GzDecoder::new(reader)?)),
+		2 => Ok(Box::new(zstd::Decoder::new(reader)?)),
+		id => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unknown codec {}, this object was written by a newer Yabs", id)))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Read;
+	use std::io::Write;
+
+	use flate2;
+	use flate2::write::GzEncoder;
+
+	use config::Codec;
+	use super::CodecSettings;
+	use super::CodecWriter;
+	use super::decoder;
+
+	fn encode(content: &[u8], codec: Codec) -> Vec<u8> {
+		let mut writer = CodecWriter::new(Vec::new(), CodecSettings::new(codec, None)).unwrap();
+		writer.write_all(content).unwrap();
+		writer.finish().unwrap()
+	}
+
+	fn decode(object: &[u8]) -> Vec<u8> {
+		let mut content = Vec::new();
+		decoder(object).unwrap().read_to_end(&mut content).unwrap();
+		content
+	}
+
+	#[test]
+	fn every_codec_round_trips() {
+		let content : Vec<u8> = (0..100000u32).map(|i| (i % 13) as u8).collect();
+		for &codec in [Codec::RAW, Codec::GZIP, Codec::ZSTD].iter() {
+			let object = encode(&content, codec);
+			assert_eq!(&object[..2], b"YC");
+			assert_eq!(decode(&object), content);
+		}
+		assert_eq!(decode(&encode(b"", Codec::ZSTD)), b"");
+	}
+
+	#[test]
+	fn objects_without_header_are_read_as_gzip() {
+		let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::Default);
+		encoder.write_all(b"written before the codec header").unwrap();
+		assert_eq!(decode(&encoder.finish().unwrap()), b"written before the codec header");
+	}
+
+	#[test]
+	fn unknown_codecs_are_refused() {
+		assert!(decoder(&b"YC\x09content"[..]).is_err());
+		assert!(decoder(&b"XXXcontent"[..]).is_err());
+	}
+}
@@ -1,10 +1,13 @@
+use std::collections::HashMap;
 use std::path::Path;
 use std::path::PathBuf;
 use std::fs::File;
 use std::io::prelude::*;
 use rustc_serialize::json;
 
-#[derive(Debug, RustcEncodable, RustcDecodable)]
+use metadata;
+
+#[derive(Debug, RustcEncodable, RustcDecodable, Clone)]
 pub struct Config {
 	local_metadata_path: String,
 	remote_metadata_path: String,
@@ -14,7 +17,101 @@ pub struct Config {
 	ssh_address: String,
 	ssh_user: String,
 	ssh_password: String,
-	ssh_root_path: String
+	ssh_root_path: String,
+	remote_url: Option<String>,
+	s3_region: Option<String>,
+	s3_access_key: Option<String>,
+	s3_secret_key: Option<String>,
+	nb_transfers: Option<usize>,
+	limit_rate: Option<String>,
+	rate_schedule: Option<Vec<RateWindow>>,
+	pack_threshold: Option<u64>,
+	codec: Option<Codec>,
+	compression_level: Option<i32>,
+	compression_rules: Option<Vec<CompressionRule>>,
+	trial_compression: Option<bool>,
+	metadata_format: Option<MetadataFormat>,
+	metadata_generations: Option<usize>,
+	remote_lock_lease: Option<i64>,
+	prune_keep_revisions: Option<usize>,
+	prune_keep_days: Option<i64>,
+	sparse_include: Option<Vec<String>>,
+	sparse_exclude: Option<Vec<String>>,
+	sources: Option<Vec<Source>>,
+	watch_debounce: Option<u64>,
+	watch_rescan_interval: Option<u64>,
+	watch_push: Option<bool>,
+	jobs: Option<Vec<Job>>,
+	/// Shell command of each hook, by name (pre-commit, post-commit, pre-update, post-update, pre-push, post-push, on-conflict, on-error)
+	hooks: Option<HashMap<String, String>>
+}
+
+/// Job run by the daemon every period, at the given hour (local time) for the daily and longer periods
+#[derive(Debug, RustcEncodable, RustcDecodable, Clone)]
+pub struct Job {
+	command: JobCommand,
+	every: JobPeriod,
+	hour: Option<u32>
+}
+
+#[derive(Debug, RustcEncodable, RustcDecodable, Clone, Copy, PartialEq)]
+pub enum JobCommand {
+	COMMIT,
+	PUSH,
+	SYNC,
+	VERIFY,
+	GC,
+	REPACK,
+	PRUNE
+}
+
+#[derive(Debug, RustcEncodable, RustcDecodable, Clone, Copy, PartialEq)]
+pub enum JobPeriod {
+	HOURLY,
+	DAILY,
+	WEEKLY,
+	MONTHLY
+}
+
+/// Directory backed up in the repository, its files are named <name>/<path in the root> in the metadata
+/// The ignore rules are "*.ext" for the file names ending with .ext, else a path in the root or a file or directory name
+#[derive(Debug, RustcEncodable, RustcDecodable, Clone)]
+pub struct Source {
+	name: String,
+	root: String,
+	ignore: Option<Vec<String>>
+}
+
+/// Codec and level used for the files with one of the extensions (jpg, mp4...)
+#[derive(Debug, RustcEncodable, RustcDecodable, Clone)]
+pub struct CompressionRule {
+	extensions: Vec<String>,
+	codec: Codec,
+	level: Option<i32>
+}
+
+/// Bandwidth cap applied between two hours of the day (local time)
+/// The window wraps around midnight when from_hour is greater than to_hour
+#[derive(Debug, RustcEncodable, RustcDecodable, Clone)]
+pub struct RateWindow {
+	from_hour: u32,
+	to_hour: u32,
+	limit_rate: String
+}
+
+/// Compression of the store objects, RAW stores the content as is
+#[derive(Debug, RustcEncodable, RustcDecodable, Clone, Copy, PartialEq)]
+pub enum Codec {
+	RAW,
+	GZIP,
+	ZSTD
+}
+
+/// JSON rewrites the whole file on each commit, LOG appends the new revisions
+#[derive(Debug, RustcEncodable, RustcDecodable, Clone, Copy, PartialEq)]
+pub enum MetadataFormat {
+	JSON,
+	LOG
 }
 
 #[derive(Debug, RustcEncodable, RustcDecodable, Clone)]
@@ -36,6 +133,37 @@ impl Config {
 			ssh_user: String::from("root"),
 			ssh_password: String::from("password"),
 			ssh_root_path: String::from("/root/Yabs"),
+			remote_url: Some(String::from("ssh://127.0.0.1:22/root/Yabs")),
+			s3_region: None,
+			s3_access_key: None,
+			s3_secret_key: None,
+			nb_transfers: Some(1),
+			limit_rate: None,
+			rate_schedule: None,
+			pack_threshold: Some(32 * 1024),
+			codec: Some(Codec::ZSTD),
+			compression_level: None,
+			compression_rules: Some(vec![CompressionRule::new_raw(&["jpg", "jpeg", "png", "gif", "webp", "heic", "mp3", "ogg", "flac", "aac", "mp4", "mkv", "avi", "mov", "webm",
+				"zip", "gz", "tgz", "bz2", "xz", "zst", "7z", "rar", "jar", "docx", "xlsx", "pptx", "odt", "pdf"])]),
+			trial_compression: Some(true),
+			metadata_format: Some(MetadataFormat::LOG),
+			metadata_generations: Some(5),
+			remote_lock_lease: Some(600),
+			prune_keep_revisions: Some(10),
+			prune_keep_days: Some(90),
+			sparse_include: None,
+			sparse_exclude: None,
+			sources: None,
+			watch_debounce: Some(5),
+			watch_rescan_interval: Some(3600),
+			watch_push: Some(false),
+			jobs: Some(vec![
+				Job {command: JobCommand::COMMIT, every: JobPeriod::HOURLY, hour: None},
+				Job {command: JobCommand::PUSH, every: JobPeriod::DAILY, hour: Some(2)},
+				Job {command: JobCommand::VERIFY, every: JobPeriod::WEEKLY, hour: Some(3)},
+				Job {command: JobCommand::PRUNE, every: JobPeriod::MONTHLY, hour: Some(4)}
+			]),
+			hooks: None,
 		}
 	}
 	pub fn get_local_metadata_path(&self) -> PathBuf {
@@ -65,6 +193,200 @@ impl Config {
 	pub fn get_ssh_root_path(&self) -> PathBuf {
 		PathBuf::from(&self.ssh_root_path)
 	}
+	/// Url of the remote (file:///mnt/backup, sftp://host:22/path, ssh://host:22/path)
+	/// Old config files without url fall back to the ssh_* fields
+	pub fn get_remote_url(&self) -> String {
+		match self.remote_url {
+			Some(ref url) => url.clone(),
+			None => format!("ssh://{}@{}{}", self.ssh_user, self.ssh_address, self.ssh_root_path)
+		}
+	}
+	pub fn get_s3_region(&self) -> String {
+		self.s3_region.clone().unwrap_or(String::from("us-east-1"))
+	}
+	pub fn get_s3_access_key(&self) -> String {
+		self.s3_access_key.clone().unwrap_or(String::new())
+	}
+	pub fn get_s3_secret_key(&self) -> String {
+		self.s3_secret_key.clone().unwrap_or(String::new())
+	}
+	/// Number of files transferred at the same time, each one with its own connection
+	pub fn get_nb_transfers(&self) -> usize {
+		self.nb_transfers.unwrap_or(1)
+	}
+	pub fn set_nb_transfers(&mut self, nb_transfers: usize) {
+		self.nb_transfers = Some(nb_transfers);
+	}
+	pub fn get_limit_rate(&self) -> Option<String> {
+		self.limit_rate.clone()
+	}
+	pub fn get_rate_schedule(&self) -> Vec<RateWindow> {
+		self.rate_schedule.clone().unwrap_or(Vec::new())
+	}
+	/// Objects smaller than this (once compressed) are stored in pack files
+	pub fn get_pack_threshold(&self) -> u64 {
+		self.pack_threshold.unwrap_or(32 * 1024)
+	}
+	/// Old config files without codec keep gzip
+	pub fn get_codec(&self) -> Codec {
+		self.codec.unwrap_or(Codec::GZIP)
+	}
+	/// None for the default level of the codec
+	pub fn get_compression_level(&self) -> Option<i32> {
+		self.compression_level
+	}
+	pub fn get_compression_rules(&self) -> Vec<CompressionRule> {
+		self.compression_rules.clone().unwrap_or(Vec::new())
+	}
+	/// Compress the beginning of each file to store raw the content which does not compress
+	pub fn get_trial_compression(&self) -> bool {
+		self.trial_compression.unwrap_or(true)
+	}
+	/// Format of the new metadata files, an existing file keeps its format
+	pub fn get_metadata_format(&self) -> MetadataFormat {
+		self.metadata_format.unwrap_or(MetadataFormat::JSON)
+	}
+	/// Number of previous metadata files kept (local-metadata.json.1, .2...)
+	/// A json file gets one at each write, a log at each compact-metadata
+	pub fn get_metadata_generations(&self) -> usize {
+		self.metadata_generations.unwrap_or(5)
+	}
+	/// Seconds after which the lock of a writer which died can be taken over
+	pub fn get_remote_lock_lease(&self) -> i64 {
+		self.remote_lock_lease.unwrap_or(600)
+	}
+	/// Number of last revisions of each file kept by prune
+	pub fn get_prune_keep_revisions(&self) -> usize {
+		self.prune_keep_revisions.unwrap_or(10)
+	}
+	pub fn set_prune_keep_revisions(&mut self, keep_revisions: usize) {
+		self.prune_keep_revisions = Some(keep_revisions);
+	}
+	/// Days during which prune keeps all the revisions of a file
+	pub fn get_prune_keep_days(&self) -> i64 {
+		self.prune_keep_days.unwrap_or(90)
+	}
+	pub fn set_prune_keep_days(&mut self, keep_days: i64) {
+		self.prune_keep_days = Some(keep_days);
+	}
+	/// Paths materialized on this machine, all the files when None and none when empty
+	/// The paths are relative to the data path, or start with the source name when there are sources
+	pub fn get_sparse_include(&self) -> Option<Vec<String>> {
+		self.sparse_include.clone()
+	}
+	pub fn set_sparse_include(&mut self, paths: Option<Vec<String>>) {
+		self.sparse_include = paths;
+	}
+	/// Paths never materialized on this machine, even under an include path
+	pub fn get_sparse_exclude(&self) -> Vec<String> {
+		self.sparse_exclude.clone().unwrap_or(Vec::new())
+	}
+	pub fn set_sparse_exclude(&mut self, paths: Vec<String>) {
+		self.sparse_exclude = Some(paths);
+	}
+	/// Seconds without event before watch commits the changed files
+	pub fn get_watch_debounce(&self) -> u64 {
+		self.watch_debounce.unwrap_or(5)
+	}
+	/// Seconds between two full scans of watch, for the events missed by inotify
+	pub fn get_watch_rescan_interval(&self) -> u64 {
+		self.watch_rescan_interval.unwrap_or(3600)
+	}
+	/// Push the objects and the metadata to the remote after each commit of watch
+	pub fn get_watch_push(&self) -> bool {
+		self.watch_push.unwrap_or(false)
+	}
+	/// Jobs of the daemon, none for the old config files
+	pub fn get_jobs(&self) -> Vec<Job> {
+		self.jobs.clone().unwrap_or(Vec::new())
+	}
+	pub fn get_hook(&self, name: &str) -> Option<String> {
+		self.hooks.as_ref().and_then(|hooks| hooks.get(name)).cloned()
+	}
+	/// Without sources, the data path is the only source, named after itself so the files keep their names
+	pub fn get_sources(&self) -> Vec<Source> {
+		match self.sources {
+			Some(ref sources) if !sources.is_empty() => sources.clone(),
+			_ => {
+				let data_path = self.data_path.trim_end_matches('/').to_string();
+				vec![Source {name: data_path.clone(), root: data_path, ignore: None}]
+			}
+		}
+	}
+}
+
+impl Job {
+	/// Name of the job in the status of the daemon
+	pub fn get_name(&self) -> String {
+		format!("{:?} {:?}", self.command, self.every).to_lowercase()
+	}
+	pub fn get_command(&self) -> JobCommand {
+		self.command
+	}
+	/// Seconds between two runs
+	pub fn get_period(&self) -> i64 {
+		match self.every {
+			JobPeriod::HOURLY => 3600,
+			JobPeriod::DAILY => 24 * 3600,
+			JobPeriod::WEEKLY => 7 * 24 * 3600,
+			JobPeriod::MONTHLY => 30 * 24 * 3600
+		}
+	}
+	/// The hour is ignored for the hourly jobs
+	pub fn get_hour(&self) -> Option<u32> {
+		match self.every {
+			JobPeriod::HOURLY => None,
+			_ => self.hour
+		}
+	}
+}
+
+impl Source {
+	pub fn get_name(&self) -> &str {
+		&self.name
+	}
+	pub fn get_root(&self) -> PathBuf {
+		PathBuf::from(&self.root)
+	}
+	/// relative is the path of a file in the root
+	pub fn is_ignored(&self, relative: &Path) -> bool {
+		self.ignore.iter().flat_map(|rules| rules.iter()).any(|rule| {
+			if rule.starts_with('*') {
+				relative.file_name().and_then(|n| n.to_str()).map_or(false, |n| n.ends_with(&rule[1..]))
+			} else {
+				let rule = rule.trim_start_matches("./").trim_end_matches('/');
+				relative.starts_with(rule) || relative.iter().any(|c| c.to_str() == Some(rule))
+			}
+		})
+	}
+}
+
+impl CompressionRule {
+	fn new_raw(extensions: &[&str]) -> CompressionRule {
+		CompressionRule {extensions: extensions.iter().map(|e| e.to_string()).collect(), codec: Codec::RAW, level: None}
+	}
+	pub fn matches(&self, extension: &str) -> bool {
+		self.extensions.iter().any(|e| e.eq_ignore_ascii_case(extension))
+	}
+	pub fn get_codec(&self) -> Codec {
+		self.codec
+	}
+	pub fn get_level(&self) -> Option<i32> {
+		self.level
+	}
+}
+
+impl RateWindow {
+	pub fn contains_hour(&self, hour: u32) -> bool {
+		if self.from_hour <= self.to_hour {
+			hour >= self.from_hour && hour < self.to_hour
+		} else {
+			hour >= self.from_hour || hour < self.to_hour
+		}
+	}
+	pub fn get_limit_rate(&self) -> String {
+		self.limit_rate.clone()
+	}
 }
 
 pub fn create_default_config_file<P: AsRef<Path>>(path: P) {
@@ -83,6 +405,12 @@ pub fn create_default_config_file<P: AsRef<Path>>(path: P) {
     file.sync_all();
 }
 
+/// Save a config changed by a command (sparse...)
+pub fn write_config_file<P: AsRef<Path>>(path: P, config: &Config) {
+	let json_config = json::as_pretty_json(config).to_string();
+	metadata::write_atomically(path.as_ref(), json_config.as_bytes(), 0).unwrap();
+}
+
 pub fn read_config_file<P: AsRef<Path>>(path: P) -> Config {
 	let mut file = File::open(&path).unwrap();
 	let mut json = String::new();
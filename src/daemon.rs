@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::os::unix::net::UnixListener;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::thread;
+
+use libc;
+use rustc_serialize::json;
+use time;
+
+use config::Job;
+use metadata;
+
+/// Socket answering the status of the running daemon, in the directory of the repository
+pub const STATUS_SOCKET: &'static str = "yabs-daemon.sock";
+/// Last run of each job, so a restarted daemon does not run them all again
+pub const STATE_FILE: &'static str = "yabs-daemon.json";
+
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(_: libc::c_int) {
+	STOPPING.store(true, Ordering::SeqCst);
+}
+
+/// SIGTERM and SIGINT only ask the daemon to stop: the current job finishes the object it writes,
+/// commits what is stored and no other job is started
+pub fn handle_signals() {
+	unsafe {
+		libc::signal(libc::SIGTERM, on_signal as libc::sighandler_t);
+		libc::signal(libc::SIGINT, on_signal as libc::sighandler_t);
+	}
+}
+
+pub fn is_stopping() -> bool {
+	STOPPING.load(Ordering::SeqCst)
+}
+
+#[derive(Debug, RustcEncodable, RustcDecodable, Clone)]
+pub struct JobStatus {
+	name: String,
+	running: bool,
+	/// Seconds since 1970
+	last_run: Option<i64>,
+	last_duration: Option<i64>,
+	last_error: Option<String>,
+	next_run: i64
+}
+
+#[derive(Debug, RustcEncodable, RustcDecodable, Clone)]
+pub struct DaemonStatus {
+	pid: u32,
+	started: i64,
+	jobs: Vec<JobStatus>
+}
+
+impl DaemonStatus {
+	/// The jobs never run (or not since the state file was removed) are due now, or at their hour
+	pub fn new(jobs: &[Job], last_runs: &HashMap<String, i64>) -> DaemonStatus {
+		let now = time::get_time().sec;
+		let jobs = jobs.iter().map(|job| {
+			let last_run = last_runs.get(&job.get_name()).cloned();
+			JobStatus {name: job.get_name(), running: false, last_run: last_run, last_duration: None, last_error: None, next_run: get_next_run(job, last_run, now)}
+		}).collect();
+		DaemonStatus {pid: ::std::process::id(), started: now, jobs: jobs}
+	}
+	pub fn is_due(&self, job: usize, now: i64) -> bool {
+		!self.jobs[job].running && self.jobs[job].next_run <= now
+	}
+	pub fn set_running(&mut self, job: usize) {
+		self.jobs[job].running = true;
+	}
+	pub fn set_done(&mut self, job: usize, definition: &Job, started: i64, error: Option<String>) {
+		let now = time::get_time().sec;
+		let status = &mut self.jobs[job];
+		status.running = false;
+		status.last_run = Some(started);
+		status.last_duration = Some(now - started);
+		status.last_error = error;
+		status.next_run = get_next_run(definition, Some(started), now);
+	}
+	/// A job which could not start (repository locked) is tried again a minute later
+	pub fn set_postponed(&mut self, job: usize, error: String) {
+		self.jobs[job].running = false;
+		self.jobs[job].last_error = Some(error);
+		self.jobs[job].next_run = time::get_time().sec + 60;
+	}
+	pub fn get_last_runs(&self) -> HashMap<String, i64> {
+		self.jobs.iter().filter_map(|j| j.last_run.map(|t| (j.name.clone(), t))).collect()
+	}
+}
+
+/// Next run after the last one: one period later, moved to the hour of the job
+/// The hour is searched from half a day before, so a job which ran late keeps its hour
+fn get_next_run(job: &Job, last_run: Option<i64>, now: i64) -> i64 {
+	match (last_run, job.get_hour()) {
+		(None, None) => now,
+		(None, Some(hour)) => get_next_hour(now, hour),
+		(Some(last_run), None) => last_run + job.get_period(),
+		(Some(last_run), Some(hour)) => get_next_hour(last_run + job.get_period() - 12 * 3600, hour)
+	}
+}
+
+/// First time at hour:00 (local time) from the given time
+fn get_next_hour(from: i64, hour: u32) -> i64 {
+	let mut tm = time::at(time::Timespec::new(from, 0));
+	tm.tm_hour = hour as i32;
+	tm.tm_min = 0;
+	tm.tm_sec = 0;
+	let at = tm.to_timespec().sec;
+	if at < from { at + 24 * 3600 } else { at }
+}
+
+pub fn read_state() -> HashMap<String, i64> {
+	let mut content = String::new();
+	match File::open(STATE_FILE).and_then(|mut f| f.read_to_string(&mut content)) {
+		Ok(_) => json::decode(&content).unwrap_or(HashMap::new()),
+		Err(_) => HashMap::new()
+	}
+}
+
+/// Written atomically, a crash keeps the previous state
+pub fn write_state(last_runs: &HashMap<String, i64>) {
+	if let Err(e) = metadata::write_atomically(Path::new(STATE_FILE), json::encode(last_runs).unwrap().as_bytes(), 0) {
+		println!("Cannot write {}: {}", STATE_FILE, e);
+	}
+}
+
+/// Answer each connection to the socket with the status as json, in a thread
+/// A socket left by a daemon which died is replaced, the socket of a running daemon is an error
+pub fn serve_status(status: Arc<Mutex<DaemonStatus>>) -> io::Result<()> {
+	let socket_path = Path::new(STATUS_SOCKET);
+	if socket_path.exists() {
+		if UnixStream::connect(socket_path).is_ok() {
+			return Err(io::Error::new(io::ErrorKind::AddrInUse, "A daemon is already running in this directory"));
+		}
+		fs::remove_file(socket_path)?;
+	}
+
+	let listener = UnixListener::bind(socket_path)?;
+	thread::spawn(move || {
+		for stream in listener.incoming() {
+			if let Ok(mut stream) = stream {
+				let content = json::encode(&*status.lock().unwrap()).unwrap();
+				let _ = stream.write_all(content.as_bytes());
+			}
+		}
+	});
+	Ok(())
+}
+
+pub fn remove_status_socket() {
+	let _ = fs::remove_file(STATUS_SOCKET);
+}
+
+pub fn query_status() -> io::Result<DaemonStatus> {
+	let mut stream = UnixStream::connect(STATUS_SOCKET)?;
+	let mut content = String::new();
+	stream.read_to_string(&mut content)?;
+	json::decode(&content).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}", e)))
+}
+
+pub fn print_status(status: &DaemonStatus) {
+	println!("Daemon {} running since {}", status.pid, format_time(status.started));
+	for job in status.jobs.iter() {
+		println!("{}", job.name);
+		if job.running {
+			println!("  running");
+		}
+		match job.last_run {
+			Some(last_run) => println!("  last run  {} ({} s)", format_time(last_run), job.last_duration.map_or(String::from("?"), |d| d.to_string())),
+			None => println!("  last run  never")
+		}
+		println!("  next run  {}", format_time(job.next_run));
+		if let Some(ref error) = job.last_error {
+			println!("  error     {}", error);
+		}
+	}
+}
+
+fn format_time(seconds: i64) -> String {
+	time::strftime("%Y-%m-%d %H:%M:%S", &time::at(time::Timespec::new(seconds, 0))).unwrap()
+}
@@ -0,0 +1,110 @@
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::process::Command;
+
+use config::Config;
+use transfer;
+
+/// Points where the command of the config is run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Hook {
+	PreCommit,
+	PostCommit,
+	PreUpdate,
+	PostUpdate,
+	PrePush,
+	PostPush,
+	OnConflict,
+	OnError
+}
+
+impl Hook {
+	pub fn get_name(&self) -> &'static str {
+		match *self {
+			Hook::PreCommit => "pre-commit",
+			Hook::PostCommit => "post-commit",
+			Hook::PreUpdate => "pre-update",
+			Hook::PostUpdate => "post-update",
+			Hook::PrePush => "pre-push",
+			Hook::PostPush => "post-push",
+			Hook::OnConflict => "on-conflict",
+			Hook::OnError => "on-error"
+		}
+	}
+}
+
+/// What the hook is told through its environment
+/// YABS_HOOK, YABS_REPOSITORY (directory of the config), YABS_STORE, YABS_METADATA, YABS_REVISION,
+/// YABS_FILES_LIST (path of a temporary file with one file per line), YABS_FILE_COUNT and YABS_ERROR
+/// The file list is not in the environment, one variable is limited to 128 KiB on Linux
+pub struct HookContext {
+	revision: Option<i32>,
+	files: Vec<String>,
+	error: Option<String>
+}
+
+impl HookContext {
+	pub fn new() -> HookContext {
+		HookContext {revision: None, files: Vec::new(), error: None}
+	}
+	pub fn with_revision(mut self, revision: i32) -> HookContext {
+		self.revision = Some(revision);
+		self
+	}
+	pub fn with_files<'a, I: Iterator<Item = &'a String>>(mut self, files: I) -> HookContext {
+		self.files = files.cloned().collect();
+		self.files.sort();
+		self
+	}
+	pub fn with_error(mut self, error: &str) -> HookContext {
+		self.error = Some(error.to_string());
+		self
+	}
+}
+
+/// Run the command of the hook with sh, true if there is none or if it succeeded
+/// A failing pre hook aborts the operation, the result of the other hooks is only reported
+pub fn run_hook(config: &Config, hook: Hook, context: HookContext) -> bool {
+	let command = match config.get_hook(hook.get_name()) {
+		Some(command) => command,
+		None => return true
+	};
+
+	println!("Run the {} hook", hook.get_name());
+	let files_path = transfer::get_tmp_path(&format!("{}-files", hook.get_name()));
+	if let Err(e) = File::create(&files_path).and_then(|mut f| f.write_all(context.files.join("\n").as_bytes())) {
+		println!("Cannot write the file list of the {} hook: {}", hook.get_name(), e);
+		return false;
+	}
+
+	let mut process = Command::new("sh");
+	process.arg("-c").arg(&command)
+		.env("YABS_HOOK", hook.get_name())
+		.env("YABS_REPOSITORY", env::current_dir().map(|d| d.to_str().unwrap_or("").to_string()).unwrap_or(String::new()))
+		.env("YABS_STORE", config.get_store_path())
+		.env("YABS_METADATA", config.get_local_metadata_path())
+		.env("YABS_FILES_LIST", &files_path)
+		.env("YABS_FILE_COUNT", context.files.len().to_string());
+	if let Some(revision) = context.revision {
+		process.env("YABS_REVISION", revision.to_string());
+	}
+	if let Some(ref error) = context.error {
+		process.env("YABS_ERROR", error);
+	}
+
+	let status = process.status();
+	let _ = fs::remove_file(&files_path);
+	match status {
+		Ok(status) if status.success() => true,
+		Ok(status) => {
+			println!("The {} hook failed ({})", hook.get_name(), status);
+			false
+		},
+		Err(e) => {
+			println!("Cannot run the {} hook: {}", hook.get_name(), e);
+			false
+		}
+	}
+}
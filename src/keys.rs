@@ -0,0 +1,364 @@
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::prelude::*;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crypto::curve25519;
+use rustc_serialize::hex::FromHex;
+use rustc_serialize::hex::ToHex;
+use rustc_serialize::json;
+
+use cipher;
+use cipher::Key;
+use config::Config;
+use config::StorageMode;
+
+/// The master key of an encrypted repository is random, it is kept in store/keys.json
+/// wrapped once per key slot, so adding or removing a slot never re-encrypts the store
+pub const KEYS_FILE: &'static str = "keys.json";
+
+/// Passphrase used instead of prompting, for scripts
+pub const PASSPHRASE_VARIABLE: &'static str = "YABS_PASSPHRASE";
+/// New passphrase used by change-passphrase instead of prompting
+pub const NEW_PASSPHRASE_VARIABLE: &'static str = "YABS_NEW_PASSPHRASE";
+/// Key file (or recovery key) used to unlock the repository instead of a passphrase
+pub const KEY_FILE_VARIABLE: &'static str = "YABS_KEY_FILE";
+
+/// scrypt cost: 2^15 iterations, 32 MiB of memory
+const LOG_N: u8 = 15;
+const R: u32 = 8;
+const P: u32 = 1;
+
+const SECRET_LENGTH: usize = 32;
+const CHECK_MESSAGE: &'static [u8] = b"yabs key check";
+
+#[derive(Debug, RustcEncodable, RustcDecodable, Clone, PartialEq)]
+pub enum SlotKind {
+	PASSPHRASE,
+	/// A secret file kept by the user, the slot is wrapped for its X25519 public key
+	KEYFILE,
+	/// The X25519 public key of a teammate, who unlocks with the matching key file
+	RECIPIENT
+}
+
+#[derive(Debug, RustcEncodable, RustcDecodable)]
+pub struct KeyFile {
+	/// Keyed hash of a constant, to check a recovery key
+	check: String,
+	slots: Vec<KeySlot>
+}
+
+#[derive(Debug, RustcEncodable, RustcDecodable)]
+pub struct KeySlot {
+	name: String,
+	kind: SlotKind,
+	salt: String,
+	scrypt: Option<ScryptParameters>,
+	public_key: Option<String>,
+	ephemeral_key: Option<String>,
+	nonce: String,
+	wrapped_key: String,
+	tag: String
+}
+
+#[derive(Debug, RustcEncodable, RustcDecodable)]
+pub struct ScryptParameters {
+	log_n: u8,
+	r: u32,
+	p: u32
+}
+
+/// What the user gives to unlock the repository
+pub enum Secret {
+	Passphrase(String),
+	/// Content of a key file, or of a recovery key
+	KeyFile(Vec<u8>)
+}
+
+impl KeySlot {
+	fn new_passphrase(name: &str, key: &Key, passphrase: &str) -> KeySlot {
+		let salt = cipher::random_bytes(32);
+		let kek = cipher::derive_key(passphrase, &salt, LOG_N, R, P);
+		let scrypt = ScryptParameters {log_n: LOG_N, r: R, p: P};
+		KeySlot::wrap(name, SlotKind::PASSPHRASE, key, &kek, salt, Some(scrypt), None, None)
+	}
+
+	/// Ephemeral X25519 key agreement with the public key, as age does
+	fn new_public_key(name: &str, kind: SlotKind, key: &Key, public_key: &[u8]) -> KeySlot {
+		let salt = cipher::random_bytes(32);
+		let ephemeral_secret = cipher::random_bytes(SECRET_LENGTH);
+		let ephemeral_key = curve25519::curve25519_base(&ephemeral_secret);
+		let shared_secret = curve25519::curve25519(&ephemeral_secret, public_key);
+		let kek = get_public_key_kek(&shared_secret, &salt, &ephemeral_key, public_key);
+		KeySlot::wrap(name, kind, key, &kek, salt, None, Some(public_key.to_hex()), Some(ephemeral_key.to_hex()))
+	}
+
+	fn wrap(name: &str, kind: SlotKind, key: &Key, kek: &[u8], salt: Vec<u8>, scrypt: Option<ScryptParameters>, public_key: Option<String>, ephemeral_key: Option<String>) -> KeySlot {
+		let (nonce, wrapped_key, tag) = cipher::seal(kek, &key.to_bytes());
+		KeySlot {name: name.to_string(), kind: kind, salt: salt.to_hex(), scrypt: scrypt, public_key: public_key, ephemeral_key: ephemeral_key,
+			nonce: nonce.to_hex(), wrapped_key: wrapped_key.to_hex(), tag: tag.to_hex()}
+	}
+
+	/// Key encryption key of this slot, None if the secret is not for this kind of slot
+	fn get_kek(&self, secret: &Secret) -> Option<Vec<u8>> {
+		let salt = from_hex(&self.salt);
+		match (&self.kind, secret) {
+			(&SlotKind::PASSPHRASE, &Secret::Passphrase(ref passphrase)) => {
+				let scrypt = self.scrypt.as_ref().expect("Invalid key file");
+				Some(cipher::derive_key(passphrase, &salt, scrypt.log_n, scrypt.r, scrypt.p))
+			},
+			(&SlotKind::KEYFILE, &Secret::KeyFile(ref secret_key)) | (&SlotKind::RECIPIENT, &Secret::KeyFile(ref secret_key)) if secret_key.len() == SECRET_LENGTH => {
+				let public_key = from_hex(self.public_key.as_ref().expect("Invalid key file"));
+				if curve25519::curve25519_base(secret_key).to_vec() != public_key {
+					return None;
+				}
+				let ephemeral_key = from_hex(self.ephemeral_key.as_ref().expect("Invalid key file"));
+				let shared_secret = curve25519::curve25519(secret_key, &ephemeral_key);
+				Some(get_public_key_kek(&shared_secret, &salt, &ephemeral_key, &public_key))
+			},
+			_ => None
+		}
+	}
+
+	/// None if the secret does not open this slot or the slot has been altered
+	fn unwrap_key(&self, secret: &Secret) -> Option<Key> {
+		self.get_kek(secret)
+			.and_then(|kek| cipher::open(&kek, &from_hex(&self.nonce), &from_hex(&self.wrapped_key), &from_hex(&self.tag)))
+			.filter(|bytes| bytes.len() == cipher::KEY_LENGTH)
+			.map(|bytes| Key::from_bytes(&bytes))
+	}
+}
+
+fn get_public_key_kek(shared_secret: &[u8], salt: &[u8], ephemeral_key: &[u8], public_key: &[u8]) -> Vec<u8> {
+	let mut info = salt.to_vec();
+	info.extend_from_slice(ephemeral_key);
+	info.extend_from_slice(public_key);
+	cipher::hmac_sha256(shared_secret, &info)
+}
+
+fn get_check(key: &Key) -> String {
+	cipher::hmac_sha256(key.get_name_key(), CHECK_MESSAGE).to_hex()
+}
+
+fn from_hex(value: &str) -> Vec<u8> {
+	value.from_hex().expect("Invalid key file")
+}
+
+impl KeyFile {
+	fn new(key: &Key) -> KeyFile {
+		KeyFile {check: get_check(key), slots: Vec::new()}
+	}
+
+	/// Master key opened by the secret, a recovery key is the master key itself
+	pub fn unlock(&self, secret: &Secret) -> Option<Key> {
+		if let &Secret::KeyFile(ref bytes) = secret {
+			if bytes.len() == cipher::KEY_LENGTH {
+				let key = Key::from_bytes(bytes);
+				return if get_check(&key) == self.check { Some(key) } else { None };
+			}
+		}
+		self.slots.iter().filter_map(|slot| slot.unwrap_key(secret)).next()
+	}
+
+	fn add_slot(&mut self, slot: KeySlot) {
+		if self.slots.iter().any(|s| s.name == slot.name) {
+			panic!("A key slot named {} already exists", slot.name);
+		}
+		println!("Key slot {} added", slot.name);
+		self.slots.push(slot);
+	}
+}
+
+pub fn get_keys_path(store_path: &Path) -> PathBuf {
+	store_path.join(KEYS_FILE)
+}
+
+pub fn is_encrypted(config: &Config) -> bool {
+	match config.get_storage_mode() {
+		StorageMode::GZIPAES => true,
+		_ => false
+	}
+}
+
+/// Passphrase from the environment variable, or read on the standard input
+pub fn read_passphrase(variable: &str, prompt: &str) -> String {
+	if let Ok(passphrase) = env::var(variable) {
+		return passphrase;
+	}
+
+	print!("{}", prompt);
+	io::stdout().flush().unwrap();
+	let mut passphrase = String::new();
+	io::stdin().read_line(&mut passphrase).expect("Cannot read the passphrase");
+	passphrase.trim_end_matches(|c| c == '\n' || c == '\r').to_string()
+}
+
+/// The key file given in the environment, else a passphrase
+fn read_secret() -> Secret {
+	match env::var(KEY_FILE_VARIABLE) {
+		Ok(path) => Secret::KeyFile(read_secret_file(Path::new(&path))),
+		Err(_) => Secret::Passphrase(read_passphrase(PASSPHRASE_VARIABLE, "Passphrase: "))
+	}
+}
+
+/// Key files are hex text, so they can be printed or copied by hand
+fn read_secret_file(path: &Path) -> Vec<u8> {
+	let mut hex = String::new();
+	File::open(path).expect("Cannot open the key file").read_to_string(&mut hex).unwrap();
+	hex.trim().from_hex().expect("Invalid key file")
+}
+
+/// Readable by the owner only, also when an existing file is replaced
+fn write_secret_file(path: &Path, secret: &[u8]) {
+	let mut file = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path).unwrap();
+	file.set_permissions(fs::Permissions::from_mode(0o600)).unwrap();
+	file.write_all(secret.to_hex().as_bytes()).unwrap();
+	file.sync_all().unwrap();
+}
+
+/// Public key of a key file, the key file is generated if it does not exist
+fn get_public_key(key_file_path: &Path) -> Vec<u8> {
+	if !key_file_path.is_file() {
+		write_secret_file(key_file_path, &cipher::random_bytes(SECRET_LENGTH));
+		println!("Key file created in {:?}, keep it secret", key_file_path);
+	}
+	let secret_key = read_secret_file(key_file_path);
+	if secret_key.len() != SECRET_LENGTH {
+		panic!("{:?} is not a key file", key_file_path);
+	}
+	curve25519::curve25519_base(&secret_key).to_vec()
+}
+
+pub fn print_public_key(key_file_path: &Path) {
+	println!("{}", get_public_key(key_file_path).to_hex());
+}
+
+/// Generate the master key of a new encrypted repository, with a first passphrase slot
+pub fn create_key_file(store_path: &Path) {
+	let keys_path = get_keys_path(store_path);
+	if keys_path.is_file() {
+		println!("Keep the existing key file {:?}", keys_path);
+		return;
+	}
+
+	let key = Key::generate();
+	let passphrase = read_passphrase(PASSPHRASE_VARIABLE, "New passphrase: ");
+	let mut key_file = KeyFile::new(&key);
+	key_file.add_slot(KeySlot::new_passphrase("passphrase", &key, &passphrase));
+	write_key_file(&keys_path, &key_file);
+	println!("Key file created in {:?}, keep a copy of it and of the passphrase", keys_path);
+}
+
+fn write_key_file(keys_path: &Path, key_file: &KeyFile) {
+	let tmp_path = keys_path.with_extension("tmp");
+	let mut file = File::create(&tmp_path).unwrap();
+	file.write_all(json::encode(key_file).unwrap().as_bytes()).unwrap();
+	file.sync_all().unwrap();
+	fs::rename(&tmp_path, keys_path).unwrap();
+}
+
+fn read_key_file(keys_path: &Path) -> KeyFile {
+	let mut json = String::new();
+	File::open(keys_path).expect("No key file, run copy-from-remote or new first").read_to_string(&mut json).unwrap();
+	json::decode(&json).expect("Invalid key file")
+}
+
+/// Master key of the repository, None if the repository is not encrypted
+pub fn load_key(config: &Config) -> Option<Key> {
+	if !is_encrypted(config) {
+		return None;
+	}
+
+	let key_file = read_key_file(&get_keys_path(&config.get_store_path()));
+	Some(unlock(&key_file))
+}
+
+fn unlock(key_file: &KeyFile) -> Key {
+	match key_file.unlock(&read_secret()) {
+		Some(key) => key,
+		None => panic!("Wrong passphrase or key file")
+	}
+}
+
+/// The slots can be changed only by someone who can unlock the master key
+fn open_for_change(config: &Config) -> (PathBuf, KeyFile, Key) {
+	if !is_encrypted(config) {
+		panic!("The repository is not encrypted, set storage_mode to GZIPAES");
+	}
+	let keys_path = get_keys_path(&config.get_store_path());
+	let key_file = read_key_file(&keys_path);
+	let key = unlock(&key_file);
+	(keys_path, key_file, key)
+}
+
+pub fn list_slots(config: &Config) {
+	let key_file = read_key_file(&get_keys_path(&config.get_store_path()));
+	for slot in key_file.slots.iter() {
+		match slot.public_key {
+			Some(ref public_key) => println!("{} {:?} {}", slot.name, slot.kind, public_key),
+			None => println!("{} {:?}", slot.name, slot.kind)
+		}
+	}
+	println!("{} key slots", key_file.slots.len());
+}
+
+pub fn add_passphrase_slot(config: &Config, name: &str) {
+	let (keys_path, mut key_file, key) = open_for_change(config);
+	let passphrase = read_passphrase(NEW_PASSPHRASE_VARIABLE, "New passphrase: ");
+	key_file.add_slot(KeySlot::new_passphrase(name, &key, &passphrase));
+	write_key_file(&keys_path, &key_file);
+}
+
+/// Slot opened by a key file, which is generated if it does not exist
+pub fn add_key_file_slot(config: &Config, name: &str, key_file_path: &Path) {
+	let (keys_path, mut key_file, key) = open_for_change(config);
+	let public_key = get_public_key(key_file_path);
+	key_file.add_slot(KeySlot::new_public_key(name, SlotKind::KEYFILE, &key, &public_key));
+	write_key_file(&keys_path, &key_file);
+}
+
+/// Slot for the public key of a teammate (printed by key public-key on their machine)
+pub fn add_recipient_slot(config: &Config, name: &str, public_key: &str) {
+	let public_key = public_key.from_hex().ok().filter(|k| k.len() == SECRET_LENGTH).expect("Invalid public key");
+	let (keys_path, mut key_file, key) = open_for_change(config);
+	key_file.add_slot(KeySlot::new_public_key(name, SlotKind::RECIPIENT, &key, &public_key));
+	write_key_file(&keys_path, &key_file);
+}
+
+/// Only the slot is removed, someone who kept a copy of the master key can still read the store
+pub fn remove_slot(config: &Config, name: &str) {
+	let (keys_path, mut key_file, _) = open_for_change(config);
+	let nb_slots = key_file.slots.len();
+	key_file.slots.retain(|slot| slot.name != name);
+	if key_file.slots.len() == nb_slots {
+		panic!("No key slot named {}", name);
+	}
+	if key_file.slots.is_empty() {
+		panic!("Cannot remove the last key slot");
+	}
+	write_key_file(&keys_path, &key_file);
+	println!("Key slot {} removed", name);
+}
+
+pub fn change_passphrase(config: &Config, name: &str) {
+	let (keys_path, mut key_file, key) = open_for_change(config);
+	let position = key_file.slots.iter().position(|slot| slot.name == name && slot.kind == SlotKind::PASSPHRASE)
+		.expect("No passphrase slot with this name");
+	let passphrase = read_passphrase(NEW_PASSPHRASE_VARIABLE, "New passphrase: ");
+	key_file.slots[position] = KeySlot::new_passphrase(name, &key, &passphrase);
+	write_key_file(&keys_path, &key_file);
+	println!("Passphrase of {} changed", name);
+}
+
+/// Write the master key itself, it unlocks the repository through YABS_KEY_FILE
+pub fn export_recovery_key(config: &Config, recovery_path: &Path) {
+	let (_, _, key) = open_for_change(config);
+	write_secret_file(recovery_path, &key.to_bytes());
+	println!("Recovery key written in {:?}, store it offline: it opens the repository whatever the key slots", recovery_path);
+}
@@ -0,0 +1,199 @@
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::path::PathBuf;
+
+use throttle;
+use transfer;
+use transport::RemoteStat;
+use transport::Transport;
+
+/// Remote stored in a local or mounted directory (USB drive, NFS share...)
+pub struct LocalTransport {
+	root_path: PathBuf
+}
+
+impl LocalTransport {
+	pub fn new(root_path: &Path) -> LocalTransport {
+		LocalTransport {root_path: root_path.to_path_buf()}
+	}
+}
+
+impl Transport for LocalTransport {
+	fn list(&self, remote_path: &Path) -> io::Result<Vec<(PathBuf, RemoteStat)>> {
+		let mut elements : Vec<(PathBuf, RemoteStat)> = Vec::new();
+
+		for entry in fs::read_dir(self.root_path.join(remote_path))? {
+			let entry = entry?;
+			let info = entry.metadata()?;
+			elements.push((remote_path.join(entry.file_name()), RemoteStat::new(info.len(), info.is_dir())));
+		}
+
+		Ok(elements)
+	}
+
+	fn get(&self, remote_path: &Path, local_path: &Path) -> io::Result<()> {
+		copy_from(&self.root_path.join(remote_path), local_path, 0)
+	}
+
+	fn put(&self, local_path: &Path, remote_path: &Path) -> io::Result<()> {
+		copy_from(local_path, &self.root_path.join(remote_path), 0)
+	}
+
+	fn delete(&self, remote_path: &Path) -> io::Result<()> {
+		fs::remove_file(self.root_path.join(remote_path))
+	}
+
+	fn stat(&self, remote_path: &Path) -> io::Result<Option<RemoteStat>> {
+		match fs::metadata(self.root_path.join(remote_path)) {
+			Ok(info) => Ok(Some(RemoteStat::new(info.len(), info.is_dir()))),
+			Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+			Err(e) => Err(e)
+		}
+	}
+
+	fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+		fs::rename(self.root_path.join(from), self.root_path.join(to))
+	}
+
+	fn mkdir(&self, remote_path: &Path) -> io::Result<()> {
+		fs::create_dir(self.root_path.join(remote_path))
+	}
+
+	fn get_remote_hash(&self, remote_path: &Path) -> io::Result<Option<String>> {
+		transfer::get_content_version(&self.root_path.join(remote_path)).map(Some)
+	}
+
+	fn put_exclusive(&self, local_path: &Path, remote_path: &Path) -> io::Result<bool> {
+		let mut destination = match OpenOptions::new().write(true).create_new(true).open(self.root_path.join(remote_path)) {
+			Ok(file) => file,
+			Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => return Ok(false),
+			Err(e) => return Err(e)
+		};
+		io::copy(&mut File::open(local_path)?, &mut destination)?;
+		destination.sync_all()?;
+		Ok(true)
+	}
+
+	fn supports_resume(&self) -> bool {
+		true
+	}
+
+	fn put_from(&self, local_path: &Path, remote_path: &Path, offset: u64) -> io::Result<()> {
+		copy_from(local_path, &self.root_path.join(remote_path), offset)
+	}
+
+	fn get_from(&self, remote_path: &Path, local_path: &Path, offset: u64) -> io::Result<()> {
+		copy_from(&self.root_path.join(remote_path), local_path, offset)
+	}
+}
+
+/// Copy the source from offset, the destination already contains the first offset bytes
+fn copy_from(source_path: &Path, destination_path: &Path, offset: u64) -> io::Result<()> {
+	let mut source = File::open(source_path)?;
+	source.seek(SeekFrom::Start(offset))?;
+
+	let mut destination = OpenOptions::new().write(true).create(true).open(destination_path)?;
+	destination.set_len(offset)?;
+	destination.seek(SeekFrom::Start(offset))?;
+
+	throttle::copy(&mut source, &mut destination)?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+	use std::fs::File;
+	use std::io::prelude::*;
+	use std::path::Path;
+
+	use cipher::Key;
+	use codec::CodecSettings;
+	use config::Codec;
+	use lock;
+	use lock::RemoteLock;
+	use store;
+	use testutil;
+	use transfer;
+	use transport;
+	use transport::Transport;
+	use super::LocalTransport;
+
+	#[test]
+	fn objects_are_pushed_then_fetched_and_checked() {
+		let key = Key::generate();
+		let store_path = testutil::new_test_directory("local-store");
+		let other_store_path = testutil::new_test_directory("local-other-store");
+		let remote_path = testutil::new_test_directory("local-remote");
+		store::create_store(&store_path);
+		store::create_store(&other_store_path);
+		let transport = LocalTransport::new(&remote_path);
+
+		let source_path = testutil::new_test_directory("local-source").join("file");
+		let content : Vec<u8> = (0..300000u32).map(|i| (i % 97) as u8).collect();
+		File::create(&source_path).unwrap().write_all(&content).unwrap();
+		let hash = store::store_file(&store_path, &source_path, Some(&key), CodecSettings::new(Codec::ZSTD, None)).unwrap();
+
+		//push, resuming an upload interrupted in the middle
+		let local_object = store::get_object_path(&store_path, &hash);
+		let remote_object = Path::new("store").join(store::get_object_relative_path(store::SHARDED_LAYOUT, &hash));
+		transport::create_remote_dirs(&transport, remote_object.parent().unwrap()).unwrap();
+		let mut object = Vec::new();
+		File::open(&local_object).unwrap().read_to_end(&mut object).unwrap();
+		File::create(remote_path.join(transport::get_part_path(&remote_object))).unwrap().write_all(&object[..object.len() / 2]).unwrap();
+		transfer::upload_object(&transport, &local_object, &remote_object).unwrap();
+		assert_eq!(transport.stat(&remote_object).unwrap().unwrap().get_size(), object.len() as u64);
+		assert!(transport.stat(&transport::get_part_path(&remote_object)).unwrap().is_none());
+		assert_eq!(transport.get_remote_hash(&remote_object).unwrap(), Some(transfer::get_content_version(&local_object).unwrap()));
+
+		//fetch in another store
+		let fetched_object = store::get_object_path(&other_store_path, &hash);
+		fs::create_dir_all(fetched_object.parent().unwrap()).unwrap();
+		assert!(transfer::download_object(&transport, &remote_object, &fetched_object, Some(&key), &hash).unwrap());
+		assert!(store::check_file(&fetched_object, Some(&key), &hash));
+
+		//a corrupted remote object is not kept
+		fs::remove_file(&fetched_object).unwrap();
+		File::create(remote_path.join(&remote_object)).unwrap().write_all(b"corrupted").unwrap();
+		assert!(!transfer::download_object(&transport, &remote_object, &fetched_object, Some(&key), &hash).unwrap());
+		assert!(!fetched_object.exists());
+
+		for path in [store_path, other_store_path, remote_path, source_path.parent().unwrap().to_path_buf()].iter() {
+			fs::remove_dir_all(path).unwrap();
+		}
+	}
+
+	#[test]
+	fn only_one_writer_gets_the_remote_lock() {
+		let remote_path = testutil::new_test_directory("lock");
+		let transport = LocalTransport::new(&remote_path);
+
+		let local_path = remote_path.join("local");
+		File::create(&local_path).unwrap().write_all(b"content").unwrap();
+		assert!(transport.put_exclusive(&local_path, Path::new("exclusive")).unwrap());
+		assert!(!transport.put_exclusive(&local_path, Path::new("exclusive")).unwrap());
+
+		{
+			let _lock = RemoteLock::acquire(&transport, 600).unwrap();
+			assert!(RemoteLock::acquire(&transport, 600).is_err());
+		}
+		let lock_path = remote_path.join(lock::REMOTE_LOCK_FILE);
+		assert!(!lock_path.exists());
+
+		//an expired lock is taken over, and its late release leaves the new one in place
+		let expired = RemoteLock::acquire(&transport, -1).unwrap();
+		let current = RemoteLock::acquire(&transport, 600).unwrap();
+		drop(expired);
+		assert!(lock_path.exists());
+		assert!(RemoteLock::acquire(&transport, 600).is_err());
+		drop(current);
+		assert!(!lock_path.exists());
+
+		fs::remove_dir_all(&remote_path).unwrap();
+	}
+}
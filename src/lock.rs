@@ -0,0 +1,246 @@
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+
+use rustc_serialize::hex::ToHex;
+use rustc_serialize::json;
+use time;
+
+use cipher;
+use transfer;
+use transport::Transport;
+
+/// Lock file of the working directory, held by the commands which write the metadata or the store
+pub const LOCAL_LOCK_FILE: &'static str = "yabs.lock";
+/// Lock file at the root of the remote, held while the remote metadata is written
+pub const REMOTE_LOCK_FILE: &'static str = "yabs.lock";
+
+/// Name of this machine
+pub fn get_hostname() -> String {
+	let hostname = match File::open("/proc/sys/kernel/hostname").or_else(|_| File::open("/etc/hostname")) {
+		Ok(mut file) => {
+			let mut hostname = String::new();
+			file.read_to_string(&mut hostname).map(|_| hostname).unwrap_or(String::new())
+		},
+		Err(_) => env::var("HOSTNAME").unwrap_or(String::new())
+	};
+	match hostname.trim() {
+		"" => String::from("localhost"),
+		name => name.to_string()
+	}
+}
+
+/// Exclusive lock of the working directory, the file holds "pid hostname"
+/// The lock is released when it is dropped
+pub struct LocalLock {
+	path: PathBuf
+}
+
+impl LocalLock {
+	/// A lock left by a dead process of this machine is stale and taken over
+	/// A lock of another machine (shared directory) is never taken over
+	pub fn acquire(path: &Path) -> io::Result<LocalLock> {
+		let owner = format!("{} {}", process::id(), get_hostname());
+
+		for _ in 0..2 {
+			match OpenOptions::new().write(true).create_new(true).open(path) {
+				Ok(mut file) => {
+					file.write_all(owner.as_bytes())?;
+					file.sync_all()?;
+					return Ok(LocalLock {path: path.to_path_buf()});
+				},
+				Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+					let mut holder = String::new();
+					File::open(path)?.read_to_string(&mut holder)?;
+					if !is_stale(&holder) {
+						return Err(io::Error::new(io::ErrorKind::WouldBlock,
+							format!("Repository locked by {}, remove {:?} if this process does not exist anymore", holder.trim(), path)));
+					}
+					println!("Remove the stale lock of {}", holder.trim());
+					fs::remove_file(path)?;
+				},
+				Err(e) => return Err(e)
+			}
+		}
+		Err(io::Error::new(io::ErrorKind::WouldBlock, "Repository locked by another process"))
+	}
+}
+
+fn is_stale(holder: &str) -> bool {
+	let mut fields = holder.split_whitespace();
+	let pid = fields.next().and_then(|pid| pid.parse::<u32>().ok());
+	let hostname = fields.next();
+	match (pid, hostname) {
+		(Some(pid), Some(hostname)) if hostname == get_hostname() => !Path::new(&format!("/proc/{}", pid)).exists(),
+		(Some(_), Some(_)) => false,
+		//an empty or invalid lock file was left by a crash right after its creation
+		_ => true
+	}
+}
+
+impl Drop for LocalLock {
+	fn drop(&mut self) {
+		let _ = fs::remove_file(&self.path);
+	}
+}
+
+#[derive(Debug, RustcEncodable, RustcDecodable)]
+struct RemoteLockInfo {
+	owner: String,
+	token: String,
+	/// Seconds since 1970, the lock can be taken over after this time
+	expires: i64
+}
+
+/// Lock of the remote with a lease: a writer which died without releasing it blocks the others until the lease expires
+/// The lock file is created exclusively (O_EXCL, SFTP CREATE|EXCLUDE, S3 If-None-Match), so only one writer gets it
+/// Where the remote has versions (s3), an expired lock is replaced with a conditional put and released with a conditional delete
+/// Elsewhere the lock is first renamed to a name of this writer: only one writer can move it,
+/// and the moved file is checked to carry the expected token before it is removed (or put back)
+pub struct RemoteLock<'a> {
+	transport: &'a dyn Transport,
+	token: String
+}
+
+impl <'a> RemoteLock<'a> {
+	pub fn acquire(transport: &'a dyn Transport, lease_seconds: i64) -> io::Result<RemoteLock<'a>> {
+		let remote_path = Path::new(REMOTE_LOCK_FILE);
+		let now = time::get_time().sec;
+
+		let expired = match read_remote_lock(transport, remote_path)? {
+			Some(ref info) if info.expires > now => {
+				return Err(io::Error::new(io::ErrorKind::WouldBlock,
+					format!("Remote locked by {} for {} more seconds", info.owner, info.expires - now)));
+			},
+			Some(info) => {
+				println!("Take over the expired remote lock of {}", info.owner);
+				Some(info)
+			},
+			None => None
+		};
+
+		let info = RemoteLockInfo {
+			owner: format!("{} {}", process::id(), get_hostname()),
+			token: cipher::random_bytes(16).to_hex(),
+			expires: now + lease_seconds
+		};
+		let local_path = transfer::get_tmp_path(REMOTE_LOCK_FILE);
+		File::create(&local_path)?.write_all(json::encode(&info).unwrap().as_bytes())?;
+		let locked = match expired {
+			Some(ref expired_info) => take_over(transport, &local_path, expired_info, &info.token),
+			None => transport.put_exclusive(&local_path, remote_path)
+		};
+		fs::remove_file(&local_path)?;
+
+		if !locked? {
+			return Err(io::Error::new(io::ErrorKind::WouldBlock, "Remote locked by another writer at the same time"));
+		}
+		Ok(RemoteLock {transport: transport, token: info.token})
+	}
+}
+
+/// Name of the lock while it is moved by the writer with this token
+fn get_moved_path(token: &str) -> PathBuf {
+	PathBuf::from(format!("{}.{}", REMOTE_LOCK_FILE, token))
+}
+
+/// Replace an expired lock, false if another writer has taken it meanwhile
+fn take_over(transport: &dyn Transport, local_path: &Path, expired_info: &RemoteLockInfo, token: &str) -> io::Result<bool> {
+	let remote_path = Path::new(REMOTE_LOCK_FILE);
+	//the version is read first, so it can only be the one of the expired lock or of a newer one
+	if let Some(version) = transport.get_version(remote_path)? {
+		return match read_remote_lock(transport, remote_path)? {
+			Some(ref current) if current.token == expired_info.token => transport.put_if_version(local_path, remote_path, Some(version)),
+			Some(_) => Ok(false),
+			None => transport.put_exclusive(local_path, remote_path)
+		};
+	}
+
+	let moved_path = get_moved_path(token);
+	if transport.rename(remote_path, &moved_path).is_err() {
+		//moved by another writer first, or released by its holder
+		return match transport.stat(remote_path)? {
+			Some(_) => Ok(false),
+			None => transport.put_exclusive(local_path, remote_path)
+		};
+	}
+	match read_remote_lock(transport, &moved_path)? {
+		Some(ref moved) if moved.token == expired_info.token => {
+			transport.delete(&moved_path)?;
+			transport.put_exclusive(local_path, remote_path)
+		},
+		_ => {
+			put_back(transport, &moved_path)?;
+			Ok(false)
+		}
+	}
+}
+
+/// Remove the lock if it still carries the token, false if it has been taken over
+fn release(transport: &dyn Transport, token: &str) -> io::Result<bool> {
+	let remote_path = Path::new(REMOTE_LOCK_FILE);
+	if let Some(version) = transport.get_version(remote_path)? {
+		return match read_remote_lock(transport, remote_path)? {
+			Some(ref current) if current.token == token => transport.delete_if_version(remote_path, version),
+			_ => Ok(false)
+		};
+	}
+
+	let moved_path = get_moved_path(token);
+	if transport.rename(remote_path, &moved_path).is_err() {
+		return Ok(false);
+	}
+	match read_remote_lock(transport, &moved_path)? {
+		Some(ref moved) if moved.token == token => {
+			transport.delete(&moved_path)?;
+			Ok(true)
+		},
+		_ => {
+			put_back(transport, &moved_path)?;
+			Ok(false)
+		}
+	}
+}
+
+/// Put back the lock of another writer moved by mistake, unless a new lock has been created meanwhile
+fn put_back(transport: &dyn Transport, moved_path: &Path) -> io::Result<()> {
+	let local_path = transfer::get_tmp_path(&format!("{}.back", REMOTE_LOCK_FILE));
+	transport.get(moved_path, &local_path)?;
+	let put = transport.put_exclusive(&local_path, Path::new(REMOTE_LOCK_FILE));
+	fs::remove_file(&local_path)?;
+	put?;
+	transport.delete(moved_path)
+}
+
+fn read_remote_lock(transport: &dyn Transport, remote_path: &Path) -> io::Result<Option<RemoteLockInfo>> {
+	if transport.stat(remote_path)?.is_none() {
+		return Ok(None);
+	}
+
+	//not the path of the lock being created by acquire
+	let local_path = transfer::get_tmp_path(&format!("{}.read", REMOTE_LOCK_FILE));
+	transport.get(remote_path, &local_path)?;
+	let mut content = String::new();
+	File::open(&local_path)?.read_to_string(&mut content)?;
+	fs::remove_file(&local_path)?;
+
+	//a lock being written by another writer is seen as held
+	Ok(Some(json::decode(&content).unwrap_or(RemoteLockInfo {owner: String::from("unknown"), token: String::new(), expires: time::get_time().sec + 60})))
+}
+
+impl <'a> Drop for RemoteLock<'a> {
+	/// The lock is removed only if it has not been taken over after the lease
+	fn drop(&mut self) {
+		match release(self.transport, &self.token) {
+			Ok(true) => (),
+			Ok(false) => println!("The remote lock has expired and been taken over"),
+			Err(e) => println!("Cannot release the remote lock: {}", e)
+		}
+	}
+}
@@ -0,0 +1,32 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+use rustc_serialize::hex::ToHex;
+
+use cipher;
+use lock;
+
+/// File of the store holding the id of this clone of the repository, it is never uploaded
+pub const MACHINE_ID_FILE: &'static str = "machine-id";
+
+/// Stable id of this clone: the hostname for the humans and a random part, so two clones on the same host differ
+/// It is generated the first time it is needed
+pub fn get_machine_id(store_path: &Path) -> String {
+	let machine_id_path = store_path.join(MACHINE_ID_FILE);
+
+	let mut machine_id = String::new();
+	if let Ok(mut file) = File::open(&machine_id_path) {
+		file.read_to_string(&mut machine_id).unwrap();
+	}
+	if !machine_id.trim().is_empty() {
+		return machine_id.trim().to_string();
+	}
+
+	let machine_id = format!("{}-{}", lock::get_hostname(), cipher::random_bytes(4).to_hex());
+	let mut file = File::create(&machine_id_path).unwrap();
+	file.write_all(machine_id.as_bytes()).unwrap();
+	file.sync_all().unwrap();
+	println!("Machine id of this clone: {}", machine_id);
+	machine_id
+}
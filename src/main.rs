@@ -3,41 +3,160 @@ extern crate rustc_serialize;
 extern crate crypto;
 extern crate flate2;
 extern crate ssh2;
-
+extern crate time;
+extern crate zstd;
+extern crate inotify;
+extern crate libc;
+extern crate native_tls;
+
+use std::env;
+use std::io;
 use std::path::PathBuf;
 use std::path::Path;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::fs::*;
+use std::any::Any;
+use std::panic;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
 
 
 mod metadata;
 mod workingdirectory;
 mod store;
 mod config;
+mod transport;
+mod localengine;
 mod sshengine;
+mod sftpengine;
+mod s3engine;
+mod transfer;
+mod throttle;
+mod pack;
+mod cipher;
+mod keys;
+mod codec;
+mod metadatalog;
+mod lock;
+mod machine;
+mod sparse;
+mod watch;
+mod daemon;
+mod hooks;
+#[cfg(test)]
+mod testutil;
 
 fn main() {
-	let mut args = std::env::args();
-	match args.nth(1) {
-		Some(option) => dispatch_option(&option),
+	let args : Vec<String> = std::env::args().collect();
+	match args.get(1) {
+		Some(option) => {
+			//most failures panic, the on-error hook runs for them too before the process dies
+			if let Err(e) = panic::catch_unwind(|| dispatch_option(option, &args[2..])) {
+				if Path::new("config.json").is_file() {
+					let error = format!("{} failed: {}", option, get_panic_message(&*e));
+					let _ = panic::catch_unwind(|| report_error(&load_config(), &error));
+				}
+				panic::resume_unwind(e);
+			}
+		},
 		None => println!("No option")
 	}
 }
 
-fn dispatch_option(option: &str) {
+/// Commands writing the metadata or the store, they hold the lock of the working directory
+const LOCKED_OPTIONS: &'static [&'static str] = &["new", "update", "update-remote", "commit", "commit-remote", "copy-from-remote",
+	"copy-to-remote", "migrate-store", "migrate-remote-store", "gc", "repack", "key", "import-metadata", "compact-metadata", "recover-metadata", "sync", "tag", "restore", "sparse", "resolve", "prune"];
+
+fn dispatch_option(option: &str, arguments: &[String]) {
+	let _lock = if LOCKED_OPTIONS.iter().any(|&o| o == option) {
+		match lock::LocalLock::acquire(Path::new(lock::LOCAL_LOCK_FILE)) {
+			Ok(lock) => Some(lock),
+			Err(e) => {
+				println!("{}", e);
+				return;
+			}
+		}
+	} else {
+		None
+	};
+
 	match option {
 		"new-config" => new_config(),
 		"new" => new_repo(),
 		"update" => update(),
-		"update-remote" => update_remote(),
-		"commit" => commit(),
-		"commit-remote" => commit_remote(),
-		"copy-to-remote" => copy_to_remote(),
-		"copy-from-remote" => download_from_remote(),
+		"update-remote" => update_remote(arguments),
+		"commit" => commit(arguments),
+		"commit-remote" => commit_remote(arguments),
+		"copy-to-remote" => copy_to_remote(arguments),
+		"copy-from-remote" => download_from_remote(arguments),
+		"migrate-store" => migrate_store(),
+		"gc" => gc(),
+		"repack" => repack(),
+		"prune" => prune(arguments),
+		"migrate-remote-store" => migrate_remote_store(arguments),
+		"key" => key(arguments),
+		"export-metadata" => export_metadata(arguments),
+		"import-metadata" => import_metadata(arguments),
+		"compact-metadata" => compact_metadata(),
+		"recover-metadata" => recover_metadata(),
+		"sync" => sync(arguments),
+		"history" => history(arguments),
+		"log" => log(),
+		"tag" => tag(arguments),
+		"restore" => restore(arguments),
+		"resolve" => resolve(arguments),
+		"sparse" => sparse(arguments),
+		"watch" => watch(arguments),
+		"verify" => verify(),
+		"daemon" => daemon(arguments),
+		"status" => status(arguments),
 		_ => println!("Unknown option {}", option)
 	}
 }
 
+/// Value following an argument like --limit-rate 2M
+fn get_argument_value(arguments: &[String], name: &str) -> Option<String> {
+	arguments.iter().position(|a| a == name).and_then(|i| arguments.get(i + 1)).cloned()
+}
+
+/// Load the config, overriding the transfer settings with the command line
+/// (--limit-rate 2M, --transfers 4) or following the bandwidth schedule
+fn load_remote_config(arguments: &[String]) -> config::Config {
+	let mut config = load_config();
+
+	if let Some(nb_transfers) = get_argument_value(arguments, "--transfers") {
+		config.set_nb_transfers(nb_transfers.parse::<usize>().expect("--transfers must be a number"));
+	}
+
+	match get_argument_value(arguments, "--limit-rate") {
+		Some(rate) => throttle::set_limit(parse_rate(&rate)),
+		None => {
+			let default_limit = config.get_limit_rate().map(|rate| parse_rate(&rate)).unwrap_or(0);
+			let windows = config.get_rate_schedule().into_iter()
+				.map(|window| {
+					let limit = parse_rate(&window.get_limit_rate());
+					(window, limit)
+				})
+				.collect();
+			throttle::set_schedule(default_limit, windows);
+		}
+	};
+	let bytes_per_second = throttle::get_limit();
+	if bytes_per_second > 0 {
+		println!("Bandwidth limited to {} bytes per second", bytes_per_second);
+	}
+
+	config
+}
+
+fn parse_rate(rate: &str) -> usize {
+	throttle::parse_rate(rate).expect("Invalid rate, use something like 500K or 2M")
+}
+
 fn new_config() {
 	println!("Creation of a new config file");
 	let json_path = Path::new("config.json");
@@ -50,8 +169,693 @@ fn load_config() -> config::Config {
 
 fn new_repo() {
 	println!("Creation of a new repo");
-	let json_path = load_config().get_local_metadata_path();
-	metadata::create_emty_metadata_file(&json_path);
+	let config = load_config();
+	store::create_store(&config.get_store_path());
+	if keys::is_encrypted(&config) {
+		keys::create_key_file(&config.get_store_path());
+	}
+	let key = keys::load_key(&config);
+	metadata::create_emty_metadata_file(&config.get_local_metadata_path(), key.as_ref(), config.get_metadata_format(), config.get_metadata_generations());
+}
+
+/// Convert a flat store to the sharded layout
+fn migrate_store() {
+	let config = load_config();
+	store::migrate_store(&config.get_store_path());
+}
+
+fn gc() {
+	let config = load_config();
+	let key = keys::load_key(&config);
+	collect_garbage(&config, key.as_ref());
+}
+
+/// Remove the loose objects which are not referenced by any revision of the local or the remote metadata
+fn collect_garbage(config: &config::Config, key: Option<&cipher::Key>) {
+	let store_path = config.get_store_path();
+	let referenced_hashes = get_known_hashes(config, key);
+
+	let mut nb_removed = 0;
+	for path in store::get_all_filenames(&store_path) {
+		let hash = path.file_name().unwrap().to_str().unwrap().to_string();
+		if store::is_object_name(&hash) && !referenced_hashes.contains(&hash) {
+			println!("- Remove unreferenced object {}", hash);
+			remove_file(&path).unwrap();
+			nb_removed = nb_removed + 1;
+		}
+	}
+	println!("{} objects removed", nb_removed);
+}
+
+/// Objects which can still be referenced: by the local metadata, or by the remote metadata of the last sync
+/// (the revisions pruned here stay on the remote until the pruned metadata is pushed, another clone can still restore them)
+fn get_known_hashes(config: &config::Config, key: Option<&cipher::Key>) -> HashSet<String> {
+	let mut hashes = metadata::read_metadata_file(&config.get_local_metadata_path(), key).get_all_hashes();
+	let base_path = metadata::get_base_path(&config.get_remote_metadata_path());
+	if base_path.is_file() {
+		hashes.extend(metadata::read_metadata_file(&base_path, key).get_all_hashes());
+	}
+	hashes
+}
+
+fn verify() {
+	let config = load_config();
+	let key = keys::load_key(&config);
+	verify_store(&config, key.as_ref());
+}
+
+/// Check that every object referenced by the metadata is in the store and matches its hash
+/// Return the number of missing or corrupted objects
+fn verify_store(config: &config::Config, key: Option<&cipher::Key>) -> usize {
+	let store_path = config.get_store_path();
+	let referenced_hashes = metadata::read_metadata_file(&config.get_local_metadata_path(), key).get_all_hashes();
+	let packed_objects = pack::read_all_indexes(&store_path);
+
+	let mut nb_bad = 0;
+	for hash in referenced_hashes.iter() {
+		let valid = match store::open_object(&store_path, &packed_objects, hash) {
+			Some(reader) => store::check_object(reader, key, hash),
+			None => false
+		};
+		if !valid {
+			println!("- Missing or corrupted object {}", hash);
+			nb_bad = nb_bad + 1;
+		}
+	}
+	println!("{} objects checked, {} missing or corrupted", referenced_hashes.len(), nb_bad);
+	nb_bad
+}
+
+fn repack() {
+	let config = load_config();
+	let key = keys::load_key(&config);
+	repack_store(&config, key.as_ref());
+}
+
+/// Compact all the packs in a single one without the unreferenced objects
+fn repack_store(config: &config::Config, key: Option<&cipher::Key>) {
+	let referenced_hashes = get_known_hashes(config, key);
+	pack::repack(&config.get_store_path(), &referenced_hashes, config.get_pack_threshold());
+}
+
+/// prune [--keep <revisions>] [--days <days>]
+/// Forget the revisions of each file older than the last ones and than the days of the config, then remove their objects
+/// The pruned revisions are recorded in the metadata, the next syncs drop them from the other clones too
+fn prune(arguments: &[String]) {
+	let mut config = load_config();
+	if let Some(keep) = get_argument_value(arguments, "--keep") {
+		config.set_prune_keep_revisions(keep.parse::<usize>().expect("--keep must be a number"));
+	}
+	if let Some(days) = get_argument_value(arguments, "--days") {
+		config.set_prune_keep_days(days.parse::<i64>().expect("--days must be a number"));
+	}
+	let key = keys::load_key(&config);
+	prune_store(&config, key.as_ref());
+}
+
+fn prune_store(config: &config::Config, key: Option<&cipher::Key>) {
+	let json_path = config.get_local_metadata_path();
+	let mut hierarchy = metadata::read_metadata_file(&json_path, key);
+	let since = time::get_time().sec - config.get_prune_keep_days() * 24 * 3600;
+	let nb_removed = hierarchy.prune(config.get_prune_keep_revisions(), since.max(0) as u64);
+	println!("{} revisions pruned", nb_removed);
+	if nb_removed > 0 {
+		metadata::write_metadata_file_as(&json_path, &hierarchy, key, metadata::get_metadata_format(&json_path), config.get_metadata_generations());
+	}
+	collect_garbage(config, key);
+	repack_store(config, key);
+}
+
+/// Write the local metadata as plain json (decrypted)
+fn export_metadata(arguments: &[String]) {
+	let config = load_config();
+	let key = keys::load_key(&config);
+	let export_path = arguments.get(0).expect("Usage: export-metadata <json file>");
+
+	let hierarchy = metadata::read_metadata_file(&config.get_local_metadata_path(), key.as_ref());
+	metadata::export_json_file(Path::new(export_path), &hierarchy);
+	println!("{} files exported to {}", hierarchy.get_number_of_files(), export_path);
+}
+
+/// Replace the local metadata by a plain json file, written in the metadata format of the config
+fn import_metadata(arguments: &[String]) {
+	let config = load_config();
+	let key = keys::load_key(&config);
+	let import_path = arguments.get(0).expect("Usage: import-metadata <json file>");
+
+	let hierarchy = metadata::import_json_file(Path::new(import_path));
+	metadata::write_metadata_file_as(&config.get_local_metadata_path(), &hierarchy, key.as_ref(), config.get_metadata_format(), config.get_metadata_generations());
+	println!("{} files imported from {}", hierarchy.get_number_of_files(), import_path);
+}
+
+/// Rewrite the local metadata as a single snapshot, in the metadata format of the config
+/// The previous log becomes a generation: a log only grows by appends, so compacting it is when its generations are taken
+fn compact_metadata() {
+	let config = load_config();
+	let key = keys::load_key(&config);
+	let json_path = config.get_local_metadata_path();
+
+	let hierarchy = metadata::read_metadata_file(&json_path, key.as_ref());
+	metadata::write_metadata_file_as(&json_path, &hierarchy, key.as_ref(), config.get_metadata_format(), config.get_metadata_generations());
+}
+
+/// Print the revisions of the files (all the files, or the files under a path), oldest first:
+/// date, size, hash and the machine which committed it
+fn history(arguments: &[String]) {
+	let config = load_config();
+	let key = keys::load_key(&config);
+	let prefix = arguments.get(0).map(|p| p.trim_start_matches("./").to_string()).unwrap_or(String::new());
+
+	let histories = metadata::read_file_histories(&config.get_local_metadata_path(), key.as_ref(), &|name| name.trim_start_matches("./").starts_with(&prefix));
+	for (filename, revisions) in histories.iter() {
+		println!("{}", filename);
+		for revision in revisions {
+			let date = time::at(time::Timespec::new(revision.get_timestamp() as i64, 0));
+			let hash = revision.get_hash();
+			println!("  {}  {:>12}  {}  {}", time::strftime("%Y-%m-%d %H:%M:%S", &date).unwrap(), revision.get_size(), &hash[..hash.len().min(12)], revision.get_origin());
+		}
+	}
+}
+
+/// Print the commits of the repository with their tags
+fn log() {
+	let config = load_config();
+	let key = keys::load_key(&config);
+	let hierarchy = metadata::read_metadata_file(&config.get_local_metadata_path(), key.as_ref());
+
+	for commit in hierarchy.get_commits() {
+		let date = time::at(time::Timespec::new(commit.get_time(), 0));
+		let tags : Vec<&String> = hierarchy.get_tags().iter().filter(|&(_, t)| t.get_commit() == Some(commit.get_id())).map(|(name, _)| name).collect();
+		println!("revision {} ({})  {}  {}@{}  {:?}", commit.get_revision(), commit.get_id(), time::strftime("%Y-%m-%d %H:%M:%S", &date).unwrap(), commit.get_user(), commit.get_host(), tags);
+		if !commit.get_message().is_empty() {
+			println!("    {}", commit.get_message());
+		}
+	}
+}
+
+/// tag: list the tags
+/// tag <name> [commit]: tag the files as they were at the commit, given by its id or its revision number (the current files by default)
+/// tag -d <name>: remove a tag
+/// A tagged snapshot keeps its objects in the store whatever happens to the history
+fn tag(arguments: &[String]) {
+	let config = load_config();
+	let key = keys::load_key(&config);
+	let json_path = config.get_local_metadata_path();
+	let mut hierarchy = metadata::read_metadata_file(&json_path, key.as_ref());
+
+	match arguments.get(0).map(|a| a.as_str()) {
+		None => {
+			for (name, tag) in hierarchy.get_tags() {
+				let date = time::at(time::Timespec::new(tag.get_time(), 0));
+				println!("{}  revision {} ({})  {}  {} files", name, tag.get_revision(), tag.get_commit().unwrap_or("current files"), time::strftime("%Y-%m-%d %H:%M:%S", &date).unwrap(), tag.get_files().len());
+			}
+			return;
+		},
+		Some("-d") => {
+			let name = arguments.get(1).expect("Usage: tag -d <name>");
+			if !hierarchy.remove_tag(name) {
+				println!("No tag {}", name);
+				return;
+			}
+		},
+		Some(name) => {
+			let commit = match arguments.get(1) {
+				Some(reference) => match hierarchy.find_commit(reference) {
+					Ok(commit) => Some(commit.clone()),
+					Err(message) => {
+						println!("{}", message);
+						return;
+					}
+				},
+				None => None
+			};
+			hierarchy.add_tag(name, commit.as_ref(), time::get_time().sec);
+			match commit {
+				Some(commit) => println!("Tag {} on commit {}", name, commit.get_id()),
+				None => println!("Tag {} on the current files", name)
+			}
+		}
+	}
+
+	metadata::write_metadata_file_as(&json_path, &hierarchy, key.as_ref(), metadata::get_metadata_format(&json_path), config.get_metadata_generations());
+}
+
+/// restore <tag|commit> [-m message], the commit is given by its id or its revision number
+/// Put the files of a snapshot back in the working directory and commit them as new revisions,
+/// the files created after the snapshot and the files outside the sparse set are left in place
+/// Refused if a file of the working directory has uncommitted changes
+fn restore(arguments: &[String]) {
+	let config = load_config();
+	let key = keys::load_key(&config);
+	let store_path = config.get_store_path();
+	let name = arguments.get(0).expect("Usage: restore <tag|commit>");
+
+	let hierarchy = metadata::read_metadata_file(&config.get_local_metadata_path(), key.as_ref());
+	let snapshot = match hierarchy.get_tags().get(name) {
+		Some(tag) => tag.get_files().clone(),
+		None => match hierarchy.find_commit(name) {
+			Ok(commit) => hierarchy.get_snapshot(commit),
+			Err(message) => {
+				println!("{}", message);
+				return;
+			}
+		}
+	};
+
+	let sparse_set = sparse::SparseSet::from_config(&config);
+	let mut wd_hierarchy : HashMap<String, model::MetaData> = workingdirectory::read_sources(&config.get_sources());
+	wd_hierarchy.retain(|filename, _| sparse_set.contains(filename));
+	let changed = files_to_commit(wd_hierarchy, &hierarchy);
+	if !changed.is_empty() {
+		println!("{} files have uncommitted changes, commit them before restoring", changed.len());
+		return;
+	}
+
+	//the restored files get the current time, so the commit records them as new revisions
+	let now = time::get_time().sec as u64;
+	let packed_objects = pack::read_all_indexes(&store_path);
+	let mut nb_failed = 0;
+	for (filename, metadata) in snapshot.iter() {
+		if !sparse_set.contains(filename) || hierarchy.get_latest_meta_data(filename).map_or(false, |m| m.get_hash() == metadata.get_hash()) {
+			continue;
+		}
+		println!("- Restore {}", filename);
+		if !extract_file(&config, &packed_objects, key.as_ref(), filename, &metadata.get_hash(), now) {
+			nb_failed = nb_failed + 1;
+		}
+	}
+	if nb_failed > 0 {
+		println!("{} files could not be restored, nothing is committed", nb_failed);
+		return;
+	}
+
+	let message = get_argument_value(arguments, "-m").unwrap_or(format!("restore {}", name));
+	commit_working_directory(&config, key.as_ref(), &message);
+}
+
+/// sparse: print the rules
+/// sparse add <path>: materialize the files under the path, it never removes a file from the set
+/// sparse remove <path>: remove the files under the path from the working directory, they stay in the repository
+/// sparse set <path>...: materialize only the files under the paths
+/// sparse reset: materialize all the files
+/// The unchanged files leaving the set are deleted, the modified ones are kept and must be handled by hand
+/// A command which would delete files outside the paths it names is refused unless --force is given
+fn sparse(arguments: &[String]) {
+	let config_path = Path::new("config.json");
+	let mut config = load_config();
+	let mut include = config.get_sparse_include();
+	let mut exclude = config.get_sparse_exclude();
+	let force = arguments.iter().any(|a| a == "--force");
+	let paths : Vec<String> = arguments.iter().skip(1).filter(|a| *a != "--force").map(|p| sparse::normalize_rule(p)).collect();
+
+	match (arguments.get(0).map(|a| a.as_str()), paths.get(0).cloned()) {
+		(None, _) => {
+			match include {
+				None if exclude.is_empty() => println!("No sparse rules, all the files are in the working directory"),
+				Some(ref include) if include.is_empty() => println!("No include path, no file is in the working directory"),
+				_ => ()
+			}
+			for path in include.iter().flat_map(|include| include.iter()) {
+				println!("include {}", path);
+			}
+			for path in exclude.iter() {
+				println!("exclude {}", path);
+			}
+			return;
+		},
+		(Some("add"), Some(path)) => {
+			if exclude.contains(&path) {
+				exclude.retain(|p| *p != path);
+			}
+			if let Some(ref mut include) = include {
+				if !include.contains(&path) {
+					include.push(path);
+				}
+			}
+		},
+		(Some("remove"), Some(path)) => {
+			let included = include.as_ref().map_or(false, |include| include.contains(&path));
+			if included {
+				include.as_mut().unwrap().retain(|p| *p != path);
+			} else if !exclude.contains(&path) {
+				exclude.push(path);
+			}
+		},
+		(Some("set"), Some(_)) => {
+			include = Some(paths.clone());
+		},
+		(Some("reset"), None) => {
+			include = None;
+			exclude.clear();
+		},
+		_ => {
+			println!("Usage: sparse [add <path> | remove <path> | set <path>... | reset] [--force]");
+			return;
+		}
+	}
+
+	//the committed files which leave the set, unchanged ones are deleted
+	let key = keys::load_key(&config);
+	let old_set = sparse::SparseSet::from_config(&config);
+	let new_set = sparse::SparseSet::new(&config, include.as_ref(), &exclude);
+	let hierarchy = metadata::read_metadata_file(&config.get_local_metadata_path(), key.as_ref());
+	let sources = config.get_sources();
+	let mut to_remove : Vec<String> = Vec::new();
+	for (filename, metadata) in workingdirectory::read_sources(&sources) {
+		if new_set.contains(&filename) {
+			continue;
+		}
+		match hierarchy.get_latest_meta_data(&filename) {
+			Some(m) if m.get_timestamp() == metadata.get_timestamp() => to_remove.push(filename),
+			_ => println!("- Keep {}, it has uncommitted changes", filename)
+		}
+	}
+
+	let unexpected = to_remove.iter().filter(|f| !old_set.contains(f) || !paths.iter().any(|p| new_set.is_under(f, p))).count();
+	let expected = arguments.get(0).map_or(false, |a| a == "remove");
+	if ((!expected && !to_remove.is_empty()) || unexpected > 0) && !force {
+		println!("{} files of the working directory would be deleted, {} of them outside the given paths: nothing done, add --force to delete them",
+			to_remove.len(), unexpected);
+		return;
+	}
+
+	config.set_sparse_include(include);
+	config.set_sparse_exclude(exclude);
+	config::write_config_file(config_path, &config);
+
+	for filename in to_remove {
+		println!("- Remove {}", filename);
+		remove_file(workingdirectory::get_file_path(&sources, &filename).unwrap()).unwrap();
+	}
+	update();
+}
+
+/// watch [--push]
+/// Commit the files changed under the sources once no event came for watch_debounce seconds,
+/// with a full scan every watch_rescan_interval seconds (and at start) for the events inotify missed
+/// The repository is locked only during the commits, the other commands can run between them
+fn watch(arguments: &[String]) {
+	let config = load_remote_config(arguments);
+	let key = keys::load_key(&config);
+	let push = config.get_watch_push() || arguments.iter().any(|a| a == "--push");
+	let debounce = Duration::from_secs(config.get_watch_debounce());
+	let rescan_interval = Duration::from_secs(config.get_watch_rescan_interval());
+
+	let mut watcher = watch::Watcher::new(config.get_sources()).expect("Cannot watch the sources");
+	let mut changed : HashSet<String> = HashSet::new();
+	let mut last_event = Instant::now();
+	let mut last_rescan : Option<Instant> = None;
+
+	loop {
+		let nb_changed = changed.len();
+		let overflow = match watcher.read_changes(&mut changed) {
+			Ok(overflow) => overflow,
+			Err(e) => {
+				//the directories added after the error may not be watched, watch them all again
+				println!("Cannot read the file system events: {}", e);
+				match watch::Watcher::new(config.get_sources()) {
+					Ok(new_watcher) => watcher = new_watcher,
+					Err(e) => println!("Cannot watch the sources again: {}", e)
+				}
+				true
+			}
+		};
+		if changed.len() > nb_changed {
+			last_event = Instant::now();
+		}
+		if overflow {
+			println!("Some file system events were lost, full scan");
+			last_rescan = None;
+		}
+
+		let rescan = last_rescan.map_or(true, |t| t.elapsed() >= rescan_interval);
+		if rescan || (!changed.is_empty() && last_event.elapsed() >= debounce) {
+			let _lock = match lock::LocalLock::acquire(Path::new(lock::LOCAL_LOCK_FILE)) {
+				Ok(lock) => lock,
+				Err(e) => {
+					println!("{}, commit postponed", e);
+					thread::sleep(debounce);
+					continue;
+				}
+			};
+
+			if rescan {
+				commit_files(&config, key.as_ref(), "watch: full scan", None);
+				last_rescan = Some(Instant::now());
+			} else {
+				commit_files(&config, key.as_ref(), &format!("watch: {} files", changed.len()), Some(&changed));
+			}
+			changed.clear();
+
+			if push {
+				push_to_remote(&config, key.as_ref());
+			}
+		}
+
+		thread::sleep(Duration::from_millis(500));
+	}
+}
+
+/// Print the error and run the on-error hook, return false for the caller to return
+fn report_error(config: &config::Config, error: &str) -> bool {
+	println!("{}", error);
+	hooks::run_hook(config, hooks::Hook::OnError, hooks::HookContext::new().with_error(error));
+	false
+}
+
+/// Message of a panic caught with catch_unwind
+fn get_panic_message(payload: &(dyn Any + Send)) -> String {
+	payload.downcast_ref::<String>().cloned().or(payload.downcast_ref::<&str>().map(|s| s.to_string())).unwrap_or(String::from("panic"))
+}
+
+/// Context of the push hooks: the revision of the local metadata
+fn push_context(config: &config::Config, key: Option<&cipher::Key>) -> hooks::HookContext {
+	let hierarchy = metadata::read_metadata_file(&config.get_local_metadata_path(), key);
+	hooks::HookContext::new().with_revision(hierarchy.get_number_of_revisions())
+}
+
+/// Upload the objects then the metadata, the next sync merges if the remote has changed
+fn push_to_remote(config: &config::Config, key: Option<&cipher::Key>) -> bool {
+	if !check_no_conflict(config, key) {
+		return report_error(config, "Push refused: some files are in conflict, run resolve <file> [--theirs]");
+	}
+	if !hooks::run_hook(config, hooks::Hook::PrePush, push_context(config, key)) {
+		return report_error(config, "Push aborted by the pre-push hook");
+	}
+	let transport = transport::open_transport(config);
+	if !push_objects(config, &*transport, key) {
+		return report_error(config, "Push stopped: some objects could not be uploaded");
+	}
+	if !push_metadata(config, &*transport) {
+		return report_error(config, "Push refused: the remote metadata has changed, run sync");
+	}
+	hooks::run_hook(config, hooks::Hook::PostPush, push_context(config, key));
+	true
+}
+
+/// Run the jobs of the config when they are due, until SIGTERM
+/// The repository is locked only while a job runs, `status --daemon` asks the daemon for its jobs
+fn daemon(arguments: &[String]) {
+	let config = load_remote_config(arguments);
+	let jobs = config.get_jobs();
+	if jobs.is_empty() {
+		println!("No job in the config");
+		return;
+	}
+
+	//the key is unlocked once: a detached daemon cannot ask the passphrase at each job
+	if keys::is_encrypted(&config) && !keys::get_keys_path(&config.get_store_path()).is_file() {
+		fetch_key_file(&config, &*transport::open_transport(&config));
+	}
+	let key = keys::load_key(&config);
+
+	daemon::handle_signals();
+	let status = Arc::new(Mutex::new(daemon::DaemonStatus::new(&jobs, &daemon::read_state())));
+	if let Err(e) = daemon::serve_status(status.clone()) {
+		println!("Cannot open the status socket: {}", e);
+		return;
+	}
+	println!("Daemon started with {} jobs", jobs.len());
+
+	while !daemon::is_stopping() {
+		for (i, job) in jobs.iter().enumerate() {
+			let started = time::get_time().sec;
+			if daemon::is_stopping() || !status.lock().unwrap().is_due(i, started) {
+				continue;
+			}
+
+			let _lock = match lock::LocalLock::acquire(Path::new(lock::LOCAL_LOCK_FILE)) {
+				Ok(lock) => lock,
+				Err(e) => {
+					status.lock().unwrap().set_postponed(i, e.to_string());
+					continue;
+				}
+			};
+
+			println!("Run {}", job.get_name());
+			status.lock().unwrap().set_running(i);
+			//the failures of push and sync have already run the on-error hook, a panic has not
+			let error = match panic::catch_unwind(panic::AssertUnwindSafe(|| run_job(&config, key.as_ref(), job.get_command()))) {
+				Ok(result) => result.err(),
+				Err(e) => {
+					let error = get_panic_message(&*e);
+					report_error(&config, &format!("{} failed: {}", job.get_name(), error));
+					Some(error)
+				}
+			};
+			if let Some(ref error) = error {
+				println!("{} failed: {}", job.get_name(), error);
+			}
+
+			let mut status = status.lock().unwrap();
+			status.set_done(i, job, started, error);
+			daemon::write_state(&status.get_last_runs());
+		}
+		thread::sleep(Duration::from_secs(1));
+	}
+
+	daemon::remove_status_socket();
+	println!("Daemon stopped");
+}
+
+fn run_job(config: &config::Config, key: Option<&cipher::Key>, command: config::JobCommand) -> Result<(), String> {
+	match command {
+		config::JobCommand::COMMIT => if !commit_working_directory(config, key, "daemon: scheduled commit") {
+			return Err(String::from("commit aborted by the pre-commit hook"));
+		},
+		config::JobCommand::PUSH => if !push_to_remote(config, key) {
+			return Err(String::from("push failed"));
+		},
+		config::JobCommand::SYNC => if !sync_with_remote(config, &*transport::open_transport(config), key, "daemon: scheduled sync") {
+			return Err(String::from("sync stopped"));
+		},
+		config::JobCommand::VERIFY => match verify_store(config, key) {
+			0 => {},
+			nb_bad => {
+				let error = format!("{} missing or corrupted objects", nb_bad);
+				report_error(config, &error);
+				return Err(error);
+			}
+		},
+		config::JobCommand::GC => collect_garbage(config, key),
+		config::JobCommand::REPACK => repack_store(config, key),
+		config::JobCommand::PRUNE => prune_store(config, key)
+	}
+	Ok(())
+}
+
+/// status --daemon: jobs of the daemon running in this directory
+fn status(arguments: &[String]) {
+	if !arguments.iter().any(|a| a == "--daemon") {
+		println!("Usage: status --daemon");
+		return;
+	}
+	match daemon::query_status() {
+		Ok(status) => daemon::print_status(&status),
+		Err(e) => println!("No daemon running in this directory: {}", e)
+	}
+}
+
+/// Rebuild the local metadata when it cannot be read
+/// - from the most recent generation which can be read
+/// - else from the objects of the store: the file names are lost, each object is restored as data/recovered/<hash>
+fn recover_metadata() {
+	let config = load_config();
+	let key = keys::load_key(&config);
+	let json_path = config.get_local_metadata_path();
+	let generations = config.get_metadata_generations();
+
+	if metadata::try_read_metadata_file(&json_path, key.as_ref()).is_some() {
+		println!("The metadata can be read, nothing to recover");
+		return;
+	}
+
+	let hierarchy = match metadata::read_last_good_generation(&json_path, key.as_ref(), generations) {
+		Some((generation, hierarchy)) => {
+			println!("Metadata recovered from generation {}", generation);
+			hierarchy
+		},
+		None => {
+			println!("No generation can be read, rebuild the metadata from the store");
+			recover_hierarchy_from_store(&config, key.as_ref())
+		}
+	};
+
+	//the broken file is kept as generation 1
+	metadata::write_metadata_file_as(&json_path, &hierarchy, key.as_ref(), config.get_metadata_format(), generations);
+	println!("{} files in the recovered metadata", hierarchy.get_number_of_files());
+}
+
+fn recover_hierarchy_from_store(config: &config::Config, key: Option<&cipher::Key>) -> model::Hierarchy {
+	let store_path = config.get_store_path();
+	let recovered_path = format!("{}/recovered", config.get_sources()[0].get_name());
+	let packed_objects = pack::read_all_indexes(&store_path);
+
+	let mut hashes : HashSet<String> = packed_objects.keys().cloned().collect();
+	for path in store::get_all_filenames(&store_path) {
+		let name = path.file_name().unwrap().to_str().unwrap().to_string();
+		if store::is_object_name(&name) {
+			hashes.insert(name);
+		}
+	}
+
+	let timestamp = time::get_time().sec as u64;
+	let mut recovered_metadata : HashMap<String, model::MetaData> = HashMap::new();
+	for hash in hashes {
+		match store::read_content_size(&store_path, &packed_objects, key, &hash) {
+			Ok(size) => {
+				let mut metadata = model::MetaData::new_without_hash(timestamp, size);
+				metadata.add_hash(hash.clone());
+				recovered_metadata.insert(format!("{}/{}", recovered_path, hash), metadata);
+			},
+			Err(e) => println!("- Unreadable object {}: {}", hash, e)
+		}
+	}
+
+	let mut hierarchy = model::Hierarchy::new_empty();
+	hierarchy.update(recovered_metadata);
+	hierarchy
+}
+
+/// Manage the key slots of an encrypted repository
+/// key list
+/// key add <name> --passphrase | --key-file <path> | --recipient <public key>
+/// key remove <name>
+/// key change-passphrase [name]
+/// key export-recovery <path>
+/// key public-key <key file> (creates the key file if needed, the public key is given to the owner of the repository)
+fn key(arguments: &[String]) {
+	let config = load_config();
+	let argument = |i: usize, usage: &str| arguments.get(i).cloned().unwrap_or_else(|| panic!("Usage: key {}", usage));
+
+	match arguments.get(0).map(|a| a.as_str()) {
+		Some("list") => keys::list_slots(&config),
+		Some("add") => {
+			let usage = "add <name> --passphrase | --key-file <path> | --recipient <public key>";
+			let name = argument(1, usage);
+			if arguments.iter().any(|a| a == "--passphrase") {
+				keys::add_passphrase_slot(&config, &name);
+			} else if let Some(path) = get_argument_value(arguments, "--key-file") {
+				keys::add_key_file_slot(&config, &name, Path::new(&path));
+			} else if let Some(public_key) = get_argument_value(arguments, "--recipient") {
+				keys::add_recipient_slot(&config, &name, &public_key);
+			} else {
+				panic!("Usage: key {}", usage);
+			}
+		},
+		Some("remove") => keys::remove_slot(&config, &argument(1, "remove <name>")),
+		Some("change-passphrase") => keys::change_passphrase(&config, &arguments.get(1).cloned().unwrap_or(String::from("passphrase"))),
+		Some("export-recovery") => keys::export_recovery_key(&config, Path::new(&argument(1, "export-recovery <path>"))),
+		Some("public-key") => keys::print_public_key(Path::new(&argument(1, "public-key <key file>"))),
+		_ => println!("Unknown key command, use list, add, remove, change-passphrase, export-recovery or public-key")
+	}
+}
+
+fn migrate_remote_store(arguments: &[String]) {
+	let config = load_remote_config(arguments);
+	let transport = transport::open_transport(&config);
+	let _remote_lock = lock::RemoteLock::acquire(&*transport, config.get_remote_lock_lease()).expect("Cannot lock the remote");
+	transfer::migrate_remote_store(&*transport).expect("Cannot migrate the remote store");
 }
 
 /// Update the working directory using the local metadata and the local store
@@ -64,63 +868,200 @@ fn new_repo() {
 /// Step 2: extract files from store
 fn update() {
 	let config = load_config();
-	let data_path = config.get_data_path();
 	let json_path = config.get_local_metadata_path();
 	let store_path = config.get_store_path();
+	let key = keys::load_key(&config);
 
-	let wd_hierarchy : HashMap<String, model::MetaData> = workingdirectory::read_working_directory(&data_path);
+	let wd_hierarchy : HashMap<String, model::MetaData> = workingdirectory::read_sources(&config.get_sources());
 	println!("{} files in the working directory", wd_hierarchy.len());
 
-	let mt_hierarchy = metadata::read_metadata_file(&json_path);
+	let mt_hierarchy = metadata::read_metadata_file(&json_path, key.as_ref());
 	println!("{} files in the metadata", mt_hierarchy.get_number_of_files());
 
-	let file_to_update = files_to_update(wd_hierarchy, &mt_hierarchy);
+	let file_to_update = files_to_update(wd_hierarchy, &mt_hierarchy, &sparse::SparseSet::from_config(&config));
+
+	let context = || hooks::HookContext::new().with_revision(mt_hierarchy.get_number_of_revisions()).with_files(file_to_update.keys());
+	if !hooks::run_hook(&config, hooks::Hook::PreUpdate, context()) {
+		println!("Update aborted by the pre-update hook");
+		return;
+	}
 
-	for (filename, metadata) in file_to_update.iter() {
-		store::extract_file(&store_path, &metadata.get_hash(), &data_path, filename, metadata.get_timestamp());
+	let packed_objects = pack::read_all_indexes(&store_path);
+	let nb_failed = file_to_update.iter()
+		.filter(|&(filename, metadata)| !extract_file(&config, &packed_objects, key.as_ref(), filename, &metadata.get_hash(), metadata.get_timestamp()))
+		.count();
+	if nb_failed > 0 {
+		report_error(&config, &format!("{} files could not be extracted", nb_failed));
+		return;
 	}
+	hooks::run_hook(&config, hooks::Hook::PostUpdate, context());
 }
 
-fn update_remote() {
-	let config = load_config();
-	let data_path = config.get_data_path();
+/// Extract a file of the metadata in the root of its source, the files of a source removed from the config are skipped
+/// Return false if the file could not be extracted, the file in the working directory is then unchanged
+fn extract_file(config: &config::Config, packed_objects: &pack::PackedObjects, key: Option<&cipher::Key>, filename: &str, hash: &String, timestamp: u64) -> bool {
+	match workingdirectory::get_file_path(&config.get_sources(), filename) {
+		Some(path) => match store::extract_file(&config.get_store_path(), packed_objects, key, hash, &path, timestamp) {
+			Ok(()) => true,
+			Err(e) => {
+				println!("- FAILED to extract {}: {}", filename, e);
+				false
+			}
+		},
+		None => {
+			println!("- No source for {}, not extracted", filename);
+			true
+		}
+	}
+}
+
+/// Full round trip with the remote, in an order which keeps the remote consistent if a step fails:
+/// 1. download the remote metadata, then the objects it references (they are pushed before the metadata)
+/// 2. apply the remote changes to the working directory, stop on conflict
+/// 3. commit the working directory
+/// 4. upload the objects, stop if one is missing on the remote
+/// 5. upload the metadata, only if the remote metadata has not changed since step 1
+fn sync(arguments: &[String]) {
+	let config = load_remote_config(arguments);
+	let transport = transport::open_transport(&config);
+	fetch_key_file(&config, &*transport);
+	let key = keys::load_key(&config);
+	sync_with_remote(&config, &*transport, key.as_ref(), &get_argument_value(arguments, "-m").unwrap_or(String::new()));
+}
+
+/// Return false if the sync stopped before the end
+fn sync_with_remote(config: &config::Config, transport: &dyn transport::Transport, key: Option<&cipher::Key>, message: &str) -> bool {
+	let remote_has_metadata = download_remote_metadata(config, transport);
+	if !fetch_objects(config, transport, key) {
+		return report_error(config, "Sync stopped: some remote objects could not be downloaded");
+	}
+	if remote_has_metadata && !apply_remote_metadata(config, key) {
+		return report_error(config, "Sync stopped: conflict between the remote and the working directory");
+	}
+	if !check_no_conflict(config, key) {
+		return report_error(config, "Sync stopped before the push: some files are in conflict, run resolve <file> [--theirs]");
+	}
+
+	if !commit_working_directory(config, key, message) {
+		return report_error(config, "Sync stopped: the commit was aborted by the pre-commit hook");
+	}
+
+	if !hooks::run_hook(config, hooks::Hook::PrePush, push_context(config, key)) {
+		return report_error(config, "Sync stopped: the push was aborted by the pre-push hook, the remote metadata is unchanged");
+	}
+	if !push_objects(config, transport, key) {
+		return report_error(config, "Sync stopped: some objects could not be uploaded, the remote metadata is unchanged");
+	}
+	if !push_metadata(config, transport) {
+		return report_error(config, "Sync stopped: the remote metadata has changed during the sync, run sync again");
+	}
+	hooks::run_hook(config, hooks::Hook::PostPush, push_context(config, key));
+	println!("Sync done");
+	true
+}
+
+fn update_remote(arguments: &[String]) {
+	let config = load_remote_config(arguments);
+	let key = keys::load_key(&config);
+
+	let transport = transport::open_transport(&config);
+	if download_remote_metadata(&config, &*transport) {
+		apply_remote_metadata(&config, key.as_ref());
+	} else {
+		println!("No metadata on the remote");
+	}
+}
+
+/// Download the remote metadata and remember its version, false if the remote has no metadata yet
+fn download_remote_metadata(config: &config::Config, transport: &dyn transport::Transport) -> bool {
 	let json_path = config.get_local_metadata_path();
 	let json_remote_path = config.get_remote_metadata_path();
-	let store_path = config.get_store_path();
+	let remote_filename = get_remote_metadata_filename(&json_path);
 
-    let remote_filename = json_path.file_name().unwrap().to_str().unwrap(); //on the remote, it uses the local path...
-	let on_server_path = config.get_ssh_root_path().join(&a);
+	if transport.stat(&remote_filename).expect("Cannot read remote metadata").is_none() {
+		return false;
+	}
+	//the etag is read before the download: if the file changes in between, the next push is refused instead of overwriting
+	let remote_version = transport.get_version(&remote_filename).expect("Cannot read remote metadata version");
+	transfer::download_file(transport, &remote_filename, &json_remote_path).expect("Cannot download remote metadata");
+	let remote_version = match remote_version {
+		Some(version) => version,
+		None => transfer::get_content_version(&json_remote_path).expect("Cannot read remote metadata")
+	};
+	metadata::write_version_file(&json_remote_path, Some(remote_version));
+	true
+}
 
-	sshengine::download_file_from_remote(&config.get_ssh_address(), &config.get_ssh_user(), &config.get_ssh_password(), &on_server_path, &json_remote_path);
+/// Extract the files changed in the downloaded remote metadata and make it the local metadata
+/// Return false on conflict, nothing is changed
+fn apply_remote_metadata(config: &config::Config, key: Option<&cipher::Key>) -> bool {
+	let json_path = config.get_local_metadata_path();
+	let json_remote_path = config.get_remote_metadata_path();
+	let store_path = config.get_store_path();
 
-	let wd_hierarchy : HashMap<String, model::MetaData> = workingdirectory::read_working_directory(&data_path);
+	let wd_hierarchy : HashMap<String, model::MetaData> = workingdirectory::read_sources(&config.get_sources());
 	println!("{} files in the working directory", wd_hierarchy.len());
 
-	let mt_hierarchy = metadata::read_metadata_file(&json_path);
+	let mt_hierarchy = metadata::read_metadata_file(&json_path, key);
 	println!("{} files in the metadata", mt_hierarchy.get_number_of_files());
 
-	let mt_remote_hierarchy = metadata::read_metadata_file(&json_remote_path);
+	let mt_remote_hierarchy = metadata::read_metadata_file(&json_remote_path, key);
 	println!("{} files in the remote metadata", mt_remote_hierarchy.get_number_of_files());
 
-	let file_top_update = files_to_update_remote(wd_hierarchy, &mt_hierarchy, &mt_remote_hierarchy);
+	let base_path = metadata::get_base_path(&json_remote_path);
+	let mt_base_hierarchy = metadata::try_read_metadata_file(&base_path, key);
+	if mt_base_hierarchy.is_none() {
+		println!("No metadata of a previous sync, the merge relies on the histories only");
+	}
+
+	let (mt_merged_hierarchy, conflicts) = mt_hierarchy.merge(&mt_remote_hierarchy, mt_base_hierarchy.as_ref());
+	for filename in conflicts.iter() {
+		println!("- CONFLICT ! Both sides committed a new version of {}, the local one stays until `resolve {}`", filename, filename);
+	}
+	if !conflicts.is_empty() {
+		hooks::run_hook(config, hooks::Hook::OnConflict, hooks::HookContext::new().with_files(conflicts.iter()));
+	}
+
+	let file_top_update = files_to_update_merged(wd_hierarchy, &mt_hierarchy, &mt_merged_hierarchy, &sparse::SparseSet::from_config(config));
 	match file_top_update {
 		Some(x) => {
+			let context = || hooks::HookContext::new().with_revision(mt_merged_hierarchy.get_number_of_revisions()).with_files(x.keys());
+			if !hooks::run_hook(config, hooks::Hook::PreUpdate, context()) {
+				println!("Update aborted by the pre-update hook");
+				return false;
+			}
+
 			println!("OK to update");
-			for (filename, metadata) in x.iter() {
-				store::extract_file(&store_path, &metadata.get_hash(), &data_path, filename, metadata.get_timestamp());
+			let packed_objects = pack::read_all_indexes(&store_path);
+			let nb_failed = x.iter()
+				.filter(|&(filename, metadata)| !extract_file(config, &packed_objects, key, filename, &metadata.get_hash(), metadata.get_timestamp()))
+				.count();
+			if nb_failed > 0 {
+				//the merged metadata is not written, the next sync extracts the files again
+				println!("{} files could not be extracted", nb_failed);
+				return false;
 			}
-			println!("Replace local metadata by remote metadata");
-			remove_file(&json_path);
-			copy(&json_remote_path, &json_path);
+			println!("Write the merged metadata");
+			metadata::write_metadata_file_as(&json_path, &mt_merged_hierarchy, key, metadata::get_metadata_format(&json_path), config.get_metadata_generations());
+			metadata::replace_metadata_file(&json_remote_path, &base_path, 0);
+			hooks::run_hook(config, hooks::Hook::PostUpdate, context());
+			true
 		},
-		None => println!("IMPOSSIBLE to update")
+		None => {
+			println!("IMPOSSIBLE to update");
+			hooks::run_hook(config, hooks::Hook::OnConflict, hooks::HookContext::new().with_error("a file changed in the working directory and on the remote"));
+			false
+		}
 	}
 }
 
-fn files_to_update(wd_hierarchy: HashMap<String, model::MetaData>, mt_hierarchy: &model::Hierarchy) -> HashMap<String, model::MetaData>  {
+fn files_to_update(wd_hierarchy: HashMap<String, model::MetaData>, mt_hierarchy: &model::Hierarchy, sparse_set: &sparse::SparseSet) -> HashMap<String, model::MetaData>  {
 	let mut file_to_update : HashMap<String, model::MetaData> = HashMap::new();
 
 	for (filename, metadataset) in mt_hierarchy.get_files().iter() {
+		if !sparse_set.contains(filename) {
+			continue;
+		}
 		let wd_metadata = wd_hierarchy.get(filename);
 
 		match wd_metadata {
@@ -151,103 +1092,238 @@ fn files_to_update(wd_hierarchy: HashMap<String, model::MetaData>, mt_hierarchy:
 	file_to_update	
 }
 
-fn files_to_update_remote(wd_hierarchy: HashMap<String, model::MetaData>, mt_local_hierarchy: &model::Hierarchy, mt_remote_hierarchy: &model::Hierarchy) -> Option<HashMap<String, model::MetaData>>  {
+/// Files to extract after a merge: the files whose last revision is not the local one anymore
+/// None if such a file has been changed in the working directory and not committed, it would be lost
+fn files_to_update_merged(wd_hierarchy: HashMap<String, model::MetaData>, mt_local_hierarchy: &model::Hierarchy, mt_merged_hierarchy: &model::Hierarchy, sparse_set: &sparse::SparseSet) -> Option<HashMap<String, model::MetaData>>  {
 	let mut file_to_update : HashMap<String, model::MetaData> = HashMap::new();
 
-	for (filename, metadataset) in mt_remote_hierarchy.get_files().iter() {
-		let wd_metadata = wd_hierarchy.get(filename);
-
-		match wd_metadata {
-			Some(x) => {
-				let wd_timestamp = x.get_timestamp();
-				let last_md_timestamp = metadataset.get_last().unwrap().get_timestamp();
+	for (filename, metadataset) in mt_merged_hierarchy.get_files().iter() {
+		if !sparse_set.contains(filename) {
+			continue;
+		}
+		let merged_metadata = metadataset.get_last().unwrap();
+		let local_metadata = mt_local_hierarchy.get_latest_meta_data(filename);
+		if local_metadata == Some(merged_metadata) {
+			continue;
+		}
 
-				if wd_timestamp == last_md_timestamp {
-					println!("- No need to update because timestamps are equal {}", filename);
-				} else if metadataset.has_metadata_with_timestamp(wd_timestamp) {
-					println!("- Existing file to update {}", filename);
-					file_to_update.insert(filename.clone(), metadataset.get_last().unwrap().clone());
-				} else { //timestamp unknown
-					
-					match mt_local_hierarchy.get_latest_meta_data(&filename) {
-						Some(m) => {
-							if m.get_timestamp() == last_md_timestamp {
-								println!("- No need to update because working directory file is a correct new version {}", filename);
-							} else {
-								println!("- CONFLICT ! The remove and the working directory file have changed {}", filename);
-								return Option::None;
-							}
-						}
-						None => {
-							println!("- CONFLICT ! New file in the working directory but a remote version exists {}", filename);
-							return Option::None;
-						}
-					};
-				}
-			    ()},
-			None => {
-				println!("- New file to update {}", filename);
-			    file_to_update.insert(filename.clone(), metadataset.get_last().unwrap().clone());
-			    () }
+		match wd_hierarchy.get(filename) {
+			Some(x) if x.get_timestamp() == merged_metadata.get_timestamp() => {
+				println!("- No need to update because timestamps are equal {}", filename);
+			},
+			Some(x) if local_metadata.map_or(true, |m| m.get_timestamp() != x.get_timestamp()) => {
+				println!("- CONFLICT ! The remote and the working directory file have changed {}", filename);
+				return Option::None;
+			},
+			_ => {
+				println!("- File to update {}", filename);
+				file_to_update.insert(filename.clone(), merged_metadata.clone());
+			}
 		}
 	}
 
 	Option::Some(file_to_update)
 }
 
-fn commit_remote() {
-	commit();
+/// Print the files in conflict since the last merge, false if there is any
+/// Pushing them would replace the remote revision by the local one without anybody choosing
+fn check_no_conflict(config: &config::Config, key: Option<&cipher::Key>) -> bool {
+	let hierarchy = metadata::read_metadata_file(&config.get_local_metadata_path(), key);
+	for (filename, theirs) in hierarchy.get_conflicts() {
+		println!("- CONFLICT on {}, the remote revision comes from {}", filename, theirs.get_origin());
+	}
+	hierarchy.get_conflicts().is_empty()
+}
 
+/// resolve <file> [--theirs] [-m message]
+/// Commit the file of the working directory (edited by hand, or replaced by the remote revision with --theirs)
+/// as a revision descending from both sides of the conflict, the next sync can then push it
+fn resolve(arguments: &[String]) {
 	let config = load_config();
+	let key = keys::load_key(&config);
 	let json_path = config.get_local_metadata_path();
-	let json_remote_path = config.get_remote_metadata_path();
+	let store_path = config.get_store_path();
+	let sources = config.get_sources();
+	let filename = arguments.get(0).expect("Usage: resolve <file> [--theirs] [-m message]").trim_start_matches("./").to_string();
+
+	let mut hierarchy = metadata::read_metadata_file(&json_path, key.as_ref());
+	let theirs = match hierarchy.get_conflicts().get(&filename) {
+		Some(theirs) => theirs.clone(),
+		None => {
+			println!("No conflict on {}", filename);
+			return;
+		}
+	};
+
+	if arguments.iter().any(|a| a == "--theirs") {
+		let packed_objects = pack::read_all_indexes(&store_path);
+		if !extract_file(&config, &packed_objects, key.as_ref(), &filename, &theirs.get_hash(), time::get_time().sec as u64) {
+			println!("The conflict on {} is not resolved", filename);
+			return;
+		}
+	}
 
-    //upload_to_remote(adress: &str, user: &str, password: &str, remote_root_path: &Path, files_path: &Vec<PathBuf>)
+	let filenames : HashSet<String> = vec![filename.clone()].into_iter().collect();
+	let mut metadata = match workingdirectory::read_files(&sources, &filenames).remove(&filename) {
+		Some(metadata) => metadata,
+		None => {
+			println!("{} is not in the working directory", filename);
+			return;
+		}
+	};
+	let file_path = workingdirectory::get_file_path(&sources, &filename).unwrap();
+	let hash = store::store_file(&store_path, &file_path, key.as_ref(), codec::choose_codec(&config, &file_path)).expect("Cannot store file");
+	pack::pack_loose_objects(&store_path, config.get_pack_threshold());
+
+	let machine_id = machine::get_machine_id(&store_path);
+	metadata.add_hash(hash);
+	metadata.set_origin_resolving(&machine_id, hierarchy.get_latest_meta_data(&filename), &theirs);
+
+	let message = get_argument_value(arguments, "-m").unwrap_or(format!("resolve {}", filename));
+	let commit = new_commit(&hierarchy, &message, machine_id);
+	metadata.set_commit(&commit);
+	let mut changes : HashMap<String, model::MetaData> = HashMap::new();
+	changes.insert(filename.clone(), metadata);
+	metadata::commit_revision(&json_path, &mut hierarchy, commit, changes, key.as_ref(), config.get_metadata_generations());
+	println!("Conflict on {} resolved, {} conflicts left", filename, hierarchy.get_conflicts().len());
+}
 
-    	
+/// Description of the next commit: this machine, the user and the time
+fn new_commit(hierarchy: &model::Hierarchy, message: &str, machine_id: String) -> model::Commit {
+	let user = env::var("USER").or_else(|_| env::var("LOGNAME")).unwrap_or(String::from("unknown"));
+	hierarchy.new_commit(message, lock::get_hostname(), user, machine_id, time::get_time().sec)
+}
 
-	let files = vec![json_path];
+/// Commit then push, the objects are on the remote before the metadata refers to them
+fn commit_remote(arguments: &[String]) {
+	let config = load_remote_config(arguments);
+	let key = keys::load_key(&config);
+	if commit_working_directory(&config, key.as_ref(), &get_argument_value(arguments, "-m").unwrap_or(String::new())) {
+		push_to_remote(&config, key.as_ref());
+	}
+}
 
-	sshengine::upload_to_remote(&config.get_ssh_address(), &config.get_ssh_user(), &config.get_ssh_password(), &config.get_ssh_root_path(), &files);
+/// Upload the local metadata if the remote metadata has not changed since it was downloaded (compare-and-swap)
+/// The version of the remote metadata is its etag, or the sha256 of its content for the remotes without etag
+/// (kept in a sidecar file so the metadata is not downloaded at each push)
+/// The check and the upload are done under the remote lock, s3 also checks the etag when writing
+/// Return false if the remote has changed
+fn push_metadata(config: &config::Config, transport: &dyn transport::Transport) -> bool {
+	let json_path = config.get_local_metadata_path();
+	let json_remote_path = config.get_remote_metadata_path();
 
+	let remote_filename = get_remote_metadata_filename(&json_path);
 
-	//remove_file(&json_remote_path);
-	//copy(&json_path, &json_remote_path);
+	let _remote_lock = lock::RemoteLock::acquire(transport, config.get_remote_lock_lease()).expect("Cannot lock the remote");
+	if let Some(parent) = remote_filename.parent() {
+		transport::create_remote_dirs(transport, parent).expect("Cannot create remote directory");
+	}
+
+	let expected_version = metadata::read_version_file(&json_remote_path);
+	let current_version = transfer::get_remote_version(transport, &remote_filename, &expected_version).expect("Cannot read remote metadata version");
+	let uploaded = current_version == expected_version && {
+		transfer::upload_version_sidecar(transport, &json_path, &remote_filename).expect("Cannot upload metadata version");
+		transport.put_if_version(&json_path, &remote_filename, expected_version).expect("Cannot upload metadata")
+	};
+	if uploaded {
+		let remote_version = match transport.get_version(&remote_filename).expect("Cannot read remote metadata version") {
+			Some(version) => version,
+			None => transfer::get_content_version(&json_path).expect("Cannot read local metadata")
+		};
+		metadata::write_version_file(&json_remote_path, Some(remote_version));
+		metadata::replace_metadata_file(&json_path, &metadata::get_base_path(&json_remote_path), 0);
+	} else {
+		println!("The remote metadata has changed since it was last downloaded, run sync first");
+	}
+	uploaded
+}
+
+/// On the remote, the metadata file keeps the relative path of the local metadata file
+fn get_remote_metadata_filename(json_path: &Path) -> PathBuf {
+	if json_path.is_absolute() {
+		PathBuf::from(json_path.file_name().unwrap())
+	} else {
+		json_path.to_path_buf()
+	}
 }
 
 /// Commit the working directory in the local metadata and in the local store
 /// - If the file does not exist in the metadata, add it
 /// - If the working directory timestamp is greater than the medtadata timestamp, add the new revision in the metadata and in the store
 /// - If the working directory timestamp is lower than the metadata timestamp, do nothing (this should no happen if "update" is run before commit)
-fn commit() {
+/// commit [-m message]
+fn commit(arguments: &[String]) {
 	let config = load_config();
-	let data_path = config.get_data_path();
+	let key = keys::load_key(&config);
+	commit_working_directory(&config, key.as_ref(), &get_argument_value(arguments, "-m").unwrap_or(String::new()));
+}
+
+/// Commit the changed files, the commit is described by the message, this machine and the time
+/// Files of all the sources share the store, the same content is stored once
+/// Return false if the pre-commit hook failed, nothing is committed
+fn commit_working_directory(config: &config::Config, key: Option<&cipher::Key>, message: &str) -> bool {
+	commit_files(config, key, message, None)
+}
+
+/// Commit the changed files among the given ones, or among all the files of the sources
+/// The pre-commit hook runs before the files are read, so what it writes (a database dump...) is committed
+fn commit_files(config: &config::Config, key: Option<&cipher::Key>, message: &str, filenames: Option<&HashSet<String>>) -> bool {
 	let json_path = config.get_local_metadata_path();
 	let store_path = config.get_store_path();
+	let sources = config.get_sources();
+
+	if !hooks::run_hook(config, hooks::Hook::PreCommit, hooks::HookContext::new()) {
+		println!("Commit aborted by the pre-commit hook");
+		return false;
+	}
 
-	let wd_hierarchy : HashMap<String, model::MetaData> = workingdirectory::read_working_directory(&data_path);
+	let mut wd_hierarchy : HashMap<String, model::MetaData> = match filenames {
+		Some(filenames) => workingdirectory::read_files(&sources, filenames),
+		None => workingdirectory::read_sources(&sources)
+	};
 	println!("{} files in the working directory", wd_hierarchy.len());
 
-	let mt_hierarchy = metadata::read_metadata_file(&json_path);
+	//the files left outside the sparse set (modified before being removed from it) are not committed
+	let sparse_set = sparse::SparseSet::from_config(config);
+	let nb_files = wd_hierarchy.len();
+	wd_hierarchy.retain(|filename, _| sparse_set.contains(filename));
+	if wd_hierarchy.len() < nb_files {
+		println!("{} files outside the sparse set are not committed", nb_files - wd_hierarchy.len());
+	}
+
+	let mt_hierarchy = metadata::read_metadata_file(&json_path, key);
 	println!("{} files in the metadata", mt_hierarchy.get_number_of_files());
 
 	let files_to_commit = files_to_commit(wd_hierarchy, &mt_hierarchy);
 	println!("{} files to commit", files_to_commit.len());
 
+	let machine_id = machine::get_machine_id(&store_path);
+	let commit = new_commit(&mt_hierarchy, message, machine_id.clone());
 	let mut updated_metadata : HashMap<String, model::MetaData> = HashMap::new();
 	for (filename, mut metadata) in files_to_commit {
-		let hash = store::store_file(&store_path, Path::new(&filename));
+		if daemon::is_stopping() {
+			println!("Stop requested, commit the {} files already stored", updated_metadata.len());
+			break;
+		}
+		let file_path = workingdirectory::get_file_path(&sources, &filename).unwrap();
+		let codec_settings = codec::choose_codec(config, &file_path);
+		let hash = store::store_file(&store_path, &file_path, key, codec_settings).expect("Cannot store file");
 		metadata.add_hash(hash);
+		metadata.set_origin(&machine_id, mt_hierarchy.get_latest_meta_data(&filename));
+		metadata.set_commit(&commit);
 
 		updated_metadata.insert(filename, metadata);
 	}	
 	let updated_metadata = updated_metadata;
 
+	pack::pack_loose_objects(&store_path, config.get_pack_threshold());
 
+	let committed_files : Vec<String> = updated_metadata.keys().cloned().collect();
 	let mut mt_hierarchy = mt_hierarchy;
-	mt_hierarchy.update(updated_metadata);
+	metadata::commit_revision(&json_path, &mut mt_hierarchy, commit, updated_metadata, key, config.get_metadata_generations());
 
-	metadata::write_metadata_file(&json_path, mt_hierarchy);
+	hooks::run_hook(config, hooks::Hook::PostCommit, hooks::HookContext::new().with_revision(mt_hierarchy.get_number_of_revisions()).with_files(committed_files.iter()));
+	true
 }
 
 fn files_to_commit(wd_hierarchy: HashMap<String, model::MetaData>, mt_hierarchy: &model::Hierarchy) -> HashMap<String, model::MetaData>  {
@@ -275,30 +1351,280 @@ fn files_to_commit(wd_hierarchy: HashMap<String, model::MetaData>, mt_hierarchy:
 	files_to_commit
 }
 
-fn copy_to_remote() {
-	let config = load_config();
+/// Upload the objects of the local store which are not on the remote
+/// Store objects are immutable and named by their hash, so an object with the same name is the same object
+fn copy_to_remote(arguments: &[String]) {
+	let config = load_remote_config(arguments);
+	let key = keys::load_key(&config);
+
+	let transport = transport::open_transport(&config);
+	push_objects(&config, &*transport, key.as_ref());
+}
+
+/// Return false if some objects are not on the remote (failed upload or corrupted local object)
+fn push_objects(config: &config::Config, transport: &dyn transport::Transport, key: Option<&cipher::Key>) -> bool {
 	let store_path = config.get_store_path();
+	if key.is_some() {
+		//the wrapped key is needed on another machine to read the remote
+		transfer::upload_file(transport, &keys::get_keys_path(&store_path), Path::new(keys::KEYS_FILE)).expect("Cannot upload key file");
+	}
+	let remote_objects = list_remote_objects(transport);
+	println!("{} objects on the remote", remote_objects.len());
+
+	//an empty remote takes the layout of the local store, a remote without version file is flat
+	let remote_version = match transfer::read_remote_store_version(transport).expect("Cannot read remote store version") {
+		Some(version) => version,
+		None if remote_objects.is_empty() => {
+			let version = store::get_store_version(&store_path);
+			transfer::write_remote_store_version(transport, version).expect("Cannot write remote store version");
+			version
+		},
+		None => store::FLAT_LAYOUT
+	};
+
+	//the objects of a pack already on the remote need no loose copy there
+	let remote_packs = list_remote_packs(transport);
+	let remotely_packed : HashSet<String> = pack::read_all_indexes(&store_path).into_iter()
+		.filter(|&(_, ref object)| remote_packs.contains(object.get_pack_path().file_name().unwrap().to_str().unwrap()))
+		.map(|(hash, _)| hash)
+		.collect();
+
+	let mut nb_corrupted = 0;
+	let mut objects_to_upload : Vec<(PathBuf, PathBuf)> = Vec::new();
+	let mut remote_dirs : HashSet<PathBuf> = HashSet::new();
+	for file_path in store::get_all_filenames(&store_path) {
+		let hash = file_path.file_name().unwrap().to_str().unwrap().to_string();
+		if !store::is_object_name(&hash) || remote_objects.contains_key(&hash) || remotely_packed.contains(&hash) {
+			continue;
+		}
+
+		if !store::check_file(&file_path, key, &hash) {
+			println!("- CORRUPTED object in the local store, not uploaded {}", hash);
+			nb_corrupted = nb_corrupted + 1;
+			continue;
+		}
+
+		let remote_path = store::get_object_relative_path(remote_version, &hash);
+		if let Some(parent) = remote_path.parent() {
+			remote_dirs.insert(parent.to_path_buf());
+		}
+		objects_to_upload.push((file_path, remote_path));
+	}
 
-	let files = store::get_all_filenames(&store_path);
+	for remote_dir in remote_dirs {
+		transport::create_remote_dirs(transport, &remote_dir).expect("Cannot create remote directory");
+	}
+
+	let (nb_uploaded, nb_failed) = transfer::upload_objects(config, objects_to_upload);
+	println!("{} objects uploaded, {} failed", nb_uploaded, nb_failed);
 
-	sshengine::upload_to_remote(&config.get_ssh_address(), &config.get_ssh_user(), &config.get_ssh_password(), &config.get_ssh_root_path(), &files);
+	//the packs are shipped whole
+	let packs_path = Path::new(pack::PACKS_DIRECTORY);
+	let mut nb_packs = 0;
+	for pack_path in pack::get_all_packs(&store_path) {
+		let pack_name = pack_path.file_name().unwrap().to_str().unwrap().to_string();
+		if remote_packs.contains(&pack_name) {
+			continue;
+		}
+		if nb_packs == 0 {
+			transport::create_remote_dirs(transport, packs_path).expect("Cannot create remote directory");
+		}
+
+		println!("- Upload {}", pack_name);
+		transfer::upload_pack(transport, &pack_path, &packs_path.join(&pack_name)).expect("Cannot upload pack");
+		nb_packs = nb_packs + 1;
+	}
+	println!("{} packs uploaded", nb_packs);
+
+	if let Err(e) = remove_superseded_packs(config, transport, &remote_packs) {
+		println!("Cannot remove the superseded remote packs: {}", e);
+		return false;
+	}
+
+	nb_failed == 0 && nb_corrupted == 0
 }
 
-fn download_from_remote() {
-	let config = load_config();
+/// The packs replaced by a repack go once the new pack is on the remote, the index first
+/// The deletion is done under the remote lock, a pack not removed stays superseded for the next push
+fn remove_superseded_packs(config: &config::Config, transport: &dyn transport::Transport, remote_packs: &HashSet<String>) -> io::Result<()> {
+	let store_path = config.get_store_path();
+	let packs_path = Path::new(pack::PACKS_DIRECTORY);
+
+	let mut superseded_packs = pack::read_superseded_packs(&store_path);
+	if superseded_packs.is_empty() {
+		return Ok(());
+	}
+
+	let _remote_lock = lock::RemoteLock::acquire(transport, config.get_remote_lock_lease())?;
+	for pack_name in superseded_packs.clone() {
+		if remote_packs.contains(&pack_name) {
+			println!("- Remove superseded {}", pack_name);
+			let remote_pack_path = packs_path.join(&pack_name);
+			let removed = transport.delete(&pack::get_index_path(&remote_pack_path))
+				.and_then(|_| transport.delete(&remote_pack_path));
+			if let Err(e) = removed {
+				pack::write_superseded_packs(&store_path, &superseded_packs);
+				return Err(e);
+			}
+		}
+		superseded_packs.remove(&pack_name);
+	}
+	pack::write_superseded_packs(&store_path, &superseded_packs);
+	Ok(())
+}
+
+/// Download the objects of the remote which are not in the local store
+/// Each downloaded object is checked against its hash before taking its final name, a corrupted object is removed
+fn download_from_remote(arguments: &[String]) {
+	let config = load_remote_config(arguments);
+
+	let transport = transport::open_transport(&config);
+	fetch_key_file(&config, &*transport);
+	let key = keys::load_key(&config);
+
+	fetch_objects(&config, &*transport, key.as_ref());
+}
+
+/// The key file of an encrypted repository comes from the remote on a new machine
+fn fetch_key_file(config: &config::Config, transport: &dyn transport::Transport) {
+	let store_path = config.get_store_path();
+	let keys_path = keys::get_keys_path(&store_path);
+	if keys::is_encrypted(config) && !keys_path.is_file() {
+		create_dir_all(&store_path).expect("Cannot create store directory");
+		transfer::download_file(transport, Path::new(keys::KEYS_FILE), &keys_path).expect("Cannot download key file");
+	}
+}
+
+/// Return false if some remote objects could not be downloaded
+fn fetch_objects(config: &config::Config, transport: &dyn transport::Transport, key: Option<&cipher::Key>) -> bool {
 	let store_path = config.get_store_path();
 
-	sshengine::download_folder_from_remote(&config.get_ssh_address(), &config.get_ssh_user(), &config.get_ssh_password(), &config.get_ssh_root_path(), &store_path);
+	//an object already in a local pack is not downloaded again
+	let mut local_objects : HashSet<String> = store::get_all_filenames(&store_path).iter()
+		.map(|p| p.file_name().unwrap().to_str().unwrap().to_string())
+		.collect();
+	local_objects.extend(pack::read_all_indexes(&store_path).into_iter().map(|(hash, _)| hash));
+
+	let remote_objects = list_remote_objects(transport);
+	println!("{} objects on the remote", remote_objects.len());
+
+	let mut objects_to_download : Vec<(PathBuf, PathBuf, String)> = Vec::new();
+	for (hash, remote_path) in remote_objects {
+		if local_objects.contains(&hash) {
+			continue;
+		}
+
+		let local_path = store::get_object_path(&store_path, &hash);
+		create_dir_all(local_path.parent().unwrap()).expect("Cannot create store directory");
+		objects_to_download.push((remote_path, local_path, hash));
+	}
+
+	let (nb_downloaded, nb_failed) = transfer::download_objects(config, key, objects_to_download);
+	println!("{} objects downloaded, {} failed", nb_downloaded, nb_failed);
+
+	let local_packs : HashSet<PathBuf> = pack::get_all_packs(&store_path).into_iter().collect();
+	let superseded_packs = pack::read_superseded_packs(&store_path);
+	let packs_path = pack::get_packs_path(&store_path);
+	let mut nb_packs = 0;
+	let mut nb_corrupted_packs = 0;
+	for pack_name in list_remote_packs(transport) {
+		let local_pack_path = packs_path.join(&pack_name);
+		if local_packs.contains(&local_pack_path) || superseded_packs.contains(&pack_name) {
+			continue;
+		}
+		create_dir_all(&packs_path).expect("Cannot create store directory");
+
+		println!("- Download {}", pack_name);
+		let remote_pack_path = Path::new(pack::PACKS_DIRECTORY).join(&pack_name);
+		if transfer::download_pack(transport, &remote_pack_path, &local_pack_path).expect("Cannot download pack") {
+			nb_packs = nb_packs + 1;
+		} else {
+			println!("- CORRUPTED pack downloaded, removed {}", pack_name);
+			nb_corrupted_packs = nb_corrupted_packs + 1;
+		}
+	}
+	println!("{} packs downloaded", nb_packs);
+
+	nb_failed == 0 && nb_corrupted_packs == 0
+}
+
+/// All the objects of the remote (hash and path), in any sub directory
+fn list_remote_objects(transport: &dyn transport::Transport) -> HashMap<String, PathBuf> {
+	let files = transport::list_remote_files(transport, Path::new("")).expect("Cannot read remote directory");
+
+	files.into_iter()
+		.map(|(path, _)| (path.file_name().unwrap().to_str().unwrap().to_string(), path))
+		.filter(|&(ref name, _)| store::is_object_name(name))
+		.collect()
+}
+
+/// Names of the remote packs having an index
+fn list_remote_packs(transport: &dyn transport::Transport) -> HashSet<String> {
+	//the packs directory does not exist before the first pack is uploaded
+	let elements = match transport.list(Path::new(pack::PACKS_DIRECTORY)) {
+		Ok(elements) => elements,
+		Err(_) => return HashSet::new()
+	};
+
+	let names : HashSet<String> = elements.into_iter()
+		.map(|(path, _)| path.file_name().unwrap().to_str().unwrap().to_string())
+		.collect();
+
+	names.iter()
+		.filter(|name| pack::is_pack_name(name))
+		.filter(|name| names.contains(&pack::get_index_path(Path::new(name)).to_str().unwrap().to_string()))
+		.cloned()
+		.collect()
 }
 
 mod model {
 
+	use std::collections::BTreeMap;
+	use std::collections::BTreeSet;
 	use std::collections::HashMap;
+	use std::collections::HashSet;
+
+	use metadata;
 
 	#[derive(Debug, RustcEncodable, RustcDecodable)]
 	pub struct Hierarchy {
+		format_version: u32,
 		nb_revision: i32,
-	    files: HashMap<String, MetaDataSet>
+	    files: HashMap<String, MetaDataSet>,
+	    commits: Vec<Commit>,
+	    tags: BTreeMap<String, Tag>,
+	    /// Files changed on both sides since they diverged: the remote revision, the local one stays the current one until resolved
+	    conflicts: BTreeMap<String, MetaData>,
+	    /// Keys of the revisions removed by prune, by file: a merge drops them, a clone still having them does not bring them back
+	    pruned: BTreeMap<String, BTreeSet<String>>
+	}
+
+	/// Description of a commit of the repository, the commits before format version 4 have none
+	/// The revision number only orders the commits of one clone, merged clones can have the same number for different commits:
+	/// a commit is identified by its id <machine id>:<sequence number of the commits of this machine>
+	#[derive(Debug, RustcEncodable, RustcDecodable, Clone, PartialEq)]
+	pub struct Commit {
+		revision: i32,
+		/// None before format version 6, the id is then given when the hierarchy is loaded
+		id: Option<String>,
+		/// Highest sequence number of each machine among the commits known when this one was made (itself included)
+		known: Option<HashMap<String, u64>>,
+		message: String,
+		host: String,
+		user: String,
+		machine: String,
+		/// Seconds since 1970 on the committing machine
+		time: i64
+	}
+
+	/// Named snapshot: the revision of each file at a commit of the repository
+	#[derive(Debug, RustcEncodable, RustcDecodable, Clone)]
+	pub struct Tag {
+		revision: i32,
+		/// Id of the tagged commit, None for a tag of the current files
+		commit: Option<String>,
+		time: i64,
+		files: HashMap<String, MetaData>
 	}
 
 	#[derive(Debug, RustcEncodable, RustcDecodable)]
@@ -306,18 +1632,29 @@ mod model {
 	    metadata: Vec<MetaData>
 	}
 
-	#[derive(Debug, RustcEncodable, RustcDecodable, Clone)]
+	#[derive(Debug, RustcEncodable, RustcDecodable, Clone, PartialEq)]
 	pub struct MetaData {
 	   timestamp: u64,
 	   size: u64,
 	   hash: String,
-	   stored_hash: String
+	   stored_hash: String,
+	   /// Machine id of the clone which committed the revision
+	   origin: Option<String>,
+	   /// Version vector: number of revisions of the file committed by each machine, known when this one was committed
+	   version: Option<HashMap<String, u64>>,
+	   /// Revision of the repository which committed it, none before format version 4
+	   revision: Option<i32>,
+	   /// Id of the commit which committed it, none before format version 4
+	   commit: Option<String>
 	}
 
 	impl Hierarchy {
 		pub fn new_empty() -> Hierarchy {
 			let empty_hierarchy_map : HashMap<String, MetaDataSet> = HashMap::new();
-			Hierarchy {nb_revision: 1, files: empty_hierarchy_map}
+			Hierarchy {format_version: metadata::FORMAT_VERSION, nb_revision: 1, files: empty_hierarchy_map, commits: Vec::new(), tags: BTreeMap::new(), conflicts: BTreeMap::new(), pruned: BTreeMap::new()}
+		}
+		pub fn get_number_of_revisions(&self) -> i32 {
+			self.nb_revision
 		}
 		pub fn get_number_of_files(&self) -> usize {
 			self.files.len()
@@ -344,6 +1681,120 @@ mod model {
 			}
 		}
 
+		/// Add the revisions of a commit with its description
+		/// A revision descending from the remote revision of a conflict resolves it
+		pub fn commit(&mut self, commit: Commit, new_metadata_map: HashMap<String, MetaData>) {
+			for (filename, metadata) in new_metadata_map.iter() {
+				if self.conflicts.get(filename).map_or(false, |theirs| metadata.descends_from(theirs)) {
+					self.conflicts.remove(filename);
+				}
+			}
+			self.commits.push(commit);
+			self.update(new_metadata_map);
+		}
+
+		pub fn get_conflicts(&self) -> &BTreeMap<String, MetaData> {
+			&self.conflicts
+		}
+
+		pub fn get_commits(&self) -> &Vec<Commit> {
+			&self.commits
+		}
+
+		/// Description of the next commit of this clone, it knows every commit of the hierarchy
+		pub fn new_commit(&self, message: &str, host: String, user: String, machine_id: String, time: i64) -> Commit {
+			let mut known : HashMap<String, u64> = HashMap::new();
+			for (machine, sequence) in self.commits.iter().filter_map(|c| c.id.as_ref()).filter_map(|id| parse_commit_id(id)) {
+				let entry = known.entry(machine.to_string()).or_insert(0);
+				if *entry < sequence {
+					*entry = sequence;
+				}
+			}
+			let sequence = known.get(&machine_id).cloned().unwrap_or(0) + 1;
+			known.insert(machine_id.clone(), sequence);
+
+			Commit {revision: self.nb_revision + 1, id: Some(format!("{}:{}", machine_id, sequence)), known: Some(known),
+				message: message.to_string(), host: host, user: user, machine: machine_id, time: time}
+		}
+
+		/// Give an id to the commits written before format version 6, from their machine and their order
+		/// so every clone computes the same ids, and link their revisions and tags to them by revision number
+		pub fn identify_commits(&mut self) {
+			let mut sequences : HashMap<String, u64> = HashMap::new();
+			let mut ids_by_revision : HashMap<i32, String> = HashMap::new();
+			for commit in self.commits.iter_mut().filter(|c| c.id.is_none()) {
+				let sequence = {
+					let entry = sequences.entry(commit.machine.clone()).or_insert(0);
+					*entry = *entry + 1;
+					*entry
+				};
+				let id = format!("{}:{}", commit.machine, sequence);
+				commit.id = Some(id.clone());
+				commit.known = Some(sequences.clone());
+				ids_by_revision.entry(commit.revision).or_insert(id);
+			}
+			if ids_by_revision.is_empty() {
+				return;
+			}
+
+			let revisions = self.files.values_mut().flat_map(|m| m.metadata.iter_mut())
+				.chain(self.tags.values_mut().flat_map(|t| t.files.values_mut()))
+				.chain(self.conflicts.values_mut());
+			for metadata in revisions.filter(|m| m.commit.is_none()) {
+				metadata.commit = metadata.revision.and_then(|r| ids_by_revision.get(&r).cloned());
+			}
+			for tag in self.tags.values_mut().filter(|t| t.commit.is_none()) {
+				tag.commit = ids_by_revision.get(&tag.revision).cloned();
+			}
+		}
+
+		/// The commit with this id, or with this revision number if only one commit has it
+		pub fn find_commit(&self, reference: &str) -> Result<&Commit, String> {
+			if let Some(commit) = self.commits.iter().find(|c| c.id.as_ref().map_or(false, |id| id == reference)) {
+				return Ok(commit);
+			}
+			let revision = reference.parse::<i32>().map_err(|_| format!("No commit {}", reference))?;
+			let commits : Vec<&Commit> = self.commits.iter().filter(|c| c.revision == revision).collect();
+			match commits.len() {
+				0 => Err(format!("No commit {}", reference)),
+				1 => Ok(commits[0]),
+				_ => Err(format!("Revision {} is ambiguous after a merge, use one of the commit ids {}", revision,
+					commits.iter().map(|c| c.get_id()).collect::<Vec<&str>>().join(", ")))
+			}
+		}
+
+		/// The files as they were at a commit: the last revision of each file committed by a commit it knew
+		/// The revisions committed before format version 4 count as the oldest ones
+		pub fn get_snapshot(&self, commit: &Commit) -> HashMap<String, MetaData> {
+			self.files.iter()
+				.filter_map(|(filename, m)| m.metadata.iter().filter(|m| commit.knows(m)).last().map(|m| (filename.clone(), m.clone())))
+				.collect()
+		}
+
+		/// The current revision of each file
+		pub fn get_current_snapshot(&self) -> HashMap<String, MetaData> {
+			self.files.iter()
+				.filter_map(|(filename, m)| m.get_last().map(|m| (filename.clone(), m.clone())))
+				.collect()
+		}
+
+		/// Tag the snapshot of a commit, or the current files, an existing tag with the same name is replaced
+		pub fn add_tag(&mut self, name: &str, commit: Option<&Commit>, time: i64) {
+			let tag = match commit {
+				Some(commit) => Tag {revision: commit.revision, commit: commit.id.clone(), time: time, files: self.get_snapshot(commit)},
+				None => Tag {revision: self.nb_revision, commit: None, time: time, files: self.get_current_snapshot()}
+			};
+			self.tags.insert(name.to_string(), tag);
+		}
+
+		pub fn remove_tag(&mut self, name: &str) -> bool {
+			self.tags.remove(name).is_some()
+		}
+
+		pub fn get_tags(&self) -> &BTreeMap<String, Tag> {
+			&self.tags
+		}
+
 		fn new_metadata(&self, filename: &String) -> bool {
 			let actual_metadata = self.files.get(filename);
 
@@ -356,6 +1807,186 @@ mod model {
 		pub fn get_files(&self) -> &HashMap<String, MetaDataSet> {
 			&self.files
 		}
+
+		/// Hashes of all the revisions of all the files, including the tagged ones
+		pub fn get_all_hashes(&self) -> HashSet<String> {
+			self.files.values()
+				.flat_map(|m| m.metadata.iter())
+				.chain(self.tags.values().flat_map(|t| t.files.values()))
+				.chain(self.conflicts.values())
+				.map(|m| m.get_hash())
+				.collect()
+		}
+
+		/// Forget the old revisions of each file: the last keep_revisions ones and the ones modified since the given time are kept,
+		/// the tagged snapshots and the conflicts keep their own revisions
+		/// Return the number of revisions removed
+		/// The removed revisions are recorded, so that a merge with a clone which still has them does not bring them back
+		pub fn prune(&mut self, keep_revisions: usize, since: u64) -> usize {
+			let mut nb_removed = 0;
+			for (filename, set) in self.files.iter_mut() {
+				let first_kept = set.metadata.len().saturating_sub(keep_revisions.max(1));
+				let mut i = 0;
+				let (kept, removed) : (Vec<MetaData>, Vec<MetaData>) = set.metadata.drain(..).partition(|m| {
+					i = i + 1;
+					i > first_kept || m.get_timestamp() >= since
+				});
+				set.metadata = kept;
+				if !removed.is_empty() {
+					nb_removed = nb_removed + removed.len();
+					self.pruned.entry(filename.clone()).or_insert(BTreeSet::new()).extend(removed.iter().map(|m| m.get_revision_key()));
+				}
+			}
+			nb_removed
+		}
+
+		/// Three-way merge with the remote hierarchy, base is the remote hierarchy of the last sync
+		/// Each file gets the union of the revisions of both sides, its last revision is:
+		/// - the common one if both sides agree
+		/// - the side which changed since the base if only one side changed
+		/// - the local one if both sides changed: the remote revision is kept in the conflicts until a revision
+		///   descending from both is committed (resolve), the new conflicts are returned
+		/// When both last revisions have a version vector, a side changed if the other side's revision does not descend from it,
+		/// which does not depend on the clocks
+		/// Without vectors nor base (first sync), a side changed if its last revision is unknown to the other side
+		pub fn merge(&self, remote: &Hierarchy, base: Option<&Hierarchy>) -> (Hierarchy, Vec<String>) {
+			let mut files : HashMap<String, MetaDataSet> = HashMap::new();
+			let mut conflicts : BTreeMap<String, MetaData> = BTreeMap::new();
+			let mut new_conflicts : Vec<String> = Vec::new();
+
+			let filenames : HashSet<&String> = self.files.keys().chain(remote.files.keys()).collect();
+			for filename in filenames {
+				let local_set = self.files.get(filename);
+				let remote_set = remote.files.get(filename);
+				let local_last = local_set.and_then(|m| m.get_last());
+				let remote_last = remote_set.and_then(|m| m.get_last());
+
+				let (local_changed, remote_changed) = match (local_last, remote_last, base) {
+					(Some(l), Some(r), _) if l.has_origin() && r.has_origin() => (!r.descends_from(l), !l.descends_from(r)),
+					(_, _, Some(base)) => {
+						let base_last = base.get_latest_meta_data(filename);
+						(local_last != base_last, remote_last != base_last)
+					},
+					(_, _, None) => (
+						remote_set.map_or(true, |m| !local_last.map_or(false, |l| m.metadata.contains(l))),
+						local_set.map_or(true, |m| !remote_last.map_or(false, |r| m.metadata.contains(r)))
+					)
+				};
+
+				let winner = match (local_last, remote_last) {
+					(Some(l), Some(r)) if l == r => l.clone(),
+					(Some(l), Some(r)) => {
+						if remote_changed && !local_changed {
+							r.clone()
+						} else if remote_changed {
+							if self.conflicts.get(filename) != Some(r) {
+								new_conflicts.push(filename.clone());
+							}
+							conflicts.insert(filename.clone(), r.clone());
+							l.clone()
+						} else {
+							l.clone()
+						}
+					},
+					(Some(l), None) => l.clone(),
+					(None, Some(r)) => r.clone(),
+					(None, None) => continue
+				};
+
+				//a conflict stays until it is resolved, even once the base has caught up with the remote
+				if let Some(theirs) = self.conflicts.get(filename) {
+					if Some(&winner) == local_last && !conflicts.contains_key(filename) {
+						conflicts.insert(filename.clone(), theirs.clone());
+					}
+				}
+
+				//a revision pruned on either side is not brought back by the other one
+				let pruned_keys : BTreeSet<String> = self.pruned.get(filename).into_iter().chain(remote.pruned.get(filename).into_iter())
+					.flat_map(|keys| keys.iter().cloned())
+					.collect();
+				let mut revisions : Vec<MetaData> = Vec::new();
+				for revision in local_set.into_iter().chain(remote_set.into_iter()).flat_map(|m| m.metadata.iter()) {
+					if *revision != winner && !revisions.contains(revision) && !pruned_keys.contains(&revision.get_revision_key()) {
+						revisions.push(revision.clone());
+					}
+				}
+				//a revision has a higher total than every revision it descends from, whatever the clocks say
+				revisions.sort_by_key(|m| (m.get_version_total(), m.get_timestamp()));
+				revisions.push(winner);
+
+				files.insert(filename.clone(), MetaDataSet {metadata: revisions});
+			}
+
+			let mut commits = self.commits.clone();
+			for commit in remote.commits.iter() {
+				if !commits.iter().any(|c| c.id == commit.id) {
+					commits.push(commit.clone());
+				}
+			}
+			commits.sort_by_key(|c| (c.revision, c.time));
+
+			//a tag of both sides keeps its local snapshot
+			let mut tags = remote.tags.clone();
+			tags.extend(self.tags.iter().map(|(name, tag)| (name.clone(), tag.clone())));
+
+			//both sides can have used the same revision numbers, the commits are told apart by their id
+			//and the next commit gets a number above both sides
+			let nb_revision = if self.nb_revision > remote.nb_revision { self.nb_revision } else { remote.nb_revision };
+
+			let mut pruned = self.pruned.clone();
+			for (filename, keys) in remote.pruned.iter() {
+				pruned.entry(filename.clone()).or_insert(BTreeSet::new()).extend(keys.iter().cloned());
+			}
+			(Hierarchy {format_version: metadata::FORMAT_VERSION, nb_revision: nb_revision, files: files, commits: commits, tags: tags, conflicts: conflicts, pruned: pruned}, new_conflicts)
+		}
+	}
+
+	/// Machine id and sequence number of a commit id
+	fn parse_commit_id(id: &str) -> Option<(&str, u64)> {
+		id.rfind(':').and_then(|i| id[i + 1..].parse::<u64>().ok().map(|sequence| (&id[..i], sequence)))
+	}
+
+	impl Commit {
+		pub fn get_revision(&self) -> i32 {
+			self.revision
+		}
+		pub fn get_id(&self) -> &str {
+			self.id.as_ref().map(|id| &id[..]).unwrap_or("")
+		}
+		/// True if the revision was committed by this commit or by a commit known when it was made
+		pub fn knows(&self, metadata: &MetaData) -> bool {
+			match (metadata.commit.as_ref().and_then(|id| parse_commit_id(id)), self.known.as_ref()) {
+				(Some((machine, sequence)), Some(known)) => known.get(machine).map_or(false, |&s| s >= sequence),
+				_ => true
+			}
+		}
+		pub fn get_message(&self) -> &str {
+			&self.message
+		}
+		pub fn get_host(&self) -> &str {
+			&self.host
+		}
+		pub fn get_user(&self) -> &str {
+			&self.user
+		}
+		pub fn get_time(&self) -> i64 {
+			self.time
+		}
+	}
+
+	impl Tag {
+		pub fn get_revision(&self) -> i32 {
+			self.revision
+		}
+		pub fn get_commit(&self) -> Option<&str> {
+			self.commit.as_ref().map(|id| &id[..])
+		}
+		pub fn get_time(&self) -> i64 {
+			self.time
+		}
+		pub fn get_files(&self) -> &HashMap<String, MetaData> {
+			&self.files
+		}
 	}
 
 	impl MetaDataSet {
@@ -367,6 +1998,10 @@ mod model {
 			v.push(m);
 			MetaDataSet {metadata: v }
 		}
+		/// Oldest first, the last one is the current revision
+		pub fn get_revisions(&self) -> &Vec<MetaData> {
+			&self.metadata
+		}
 		pub fn add_revision(&mut self, m: MetaData) {
 			self.metadata.push(m);
 		}
@@ -381,7 +2016,57 @@ mod model {
 
 	impl MetaData {
 		pub fn new_without_hash(timestamp: u64, size: u64) -> MetaData {
-			MetaData {timestamp: timestamp, size: size, hash: "".to_string(), stored_hash: "".to_string()}
+			MetaData {timestamp: timestamp, size: size, hash: "".to_string(), stored_hash: "".to_string(), origin: None, version: None, revision: None, commit: None}
+		}
+		/// Revision committed by machine_id after the previous revision of the file
+		pub fn set_origin(&mut self, machine_id: &str, previous: Option<&MetaData>) {
+			let mut version = previous.and_then(|p| p.version.clone()).unwrap_or(HashMap::new());
+			*version.entry(machine_id.to_string()).or_insert(0) += 1;
+			self.origin = Some(machine_id.to_string());
+			self.version = Some(version);
+		}
+		/// Revision committed by machine_id to resolve a conflict: it descends from both the local and the remote revision
+		pub fn set_origin_resolving(&mut self, machine_id: &str, local: Option<&MetaData>, theirs: &MetaData) {
+			self.set_origin(machine_id, local);
+			let version = self.version.as_mut().unwrap();
+			for (machine_id, &counter) in theirs.version.iter().flat_map(|v| v.iter()) {
+				let entry = version.entry(machine_id.clone()).or_insert(0);
+				if *entry < counter {
+					*entry = counter;
+				}
+			}
+		}
+		/// True if this revision was committed knowing the other one: each counter of the other vector is reached
+		pub fn descends_from(&self, other: &MetaData) -> bool {
+			let empty = HashMap::new();
+			let version = self.version.as_ref().unwrap_or(&empty);
+			other.version.as_ref().unwrap_or(&empty).iter().all(|(machine_id, counter)| version.get(machine_id).map_or(false, |c| c >= counter))
+		}
+		/// The revisions migrated from older metadata have no machine and cannot be ordered by their vector
+		pub fn has_origin(&self) -> bool {
+			self.version.is_some() && self.origin.as_ref().map_or(false, |o| o != metadata::UNKNOWN_ORIGIN)
+		}
+		pub fn get_origin(&self) -> &str {
+			self.origin.as_ref().map(|o| &o[..]).unwrap_or(metadata::UNKNOWN_ORIGIN)
+		}
+		pub fn get_version_total(&self) -> u64 {
+			self.version.as_ref().map_or(0, |v| v.values().sum())
+		}
+		/// Identifies the revision in every clone: its machine and its count in the version vector,
+		/// the hash and the time for the revisions without origin
+		pub fn get_revision_key(&self) -> String {
+			match (&self.origin, &self.version) {
+				(&Some(ref origin), &Some(ref version)) if self.has_origin() => format!("{}:{}", origin, version.get(origin).cloned().unwrap_or(0)),
+				_ => format!("{}@{}", self.hash, self.timestamp)
+			}
+		}
+		/// Revision committed by this commit
+		pub fn set_commit(&mut self, commit: &Commit) {
+			self.revision = Some(commit.revision);
+			self.commit = commit.id.clone();
+		}
+		pub fn get_size(&self) -> u64 {
+			self.size
 		}
 		pub fn add_hash(&mut self, hash: String) {
 			self.hash = hash;
@@ -397,4 +2082,148 @@ mod model {
 		}	
 	}
 
-}
\ No newline at end of file
+	#[cfg(test)]
+	mod tests {
+		use std::collections::HashMap;
+
+		use rustc_serialize::json;
+
+		use super::Hierarchy;
+		use super::MetaData;
+
+		fn copy(hierarchy: &Hierarchy) -> Hierarchy {
+			json::decode(&json::encode(hierarchy).unwrap()).unwrap()
+		}
+
+		/// Commit a new revision of the file on the machine, descending from its current revision there
+		fn commit_file(hierarchy: &mut Hierarchy, machine_id: &str, filename: &str, hash: &str) {
+			let commit = hierarchy.new_commit(hash, String::from("host"), String::from("user"), machine_id.to_string(), 0);
+			let mut metadata = MetaData::new_without_hash(0, 1);
+			metadata.add_hash(hash.to_string());
+			metadata.set_origin(machine_id, hierarchy.get_latest_meta_data(&filename.to_string()));
+			metadata.set_commit(&commit);
+			let mut changes = HashMap::new();
+			changes.insert(filename.to_string(), metadata);
+			hierarchy.commit(commit, changes);
+		}
+
+		fn get_last_hash(hierarchy: &Hierarchy, filename: &str) -> String {
+			hierarchy.get_latest_meta_data(&filename.to_string()).unwrap().get_hash()
+		}
+
+		#[test]
+		fn merge_takes_the_side_which_changed() {
+			let mut base = Hierarchy::new_empty();
+			commit_file(&mut base, "m1", "data/a", "a1");
+			commit_file(&mut base, "m1", "data/b", "b1");
+
+			let mut local = copy(&base);
+			commit_file(&mut local, "m1", "data/a", "a2");
+			let mut remote = copy(&base);
+			commit_file(&mut remote, "m2", "data/b", "b2");
+			commit_file(&mut remote, "m2", "data/c", "c1");
+
+			//the clocks do not matter, only the vectors
+			let (merged, conflicts) = local.merge(&remote, None);
+			assert!(conflicts.is_empty());
+			assert_eq!(get_last_hash(&merged, "data/a"), "a2");
+			assert_eq!(get_last_hash(&merged, "data/b"), "b2");
+			assert_eq!(get_last_hash(&merged, "data/c"), "c1");
+			assert_eq!(merged.get_files()["data/b"].get_revisions().len(), 2);
+
+			//the other side gets the same result
+			let (merged, conflicts) = remote.merge(&local, Some(&base));
+			assert!(conflicts.is_empty());
+			assert_eq!(get_last_hash(&merged, "data/a"), "a2");
+			assert_eq!(get_last_hash(&merged, "data/b"), "b2");
+		}
+
+		#[test]
+		fn a_conflict_is_kept_until_a_revision_descends_from_both_sides() {
+			let mut base = Hierarchy::new_empty();
+			commit_file(&mut base, "m1", "data/a", "a1");
+			let mut local = copy(&base);
+			commit_file(&mut local, "m1", "data/a", "ours");
+			let mut remote = copy(&base);
+			commit_file(&mut remote, "m2", "data/a", "theirs");
+
+			let (mut merged, conflicts) = local.merge(&remote, Some(&base));
+			assert_eq!(conflicts, vec![String::from("data/a")]);
+			assert_eq!(get_last_hash(&merged, "data/a"), "ours");
+			assert_eq!(merged.get_conflicts()["data/a"].get_hash(), "theirs");
+
+			//merging the same remote again reports nothing new but keeps the conflict
+			let (again, conflicts) = merged.merge(&remote, Some(&remote));
+			assert!(conflicts.is_empty());
+			assert!(again.get_conflicts().contains_key("data/a"));
+
+			let theirs = merged.get_conflicts()["data/a"].clone();
+			let commit = merged.new_commit("resolve", String::from("host"), String::from("user"), String::from("m1"), 0);
+			let mut resolved = MetaData::new_without_hash(0, 1);
+			resolved.add_hash(String::from("both"));
+			resolved.set_origin_resolving("m1", merged.get_latest_meta_data(&String::from("data/a")), &theirs);
+			resolved.set_commit(&commit);
+			assert!(resolved.descends_from(&theirs));
+			let mut changes = HashMap::new();
+			changes.insert(String::from("data/a"), resolved);
+			merged.commit(commit, changes);
+			assert!(merged.get_conflicts().is_empty());
+
+			//the remote now takes the resolution without conflict
+			let (merged, conflicts) = remote.merge(&merged, Some(&base));
+			assert!(conflicts.is_empty());
+			assert_eq!(get_last_hash(&merged, "data/a"), "both");
+		}
+
+		#[test]
+		fn merged_commits_keep_distinct_ids() {
+			let mut base = Hierarchy::new_empty();
+			commit_file(&mut base, "m1", "data/a", "a1");
+			let mut local = copy(&base);
+			commit_file(&mut local, "m1", "data/a", "a2");
+			let mut remote = copy(&base);
+			commit_file(&mut remote, "m2", "data/b", "b1");
+
+			let (merged, _) = local.merge(&remote, Some(&base));
+			assert_eq!(merged.get_commits().len(), 3);
+			let last_revision = local.get_commits()[1].get_revision();
+			assert_eq!(last_revision, remote.get_commits()[1].get_revision());
+			assert!(merged.find_commit(&last_revision.to_string()).is_err());
+
+			let ours = merged.find_commit("m1:2").unwrap();
+			let snapshot = merged.get_snapshot(ours);
+			assert_eq!(snapshot["data/a"].get_hash(), "a2");
+			assert!(!snapshot.contains_key("data/b"));
+
+			let next = merged.new_commit("next", String::from("host"), String::from("user"), String::from("m2"), 0);
+			assert_eq!(next.get_id(), "m2:2");
+			assert!(next.get_revision() > last_revision);
+		}
+
+		#[test]
+		fn pruned_revisions_do_not_come_back_with_a_merge() {
+			let mut local = Hierarchy::new_empty();
+			for hash in ["a1", "a2", "a3"].iter() {
+				commit_file(&mut local, "m1", "data/a", hash);
+			}
+			let other = copy(&local);
+
+			assert_eq!(local.prune(1, 1), 2);
+			let (merged, conflicts) = local.merge(&other, Some(&other));
+			assert!(conflicts.is_empty());
+			let hashes : Vec<String> = merged.get_files()["data/a"].get_revisions().iter().map(|m| m.get_hash()).collect();
+			assert_eq!(hashes, vec!["a3"]);
+
+			//every revision a restore can ask for keeps its object
+			let kept = merged.get_all_hashes();
+			for commit in merged.get_commits() {
+				assert!(merged.get_snapshot(commit).values().all(|m| kept.contains(&m.get_hash())));
+			}
+
+			//the other clone drops them too once it merges the pruned metadata
+			let (merged, _) = other.merge(&merged, Some(&other));
+			assert_eq!(merged.get_files()["data/a"].get_revisions().len(), 1);
+			assert_eq!(get_last_hash(&merged, "data/a"), "a3");
+		}
+	}
+}
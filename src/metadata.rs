@@ -1,45 +1,377 @@
 
 use std::path::Path;
 use std::path::PathBuf;
+use std::fs;
 use std::fs::File;
+use std::io;
 use std::io::prelude::*;
+use std::panic;
 
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+
+use rustc_serialize::Decodable;
 use rustc_serialize::json;
+use rustc_serialize::json::Json;
 
+use cipher;
+use cipher::Key;
+use config::MetadataFormat;
+use metadatalog;
 use model::Hierarchy;
+use model::MetaData;
+use model::Commit;
 
-pub fn create_emty_metadata_file<P: AsRef<Path>>(path: P) {
+pub fn create_emty_metadata_file<P: AsRef<Path>>(path: P, key: Option<&Key>, format: MetadataFormat, generations: usize) {
 	println!("Creating empty metadata file in {}", path.as_ref().to_str().unwrap());  
 
 	let hierarchy = Hierarchy::new_empty();
 
-	let json_hierarchy =   json::encode(&hierarchy).unwrap();
+	write_metadata_file_as(path.as_ref(), &hierarchy, key, format, generations);
+}
 
-	let mut file = File::create(&path).unwrap();
+/// Version of the metadata format written by this Yabs
+/// The files written before format_version existed are version 1
+pub const FORMAT_VERSION: u32 = 7;
 
-    let u8_vec = json_hierarchy.into_bytes();
-	let u8_slice = &u8_vec[..];
-    file.write_all(u8_slice);
+/// MIGRATIONS[i] upgrades the json of a version i + 1 file to version i + 2
+static MIGRATIONS: &'static [fn(&mut BTreeMap<String, Json>)] = &[
+	migrate_unversioned,
+	migrate_origins,
+	migrate_commits,
+	migrate_conflicts,
+	migrate_commit_ids,
+	migrate_pruned
+];
+
+/// Version 2 only adds format_version
+fn migrate_unversioned(_: &mut BTreeMap<String, Json>) {
+}
+
+/// Version 3 adds the origin machine and the version vector of each revision
+/// The revisions of older files come from an unknown machine, counted in their order
+fn migrate_origins(object: &mut BTreeMap<String, Json>) {
+	let files = match object.get_mut("files") {
+		Some(&mut Json::Object(ref mut files)) => files,
+		_ => return
+	};
+
+	for metadataset in files.values_mut() {
+		if let Some(&mut Json::Array(ref mut revisions)) = metadataset.as_object_mut().and_then(|m| m.get_mut("metadata")) {
+			for (i, revision) in revisions.iter_mut().enumerate() {
+				if let Some(revision) = revision.as_object_mut() {
+					let mut version = BTreeMap::new();
+					version.insert(String::from(UNKNOWN_ORIGIN), Json::U64(i as u64 + 1));
+					revision.insert(String::from("origin"), Json::String(String::from(UNKNOWN_ORIGIN)));
+					revision.insert(String::from("version"), Json::Object(version));
+				}
+			}
+		}
+	}
+}
+
+/// Version 4 adds the commit messages and the tags, the older commits have none
+fn migrate_commits(object: &mut BTreeMap<String, Json>) {
+	object.insert(String::from("commits"), Json::Array(Vec::new()));
+	object.insert(String::from("tags"), Json::Object(BTreeMap::new()));
+}
+
+/// Version 5 keeps the conflicts of the last merge until they are resolved
+fn migrate_conflicts(object: &mut BTreeMap<String, Json>) {
+	object.insert(String::from("conflicts"), Json::Object(BTreeMap::new()));
+}
+
+/// Version 6 identifies the commits by machine and sequence number, the revision numbers of merged clones collide
+/// The older commits get their id when the hierarchy is read (Hierarchy::identify_commits), nothing to change in the json
+fn migrate_commit_ids(_: &mut BTreeMap<String, Json>) {
+}
+
+/// Version 7 records the revisions removed by prune, none before
+fn migrate_pruned(object: &mut BTreeMap<String, Json>) {
+	object.insert(String::from("pruned"), Json::Object(BTreeMap::new()));
+}
+
+/// Origin of the revisions written before the machine ids
+pub const UNKNOWN_ORIGIN: &'static str = "unknown";
 
-    file.sync_all();
+/// The metadata file of an encrypted repository is an encrypted stream of the json
+/// The format of the file (json or log) is recognized from its content
+pub fn read_metadata_file<P: AsRef<Path>>(path: P, key: Option<&Key>) -> Hierarchy {
+	let mut hierarchy = if metadatalog::is_log_file(path.as_ref()) {
+		metadatalog::read_log(path.as_ref(), key)
+	} else {
+		let file = File::open(&path).unwrap();
+		let mut json = String::new();
+		cipher::decrypt_reader(file, key).and_then(|mut reader| reader.read_to_string(&mut json)).expect("Cannot read the metadata, wrong key?");
+		decode_metadata(&json, path.as_ref())
+	};
+
+	hierarchy.identify_commits();
+	hierarchy
+}
+
+/// Upgrade the json to the current format before decoding it
+/// A file written by a newer Yabs is refused, it would lose what this version does not know
+pub fn decode_metadata(json: &str, path: &Path) -> Hierarchy {
+	let mut object = match Json::from_str(json) {
+		Ok(Json::Object(object)) => object,
+		_ => panic!("Invalid metadata file {:?}", path)
+	};
+
+	let version = match object.get("format_version") {
+		Some(&Json::U64(version)) => version as u32,
+		Some(_) => panic!("Invalid format version in {:?}", path),
+		None => 1
+	};
+	if version > FORMAT_VERSION {
+		panic!("{:?} has format version {}, this Yabs only reads up to version {}: upgrade Yabs", path, version, FORMAT_VERSION);
+	}
+
+	for v in version..FORMAT_VERSION {
+		println!("Migrate {:?} from format version {} to {}", path, v, v + 1);
+		MIGRATIONS[(v - 1) as usize](&mut object);
+	}
+	object.insert(String::from("format_version"), Json::U64(FORMAT_VERSION as u64));
+
+	let mut decoder = json::Decoder::new(Json::Object(object));
+	Decodable::decode(&mut decoder).expect("Invalid metadata file")
+}
+
+pub fn get_metadata_format(path: &Path) -> MetadataFormat {
+	if metadatalog::is_log_file(path) { MetadataFormat::LOG } else { MetadataFormat::JSON }
+}
+
+/// Replace the whole metadata file atomically, the previous file becomes generation 1
+pub fn write_metadata_file_as(path: &Path, hierarchy: &Hierarchy, key: Option<&Key>, format: MetadataFormat, generations: usize) {
+	let content = match format {
+		MetadataFormat::JSON => encode_json(hierarchy, key),
+		MetadataFormat::LOG => metadatalog::encode_log(hierarchy, key)
+	};
+	write_atomically(path, &content, generations).expect("Cannot write the metadata");
+	metadatalog::remove_index(path);
+}
+
+/// Replace the metadata file by a copy of another one (the downloaded remote metadata)
+pub fn replace_metadata_file(source_path: &Path, path: &Path, generations: usize) {
+	let mut content = Vec::new();
+	File::open(source_path).and_then(|mut f| f.read_to_end(&mut content)).expect("Cannot read the metadata");
+	write_atomically(path, &content, generations).expect("Cannot write the metadata");
+	metadatalog::remove_index(path);
 }
 
-pub fn read_metadata_file<P: AsRef<Path>>(path: P) -> Hierarchy {
-	let mut file = File::open(&path).unwrap();
-	let mut json = String::new();
-    file.read_to_string(&mut json);
+/// Revisions of the files selected by the filter, oldest first
+/// A log is read through its index, only the records holding the files are decoded
+pub fn read_file_histories(path: &Path, key: Option<&Key>, selected: &dyn Fn(&str) -> bool) -> BTreeMap<String, Vec<MetaData>> {
+	if metadatalog::is_log_file(path) {
+		return metadatalog::read_file_histories(path, key, selected);
+	}
+
+	read_metadata_file(path, key).get_files().iter()
+		.filter(|&(filename, _)| selected(filename))
+		.map(|(filename, m)| (filename.clone(), m.get_revisions().clone()))
+		.collect()
+}
+
+/// Add the revisions of a commit to the hierarchy and to the metadata file
+/// A log only gets the new record appended and fsynced, its generations are kept when it is rewritten (compact-metadata),
+/// a json file is rewritten atomically and the previous file becomes generation 1
+pub fn commit_revision<P: AsRef<Path>>(path: P, hierarchy: &mut Hierarchy, commit: Commit, changes: HashMap<String, MetaData>, key: Option<&Key>, generations: usize) {
+	match get_metadata_format(path.as_ref()) {
+		MetadataFormat::LOG => {
+			metadatalog::append_commit(path.as_ref(), &commit, &changes, key).expect("Cannot write the metadata");
+			hierarchy.commit(commit, changes);
+		},
+		MetadataFormat::JSON => {
+			hierarchy.commit(commit, changes);
+			write_metadata_file_as(path.as_ref(), hierarchy, key, MetadataFormat::JSON, generations);
+		}
+	}
+}
+
+/// Plain json, not encrypted, to exchange the metadata with other tools
+pub fn export_json_file(path: &Path, hierarchy: &Hierarchy) {
+	write_atomically(path, &encode_json(hierarchy, None), 0).expect("Cannot write the json file");
+}
 
-    let hierarchy: Hierarchy = json::decode(&json).unwrap();
-    hierarchy
+pub fn import_json_file(path: &Path) -> Hierarchy {
+	read_metadata_file(path, None)
 }
 
-pub fn write_metadata_file<P: AsRef<Path>>(path: P, hierarchy: Hierarchy) {
-	let json_hierarchy = json::encode(&hierarchy).unwrap();
+fn encode_json(hierarchy: &Hierarchy, key: Option<&Key>) -> Vec<u8> {
+	let json_hierarchy = json::encode(hierarchy).unwrap();
     let u8_vec = json_hierarchy.into_bytes();
 	let u8_slice = &u8_vec[..];
 
-	let mut file = File::create(&path).unwrap();
-    file.write_all(u8_slice);
-    file.sync_all();
+	let mut content = Vec::new();
+	{
+		let mut writer = cipher::encrypt_writer(&mut content, key).unwrap();
+		writer.write_all(u8_slice).unwrap();
+		writer.finish().unwrap();
+	}
+	content
 }
 
+/// Write a temporary file and fsync it, keep the current file as generation 1, rename the temporary file
+/// over the current one and fsync the directory: after a crash the file is either the old one or the new one
+pub fn write_atomically(path: &Path, content: &[u8], generations: usize) -> io::Result<()> {
+	let tmp_path = get_suffixed_path(path, "tmp");
+	{
+		let mut file = File::create(&tmp_path)?;
+		file.write_all(content)?;
+		file.sync_all()?;
+	}
+
+	if generations > 0 && path.is_file() {
+		rotate_generations(path, generations)?;
+	}
+	fs::rename(&tmp_path, path)?;
+	sync_directory(path)
+}
+
+/// Copy of the remote metadata at the last sync, the base of the next three-way merge
+pub fn get_base_path(remote_metadata_path: &Path) -> PathBuf {
+	get_suffixed_path(remote_metadata_path, "base")
+}
+
+/// Generation n is the file as it was n writes ago
+pub fn get_generation_path(path: &Path, generation: usize) -> PathBuf {
+	get_suffixed_path(path, &generation.to_string())
+}
+
+fn get_suffixed_path(path: &Path, suffix: &str) -> PathBuf {
+	PathBuf::from(format!("{}.{}", path.to_str().unwrap(), suffix))
+}
+
+fn rotate_generations(path: &Path, generations: usize) -> io::Result<()> {
+	for generation in (1..generations).rev() {
+		let generation_path = get_generation_path(path, generation);
+		if generation_path.is_file() {
+			fs::rename(&generation_path, get_generation_path(path, generation + 1))?;
+		}
+	}
+
+	//the current file stays in place until the rename, a link is enough to keep it
+	let first_generation = get_generation_path(path, 1);
+	let _ = fs::remove_file(&first_generation);
+	if fs::hard_link(path, &first_generation).is_err() {
+		fs::copy(path, &first_generation)?;
+	}
+	Ok(())
+}
+
+/// Make the last renames in the directory of path durable
+pub fn sync_directory(path: &Path) -> io::Result<()> {
+	let directory = match path.parent() {
+		Some(parent) if parent != Path::new("") => parent.to_path_buf(),
+		_ => PathBuf::from(".")
+	};
+	File::open(directory)?.sync_all()
+}
+
+/// None instead of a panic if the file is missing, truncated or cannot be decoded
+pub fn try_read_metadata_file(path: &Path, key: Option<&Key>) -> Option<Hierarchy> {
+	if !path.is_file() {
+		return None;
+	}
+	panic::catch_unwind(panic::AssertUnwindSafe(|| read_metadata_file(path, key))).ok()
+}
+
+/// The most recent generation which can still be read, with its number
+pub fn read_last_good_generation(path: &Path, key: Option<&Key>, generations: usize) -> Option<(usize, Hierarchy)> {
+	(1..generations + 1)
+		.filter_map(|generation| try_read_metadata_file(&get_generation_path(path, generation), key).map(|h| (generation, h)))
+		.next()
+}
+
+
+/// The version (etag...) of the remote metadata seen at the last download is kept next to the downloaded copy
+fn get_version_path(path: &Path) -> PathBuf {
+	get_suffixed_path(path, "version")
+}
+
+pub fn read_version_file<P: AsRef<Path>>(path: P) -> Option<String> {
+	let mut version = String::new();
+	match File::open(get_version_path(path.as_ref())) {
+		Ok(mut file) => { file.read_to_string(&mut version).unwrap(); },
+		Err(_) => return None
+	};
+	Some(version)
+}
+
+pub fn write_version_file<P: AsRef<Path>>(path: P, version: Option<String>) {
+	let version_path = get_version_path(path.as_ref());
+	match version {
+		Some(v) => write_atomically(&version_path, v.as_bytes(), 0).unwrap(),
+		None => { let _ = fs::remove_file(&version_path); }
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+	use std::fs::File;
+	use std::io::prelude::*;
+	use std::path::PathBuf;
+
+	use config::MetadataFormat;
+	use testutil;
+	use super::FORMAT_VERSION;
+	use super::UNKNOWN_ORIGIN;
+	use super::read_metadata_file;
+	use super::write_metadata_file_as;
+
+	fn write_test_file(name: &str, json: &str) -> PathBuf {
+		let path = testutil::get_test_path(&format!("metadata-{}", name));
+		File::create(&path).unwrap().write_all(json.as_bytes()).unwrap();
+		path
+	}
+
+	#[test]
+	fn an_unversioned_file_is_migrated_to_the_current_format() {
+		let path = write_test_file("unversioned", r#"{"nb_revision": 3, "files": {"data/a": {"metadata": [
+			{"timestamp": 10, "size": 1, "hash": "h1", "stored_hash": "s1"},
+			{"timestamp": 20, "size": 2, "hash": "h2", "stored_hash": "s2"}]}}}"#);
+
+		let hierarchy = read_metadata_file(&path, None);
+		let revisions = hierarchy.get_files()["data/a"].get_revisions();
+		assert_eq!(revisions.len(), 2);
+		assert_eq!(revisions[1].get_hash(), "h2");
+		assert_eq!(revisions[0].get_origin(), UNKNOWN_ORIGIN);
+		assert!(revisions[1].descends_from(&revisions[0]));
+		assert!(hierarchy.get_commits().is_empty());
+		assert!(hierarchy.get_tags().is_empty());
+		assert!(hierarchy.get_conflicts().is_empty());
+
+		write_metadata_file_as(&path, &hierarchy, None, MetadataFormat::JSON, 0);
+		let mut json = String::new();
+		File::open(&path).unwrap().read_to_string(&mut json).unwrap();
+		assert!(json.contains(&format!("\"format_version\":{}", FORMAT_VERSION)));
+		assert_eq!(read_metadata_file(&path, None).get_files()["data/a"].get_revisions(), revisions);
+
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn the_commits_of_version_4_get_ids() {
+		let path = write_test_file("version-4", r#"{"format_version": 4, "nb_revision": 2,
+			"files": {"data/a": {"metadata": [
+				{"timestamp": 10, "size": 1, "hash": "h1", "stored_hash": "s1", "origin": "m1", "version": {"m1": 1}, "revision": 1},
+				{"timestamp": 20, "size": 2, "hash": "h2", "stored_hash": "s2", "origin": "m1", "version": {"m1": 2}, "revision": 2}]}},
+			"commits": [
+				{"revision": 1, "message": "first", "host": "h", "user": "u", "machine": "m1", "time": 10},
+				{"revision": 2, "message": "second", "host": "h", "user": "u", "machine": "m1", "time": 20}],
+			"tags": {"v1": {"revision": 1, "time": 15, "files": {"data/a":
+				{"timestamp": 10, "size": 1, "hash": "h1", "stored_hash": "s1", "origin": "m1", "version": {"m1": 1}, "revision": 1}}}}}"#);
+
+		let hierarchy = read_metadata_file(&path, None);
+		let ids : Vec<&str> = hierarchy.get_commits().iter().map(|c| c.get_id()).collect();
+		assert_eq!(ids, vec!["m1:1", "m1:2"]);
+		assert_eq!(hierarchy.get_tags()["v1"].get_commit(), Some("m1:1"));
+
+		let first = hierarchy.find_commit("1").unwrap();
+		assert_eq!(hierarchy.get_snapshot(first)["data/a"].get_hash(), "h1");
+		assert_eq!(hierarchy.new_commit("third", String::from("h"), String::from("u"), String::from("m1"), 30).get_id(), "m1:3");
+
+		fs::remove_file(&path).unwrap();
+	}
+}
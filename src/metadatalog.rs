@@ -0,0 +1,375 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::path::PathBuf;
+
+use rustc_serialize::json;
+
+use cipher;
+use cipher::Key;
+use metadata;
+use model::Hierarchy;
+use model::MetaData;
+use model::Commit;
+
+/// Append-only metadata log: magic, then records (length on 4 bytes big-endian, payload)
+/// A payload is a kind byte and json, encrypted record by record when there is a key
+/// The first record is a snapshot of the whole hierarchy, each commit appends only its revisions
+/// The commits written before the commit messages are revision records, without the description of the commit
+const LOG_MAGIC: &'static [u8] = b"YLOG1";
+const SNAPSHOT_RECORD: u8 = b'S';
+const REVISION_RECORD: u8 = b'R';
+const COMMIT_RECORD: u8 = b'C';
+
+#[derive(RustcEncodable, RustcDecodable)]
+struct CommitRecord {
+	commit: Commit,
+	changes: HashMap<String, MetaData>
+}
+
+/// Index of a log, next to it: for each path, the offsets of the records holding its revisions
+/// It covers the log up to length and is completed from there when the log has grown
+/// It is encrypted like the log since it holds the paths
+#[derive(RustcEncodable, RustcDecodable)]
+struct LogIndex {
+	snapshot_length: u64,
+	length: u64,
+	records: BTreeMap<String, Vec<u64>>
+}
+
+pub fn is_log_file(path: &Path) -> bool {
+	let mut magic = vec![0; LOG_MAGIC.len()];
+	match File::open(path) {
+		Ok(mut file) => file.read_exact(&mut magic).is_ok() && &magic[..] == LOG_MAGIC,
+		Err(_) => false
+	}
+}
+
+/// Content of a new log holding a snapshot of the hierarchy (creation, import or compaction)
+pub fn encode_log(hierarchy: &Hierarchy, key: Option<&Key>) -> Vec<u8> {
+	let mut log = LOG_MAGIC.to_vec();
+	write_record(&mut log, SNAPSHOT_RECORD, &json::encode(hierarchy).unwrap(), key).unwrap();
+	log
+}
+
+/// Append the description and the revisions of one commit and fsync it
+/// The log is not rewritten, a crash can only leave a truncated last record which is ignored,
+/// then cut before the next record is appended
+pub fn append_commit(path: &Path, commit: &Commit, changes: &HashMap<String, MetaData>, key: Option<&Key>) -> io::Result<()> {
+	let record = CommitRecord {commit: commit.clone(), changes: changes.clone()};
+
+	let mut file = OpenOptions::new().read(true).append(true).open(path)?;
+	let valid_length = get_valid_length(&mut file, LOG_MAGIC.len() as u64)?;
+	if valid_length < file.metadata()?.len() {
+		println!("Remove the truncated record at the end of {:?}", path);
+		file.set_len(valid_length)?;
+	}
+	write_record(&mut file, COMMIT_RECORD, &json::encode(&record).unwrap(), key)?;
+	file.sync_data()
+}
+
+fn write_record<W: Write>(writer: &mut W, kind: u8, json: &str, key: Option<&Key>) -> io::Result<()> {
+	let mut payload = Vec::new();
+	{
+		let mut writer = cipher::encrypt_writer(&mut payload, key)?;
+		writer.write_all(&[kind])?;
+		writer.write_all(json.as_bytes())?;
+		writer.finish()?;
+	}
+
+	let length = payload.len() as u32;
+	let mut record = vec![(length >> 24) as u8, (length >> 16) as u8, (length >> 8) as u8, length as u8];
+	record.extend_from_slice(&payload);
+	//a single write, so a crash leaves at most a truncated last record
+	writer.write_all(&record)
+}
+
+/// Length of the magic and the complete records, the rest is a truncated record
+/// The records are walked from a record boundary, reading only their lengths
+fn get_valid_length(file: &mut File, from: u64) -> io::Result<u64> {
+	let file_length = file.metadata()?.len();
+	let mut position = from;
+	let mut header = [0; 4];
+	while position + 4 <= file_length {
+		file.seek(SeekFrom::Start(position))?;
+		file.read_exact(&mut header)?;
+		let length = get_record_length(&header) as u64;
+		if file_length - position - 4 < length {
+			break;
+		}
+		position = position + 4 + length;
+	}
+	Ok(position)
+}
+
+fn get_record_length(header: &[u8]) -> usize {
+	header.iter().fold(0, |l, &b| (l << 8) | b as usize)
+}
+
+/// Replay the log: the snapshot then every revision in order
+/// A truncated last record (interrupted commit) is ignored
+pub fn read_log(path: &Path, key: Option<&Key>) -> Hierarchy {
+	let mut bytes = Vec::new();
+	File::open(path).unwrap().read_to_end(&mut bytes).unwrap();
+	if !bytes.starts_with(LOG_MAGIC) {
+		panic!("{:?} is not a metadata log", path);
+	}
+
+	let mut hierarchy : Option<Hierarchy> = None;
+	let mut position = LOG_MAGIC.len();
+	while position < bytes.len() {
+		if bytes.len() - position < 4 {
+			println!("Ignore the truncated record at the end of {:?}", path);
+			break;
+		}
+		let length = get_record_length(&bytes[position..position + 4]);
+		position = position + 4;
+		if bytes.len() - position < length {
+			println!("Ignore the truncated record at the end of {:?}", path);
+			break;
+		}
+
+		let (kind, json) = decode_payload(&bytes[position..position + length], key, path);
+		position = position + length;
+
+		match kind {
+			SNAPSHOT_RECORD => hierarchy = Some(metadata::decode_metadata(&json, path)),
+			REVISION_RECORD => {
+				let changes : HashMap<String, MetaData> = json::decode(&json).expect("Invalid metadata log");
+				hierarchy.as_mut().expect("Metadata log without snapshot").update(changes);
+			},
+			COMMIT_RECORD => {
+				let record : CommitRecord = json::decode(&json).expect("Invalid metadata log");
+				hierarchy.as_mut().expect("Metadata log without snapshot").commit(record.commit, record.changes);
+			},
+			kind => panic!("Unknown record {} in {:?}, this log was written by a newer Yabs", kind, path)
+		}
+	}
+
+	hierarchy.expect("Empty metadata log")
+}
+
+/// Kind and json of a record payload
+fn decode_payload(bytes: &[u8], key: Option<&Key>, path: &Path) -> (u8, String) {
+	let mut payload = Vec::new();
+	cipher::decrypt_reader(bytes, key)
+		.and_then(|mut reader| reader.read_to_end(&mut payload))
+		.expect("Cannot read the metadata, wrong key?");
+	if payload.is_empty() {
+		panic!("Invalid metadata log {:?}", path);
+	}
+
+	(payload[0], String::from_utf8(payload[1..].to_vec()).expect("Invalid metadata log"))
+}
+
+/// Kind, json and payload length of the record at the position
+fn read_record_at(file: &mut File, position: u64, key: Option<&Key>, path: &Path) -> io::Result<(u8, String, u64)> {
+	let mut header = [0; 4];
+	file.seek(SeekFrom::Start(position))?;
+	file.read_exact(&mut header)?;
+	let mut bytes = vec![0; get_record_length(&header)];
+	file.read_exact(&mut bytes)?;
+
+	let (kind, json) = decode_payload(&bytes, key, path);
+	Ok((kind, json, bytes.len() as u64))
+}
+
+/// Revisions held by a record, by path
+fn decode_record_files(kind: u8, json: &str, path: &Path) -> HashMap<String, Vec<MetaData>> {
+	match kind {
+		SNAPSHOT_RECORD => metadata::decode_metadata(json, path).get_files().iter()
+			.map(|(filename, m)| (filename.clone(), m.get_revisions().clone()))
+			.collect(),
+		REVISION_RECORD => {
+			let changes : HashMap<String, MetaData> = json::decode(json).expect("Invalid metadata log");
+			changes.into_iter().map(|(filename, m)| (filename, vec![m])).collect()
+		},
+		COMMIT_RECORD => {
+			let record : CommitRecord = json::decode(json).expect("Invalid metadata log");
+			record.changes.into_iter().map(|(filename, m)| (filename, vec![m])).collect()
+		},
+		kind => panic!("Unknown record {} in {:?}, this log was written by a newer Yabs", kind, path)
+	}
+}
+
+fn get_index_path(path: &Path) -> PathBuf {
+	PathBuf::from(format!("{}.index", path.to_str().unwrap()))
+}
+
+/// A log replaced as a whole (compaction, import, merge) is indexed again from its start
+pub fn remove_index(path: &Path) {
+	let _ = fs::remove_file(get_index_path(path));
+}
+
+fn load_index(path: &Path, key: Option<&Key>) -> Option<LogIndex> {
+	let file = File::open(get_index_path(path)).ok()?;
+	let mut json = String::new();
+	cipher::decrypt_reader(file, key).and_then(|mut reader| reader.read_to_string(&mut json)).ok()?;
+	json::decode(&json).ok()
+}
+
+fn save_index(path: &Path, index: &LogIndex, key: Option<&Key>) -> io::Result<()> {
+	let mut content = Vec::new();
+	{
+		let mut writer = cipher::encrypt_writer(&mut content, key)?;
+		writer.write_all(json::encode(index).unwrap().as_bytes())?;
+		writer.finish()?;
+	}
+	metadata::write_atomically(&get_index_path(path), &content, 0)
+}
+
+/// Index of the log, completed with the records appended since it was written
+/// An index of another log (same path but rewritten) is dropped and built again
+fn read_index(path: &Path, file: &mut File, key: Option<&Key>) -> io::Result<LogIndex> {
+	let file_length = file.metadata()?.len();
+	let mut header = [0; 4];
+	file.seek(SeekFrom::Start(LOG_MAGIC.len() as u64))?;
+	file.read_exact(&mut header)?;
+	let snapshot_length = get_record_length(&header) as u64;
+
+	let mut index = match load_index(path, key) {
+		Some(index) if index.snapshot_length == snapshot_length && index.length <= file_length => index,
+		_ => LogIndex {snapshot_length: snapshot_length, length: LOG_MAGIC.len() as u64, records: BTreeMap::new()}
+	};
+
+	let valid_length = get_valid_length(file, index.length)?;
+	if valid_length == index.length {
+		return Ok(index);
+	}
+
+	let mut position = index.length;
+	while position < valid_length {
+		let (kind, json, length) = read_record_at(file, position, key, path)?;
+		for (filename, _) in decode_record_files(kind, &json, path) {
+			index.records.entry(filename).or_insert(Vec::new()).push(position);
+		}
+		position = position + 4 + length;
+	}
+	index.length = valid_length;
+
+	//the index is only a cache, the lookup works without it
+	if let Err(e) = save_index(path, &index, key) {
+		println!("Cannot write the index of {:?}: {}", path, e);
+	}
+	Ok(index)
+}
+
+/// Revisions of the selected files, oldest first, read only from the records holding them
+pub fn read_file_histories(path: &Path, key: Option<&Key>, selected: &dyn Fn(&str) -> bool) -> BTreeMap<String, Vec<MetaData>> {
+	let mut file = File::open(path).unwrap();
+	let index = read_index(path, &mut file, key).expect("Cannot read the metadata log");
+
+	let offsets : BTreeSet<u64> = index.records.iter()
+		.filter(|&(filename, _)| selected(filename))
+		.flat_map(|(_, offsets)| offsets.iter().cloned())
+		.collect();
+
+	let mut histories : BTreeMap<String, Vec<MetaData>> = BTreeMap::new();
+	for offset in offsets {
+		let (kind, json, _) = read_record_at(&mut file, offset, key, path).expect("Cannot read the metadata log");
+		for (filename, revisions) in decode_record_files(kind, &json, path) {
+			if selected(&filename) {
+				histories.entry(filename).or_insert(Vec::new()).extend(revisions);
+			}
+		}
+	}
+	histories
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+	use std::fs;
+	use std::fs::File;
+	use std::fs::OpenOptions;
+	use std::io::prelude::*;
+	use std::path::Path;
+
+	use cipher::Key;
+	use model::Hierarchy;
+	use model::MetaData;
+	use testutil;
+	use super::append_commit;
+	use super::encode_log;
+	use super::get_index_path;
+	use super::read_file_histories;
+	use super::read_log;
+
+	fn commit_file(path: &Path, filename: &str, hash: &str, key: &Key) {
+		let hierarchy = read_log(path, Some(key));
+		let commit = hierarchy.new_commit(hash, String::from("host"), String::from("user"), String::from("m1"), 0);
+		let mut metadata = MetaData::new_without_hash(0, 1);
+		metadata.add_hash(hash.to_string());
+		metadata.set_origin("m1", hierarchy.get_latest_meta_data(&filename.to_string()));
+		metadata.set_commit(&commit);
+		let mut changes = HashMap::new();
+		changes.insert(filename.to_string(), metadata);
+
+		append_commit(path, &commit, &changes, Some(key)).unwrap();
+	}
+
+	fn truncate(path: &Path, length: u64) {
+		OpenOptions::new().write(true).open(path).unwrap().set_len(length).unwrap();
+	}
+
+	#[test]
+	fn a_torn_last_record_is_ignored_then_cut_by_the_next_commit() {
+		let key = Key::generate();
+		let path = testutil::get_test_path("log");
+		File::create(&path).unwrap().write_all(&encode_log(&Hierarchy::new_empty(), Some(&key))).unwrap();
+
+		commit_file(&path, "data/a", "h1", &key);
+		let length_after_a = fs::metadata(&path).unwrap().len();
+		commit_file(&path, "data/b", "h2", &key);
+		let length_after_b = fs::metadata(&path).unwrap().len();
+
+		//torn inside the payload, then inside the length of the record
+		for &length in [length_after_b - 5, length_after_a + 2].iter() {
+			truncate(&path, length);
+			let hierarchy = read_log(&path, Some(&key));
+			assert!(hierarchy.get_files().contains_key("data/a"));
+			assert!(!hierarchy.get_files().contains_key("data/b"));
+			assert_eq!(hierarchy.get_commits().len(), 1);
+		}
+
+		commit_file(&path, "data/c", "h3", &key);
+		let hierarchy = read_log(&path, Some(&key));
+		assert!(hierarchy.get_files().contains_key("data/c"));
+		assert!(!hierarchy.get_files().contains_key("data/b"));
+		let ids : Vec<&str> = hierarchy.get_commits().iter().map(|c| c.get_id()).collect();
+		assert_eq!(ids, vec!["m1:1", "m1:2"]);
+
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn the_index_gives_the_revisions_of_a_path_and_follows_the_appends() {
+		let key = Key::generate();
+		let path = testutil::get_test_path("log-index");
+		File::create(&path).unwrap().write_all(&encode_log(&Hierarchy::new_empty(), Some(&key))).unwrap();
+
+		commit_file(&path, "data/a", "h1", &key);
+		commit_file(&path, "data/b", "h2", &key);
+		let hashes = |filename: &str| -> Vec<String> {
+			let histories = read_file_histories(&path, Some(&key), &|f| f == filename);
+			histories.get(filename).map(|revisions| revisions.iter().map(|m| m.get_hash()).collect()).unwrap_or(Vec::new())
+		};
+		assert_eq!(hashes("data/a"), vec!["h1"]);
+		assert!(get_index_path(&path).is_file());
+
+		commit_file(&path, "data/a", "h3", &key);
+		assert_eq!(hashes("data/a"), vec!["h1", "h3"]);
+		assert_eq!(hashes("data/b"), vec!["h2"]);
+		assert!(hashes("data/c").is_empty());
+
+		fs::remove_file(get_index_path(&path)).unwrap();
+		fs::remove_file(&path).unwrap();
+	}
+}
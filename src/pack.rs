@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use rustc_serialize::json;
+
+use metadata;
+use store;
+use store::HashWriter;
+
+/// Small objects are appended to pack files instead of being stored one file per object
+/// A pack is immutable: store/packs/pack-<sha256 of the pack>.pack with its index pack-<sha256>.idx
+/// The index is written after the pack, so a pack without index is ignored
+pub const PACKS_DIRECTORY: &'static str = "packs";
+/// Names of the packs replaced by a repack, removed from the remote at the next push
+const SUPERSEDED_FILE: &'static str = "superseded.json";
+
+#[derive(Debug, RustcEncodable, RustcDecodable)]
+pub struct PackIndex {
+	objects: HashMap<String, PackEntry>
+}
+
+#[derive(Debug, RustcEncodable, RustcDecodable, Clone)]
+pub struct PackEntry {
+	offset: u64,
+	length: u64
+}
+
+/// Location of an object inside a pack
+#[derive(Debug, Clone)]
+pub struct PackedObject {
+	pack_path: PathBuf,
+	entry: PackEntry
+}
+
+pub type PackedObjects = HashMap<String, PackedObject>;
+
+impl PackedObject {
+	/// Reader on the bytes of the object inside the pack
+	pub fn open(&self) -> io::Result<io::Take<BufReader<File>>> {
+		let mut file = File::open(&self.pack_path)?;
+		file.seek(SeekFrom::Start(self.entry.offset))?;
+		Ok(BufReader::new(file).take(self.entry.length))
+	}
+	pub fn get_pack_path(&self) -> PathBuf {
+		self.pack_path.clone()
+	}
+}
+
+pub fn get_packs_path(store_path: &Path) -> PathBuf {
+	store_path.join(PACKS_DIRECTORY)
+}
+
+pub fn get_index_path(pack_path: &Path) -> PathBuf {
+	pack_path.with_extension("idx")
+}
+
+pub fn is_pack_name(name: &str) -> bool {
+	name.starts_with("pack-") && name.ends_with(".pack") && store::is_object_name(&name[5..name.len() - 5])
+}
+
+/// All the packs of the store having an index
+pub fn get_all_packs(store_path: &Path) -> Vec<PathBuf> {
+	let packs_path = get_packs_path(store_path);
+	if !packs_path.is_dir() {
+		return Vec::new();
+	}
+
+	fs::read_dir(&packs_path).unwrap()
+		.map(|entry| entry.unwrap().path())
+		.filter(|path| is_pack_name(path.file_name().unwrap().to_str().unwrap()))
+		.filter(|path| get_index_path(path).is_file())
+		.collect()
+}
+
+pub fn read_index(index_path: &Path) -> PackIndex {
+	let mut json = String::new();
+	File::open(index_path).unwrap().read_to_string(&mut json).unwrap();
+	json::decode(&json).expect("Invalid pack index")
+}
+
+/// Location of every packed object of the store
+pub fn read_all_indexes(store_path: &Path) -> PackedObjects {
+	let mut packed_objects : PackedObjects = HashMap::new();
+
+	for pack_path in get_all_packs(store_path) {
+		let index = read_index(&get_index_path(&pack_path));
+		for (hash, entry) in index.objects {
+			packed_objects.insert(hash, PackedObject {pack_path: pack_path.clone(), entry: entry});
+		}
+	}
+
+	packed_objects
+}
+
+/// Write a new pack with the given objects, each one is opened only when it is copied in the pack
+/// Return the path of the pack, None if there is nothing to pack
+pub fn write_pack(store_path: &Path, hashes: Vec<String>, open_object: &dyn Fn(&String) -> Box<dyn Read>) -> Option<PathBuf> {
+	if hashes.is_empty() {
+		return None;
+	}
+
+	let packs_path = get_packs_path(store_path);
+	fs::create_dir_all(&packs_path).unwrap();
+	let tmp_pack_path = packs_path.join("tmp.pack");
+	let tmp_index_path = packs_path.join("tmp.idx");
+
+	let mut index = PackIndex {objects: HashMap::new()};
+	let mut pack_writer = HashWriter::new(BufWriter::new(File::create(&tmp_pack_path).unwrap()));
+
+	let mut offset = 0;
+	for hash in hashes {
+		if index.objects.contains_key(&hash) {
+			continue;
+		}
+		let mut reader = open_object(&hash);
+		let length = io::copy(&mut reader, &mut pack_writer).unwrap();
+		index.objects.insert(hash, PackEntry {offset: offset, length: length});
+		offset = offset + length;
+	}
+	pack_writer.flush().unwrap();
+	let pack_hash = pack_writer.get_hash();
+	let pack_file = pack_writer.into_inner().into_inner().unwrap();
+	pack_file.sync_all().unwrap();
+	drop(pack_file);
+
+	let mut index_file = File::create(&tmp_index_path).unwrap();
+	index_file.write_all(json::encode(&index).unwrap().as_bytes()).unwrap();
+	index_file.sync_all().unwrap();
+
+	let pack_path = packs_path.join(format!("pack-{}.pack", pack_hash));
+	fs::rename(&tmp_pack_path, &pack_path).unwrap();
+	fs::rename(&tmp_index_path, get_index_path(&pack_path)).unwrap();
+	//the pack must be durable before the objects it contains are removed from elsewhere
+	metadata::sync_directory(&pack_path).unwrap();
+
+	println!("{} objects packed in {:?}", index.objects.len(), pack_path);
+	Some(pack_path)
+}
+
+/// Move the loose objects smaller than the threshold into a new pack
+pub fn pack_loose_objects(store_path: &Path, threshold: u64) {
+	let small_objects : Vec<(String, PathBuf)> = store::get_all_filenames(store_path).into_iter()
+		.filter(|path| !path.starts_with(get_packs_path(store_path)))
+		.filter(|path| store::is_object_name(path.file_name().unwrap().to_str().unwrap()))
+		.filter(|path| fs::metadata(path).unwrap().len() < threshold)
+		.map(|path| (path.file_name().unwrap().to_str().unwrap().to_string(), path))
+		.collect();
+
+	let paths : HashMap<String, PathBuf> = small_objects.iter().cloned().collect();
+	let hashes : Vec<String> = small_objects.iter().map(|&(ref hash, _)| hash.clone()).collect();
+	let open_object = |hash: &String| Box::new(BufReader::new(File::open(&paths[hash]).unwrap())) as Box<dyn Read>;
+
+	if write_pack(store_path, hashes, &open_object).is_some() {
+		for (_, path) in small_objects {
+			fs::remove_file(&path).unwrap();
+		}
+	}
+}
+
+/// Check a pack against the hash in its name
+pub fn check_pack(pack_path: &Path, pack_name: &str) -> bool {
+	if !is_pack_name(pack_name) {
+		return false;
+	}
+
+	let mut reader = match File::open(pack_path) {
+		Ok(f) => BufReader::new(f),
+		Err(_) => return false
+	};
+	let mut hash_writer = HashWriter::new(io::sink());
+	match io::copy(&mut reader, &mut hash_writer) {
+		Ok(_) => hash_writer.get_hash() == &pack_name[5..pack_name.len() - 5],
+		Err(_) => false
+	}
+}
+
+/// Rewrite all the packs in a single one keeping only the referenced objects
+/// The small referenced loose objects are packed at the same time
+pub fn repack(store_path: &Path, referenced_hashes: &HashSet<String>, threshold: u64) {
+	pack_loose_objects(store_path, threshold);
+
+	let old_packs = get_all_packs(store_path);
+	let packed_objects = read_all_indexes(store_path);
+	let nb_objects = packed_objects.len();
+
+	let hashes : Vec<String> = packed_objects.keys()
+		.filter(|&hash| referenced_hashes.contains(hash))
+		.cloned()
+		.collect();
+	println!("{} packed objects, {} still referenced", nb_objects, hashes.len());
+
+	let open_object = |hash: &String| Box::new(packed_objects[hash].open().unwrap()) as Box<dyn Read>;
+	let new_pack = write_pack(store_path, hashes, &open_object);
+
+	let mut superseded_packs = read_superseded_packs(store_path);
+	for pack_path in old_packs {
+		if Some(&pack_path) != new_pack.as_ref() {
+			superseded_packs.insert(pack_path.file_name().unwrap().to_str().unwrap().to_string());
+			fs::remove_file(get_index_path(&pack_path)).unwrap();
+			fs::remove_file(&pack_path).unwrap();
+		}
+	}
+	write_superseded_packs(store_path, &superseded_packs);
+}
+
+/// Packs removed locally by a repack which may still be on the remote
+pub fn read_superseded_packs(store_path: &Path) -> HashSet<String> {
+	let mut json = String::new();
+	match File::open(get_packs_path(store_path).join(SUPERSEDED_FILE)) {
+		Ok(mut file) => { file.read_to_string(&mut json).unwrap(); },
+		Err(_) => return HashSet::new()
+	};
+	json::decode(&json).expect("Invalid superseded packs file")
+}
+
+pub fn write_superseded_packs(store_path: &Path, superseded_packs: &HashSet<String>) {
+	let path = get_packs_path(store_path).join(SUPERSEDED_FILE);
+	if superseded_packs.is_empty() {
+		let _ = fs::remove_file(&path);
+	} else {
+		metadata::write_atomically(&path, json::encode(superseded_packs).unwrap().as_bytes(), 0).unwrap();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+	use std::collections::HashSet;
+	use std::fs;
+	use std::io::Cursor;
+	use std::io::Read;
+
+	use testutil;
+	use super::check_pack;
+	use super::read_all_indexes;
+	use super::read_superseded_packs;
+	use super::repack;
+	use super::write_pack;
+
+	#[test]
+	fn packed_objects_are_read_back_and_repack_drops_the_unreferenced_ones() {
+		let store_path = testutil::new_test_directory("pack");
+
+		let mut contents : HashMap<String, Vec<u8>> = HashMap::new();
+		contents.insert(String::from("o1"), b"first object".to_vec());
+		contents.insert(String::from("o2"), Vec::new());
+		contents.insert(String::from("o3"), (0..5000u32).map(|i| i as u8).collect());
+		let open_object = |hash: &String| Box::new(Cursor::new(contents[hash].clone())) as Box<dyn Read>;
+
+		let hashes = vec![String::from("o1"), String::from("o2"), String::from("o3"), String::from("o1")];
+		let pack_path = write_pack(&store_path, hashes, &open_object).unwrap();
+		assert!(check_pack(&pack_path, pack_path.file_name().unwrap().to_str().unwrap()));
+
+		let read_object = |hash: &str| {
+			let mut content = Vec::new();
+			read_all_indexes(&store_path)[hash].open().unwrap().read_to_end(&mut content).unwrap();
+			content
+		};
+		assert_eq!(read_all_indexes(&store_path).len(), 3);
+		for (hash, content) in contents.iter() {
+			assert_eq!(&read_object(&hash[..]), content);
+		}
+
+		let referenced : HashSet<String> = vec![String::from("o3")].into_iter().collect();
+		repack(&store_path, &referenced, 0);
+		let packed_objects = read_all_indexes(&store_path);
+		assert_eq!(packed_objects.len(), 1);
+		assert_eq!(read_object("o3"), contents["o3"]);
+		assert!(!pack_path.exists());
+		assert!(read_superseded_packs(&store_path).contains(pack_path.file_name().unwrap().to_str().unwrap()));
+
+		fs::remove_dir_all(&store_path).unwrap();
+	}
+}
@@ -0,0 +1,740 @@
+use std::cmp;
+use std::fs::File;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::net::TcpStream;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use native_tls::TlsConnector;
+use native_tls::TlsStream;
+use rustc_serialize::hex::ToHex;
+use time;
+
+use cipher;
+use throttle;
+use transport::RemoteStat;
+use transport::Transport;
+
+/// Files bigger than this are sent with a multipart upload
+const MULTIPART_THRESHOLD: u64 = 64 * 1024 * 1024;
+const PART_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Remote stored in an S3-compatible bucket (AWS, MinIO...)
+/// The bucket is addressed in path style: https://endpoint/bucket/prefix/key
+/// (plain http only for the s3+http urls of local servers)
+/// S3 has no rename, so every object is written in a single atomic PUT (or a completed multipart upload)
+pub struct S3Transport {
+	address: String,
+	secure: bool,
+	bucket: String,
+	prefix: String,
+	region: String,
+	access_key: String,
+	secret_key: String
+}
+
+/// Connection to the endpoint, encrypted unless the url asks for plain http
+enum Connection {
+	Plain(TcpStream),
+	Tls(TlsStream<TcpStream>)
+}
+
+impl Read for Connection {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		match *self {
+			Connection::Plain(ref mut stream) => stream.read(buf),
+			Connection::Tls(ref mut stream) => stream.read(buf)
+		}
+	}
+}
+
+impl Write for Connection {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		match *self {
+			Connection::Plain(ref mut stream) => stream.write(buf),
+			Connection::Tls(ref mut stream) => stream.write(buf)
+		}
+	}
+	fn flush(&mut self) -> io::Result<()> {
+		match *self {
+			Connection::Plain(ref mut stream) => stream.flush(),
+			Connection::Tls(ref mut stream) => stream.flush()
+		}
+	}
+}
+
+struct Response {
+	status: u32,
+	headers: Vec<(String, String)>,
+	reader: BufReader<Connection>
+}
+
+impl Response {
+	fn get_header(&self, name: &str) -> Option<String> {
+		self.headers.iter().find(|&&(ref n, _)| n.eq_ignore_ascii_case(name)).map(|&(_, ref v)| v.clone())
+	}
+	fn get_content_length(&self) -> Option<u64> {
+		self.get_header("content-length").and_then(|v| v.parse::<u64>().ok())
+	}
+	/// Reader of the body, decoded if the server sends it in chunks
+	fn into_body(self) -> Box<dyn Read> {
+		let chunked = self.get_header("transfer-encoding").map(|v| v.to_lowercase().contains("chunked")).unwrap_or(false);
+		if chunked {
+			return Box::new(ChunkedReader::new(self.reader));
+		}
+		match self.get_content_length() {
+			Some(length) => Box::new(self.reader.take(length)),
+			None => Box::new(self.reader)
+		}
+	}
+	fn read_body(self) -> io::Result<String> {
+		let mut body = String::new();
+		self.into_body().read_to_string(&mut body)?;
+		Ok(body)
+	}
+	fn is_success(&self) -> bool {
+		self.status >= 200 && self.status < 300
+	}
+	/// Turn an unexpected status in an error containing the S3 error message
+	fn into_error(self) -> io::Error {
+		let status = self.status;
+		let body = self.read_body().unwrap_or(String::new());
+		io::Error::new(io::ErrorKind::Other, format!("S3 request failed with status {}: {}", status, body))
+	}
+}
+
+/// Body sent with Transfer-Encoding: chunked, the chunk sizes are removed
+struct ChunkedReader<R: BufRead> {
+	inner: R,
+	remaining: u64,
+	done: bool
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+	fn new(inner: R) -> ChunkedReader<R> {
+		ChunkedReader {inner: inner, remaining: 0, done: false}
+	}
+
+	/// Read the size line of the next chunk, the last chunk is empty and followed by optional trailers
+	fn start_chunk(&mut self) -> io::Result<()> {
+		let mut line = String::new();
+		self.inner.read_line(&mut line)?;
+		let size = line.split(';').next().unwrap().trim();
+		self.remaining = u64::from_str_radix(size, 16)
+			.map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid HTTP chunk size {:?}", line)))?;
+
+		if self.remaining == 0 {
+			loop {
+				let mut trailer = String::new();
+				if self.inner.read_line(&mut trailer)? == 0 || trailer.trim_end().is_empty() {
+					break;
+				}
+			}
+			self.done = true;
+		}
+		Ok(())
+	}
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		if self.done || buf.is_empty() {
+			return Ok(0);
+		}
+		if self.remaining == 0 {
+			self.start_chunk()?;
+			if self.done {
+				return Ok(0);
+			}
+		}
+
+		let length = cmp::min(buf.len() as u64, self.remaining) as usize;
+		let read = self.inner.read(&mut buf[..length])?;
+		if read == 0 {
+			return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "HTTP chunk is truncated"));
+		}
+		self.remaining = self.remaining - read as u64;
+
+		if self.remaining == 0 {
+			let mut end_of_chunk = String::new();
+			self.inner.read_line(&mut end_of_chunk)?;
+		}
+		Ok(read)
+	}
+}
+
+impl S3Transport {
+	/// address is host:port, root_path is /bucket/optional/prefix
+	pub fn new(address: &str, secure: bool, root_path: &Path, region: &str, access_key: &str, secret_key: &str) -> S3Transport {
+		let root = root_path.to_str().expect("cannot convert path to utf8 string").trim_matches('/').to_string();
+		let (bucket, prefix) = match root.find('/') {
+			Some(i) => (root[..i].to_string(), root[i + 1..].to_string()),
+			None => (root.clone(), String::new())
+		};
+
+		S3Transport {
+			address: address.to_string(),
+			secure: secure,
+			bucket: bucket,
+			prefix: prefix,
+			region: region.to_string(),
+			access_key: access_key.to_string(),
+			secret_key: secret_key.to_string()
+		}
+	}
+
+	/// Host header: the port is left out when it is the default one of the protocol
+	fn get_host(&self) -> String {
+		let default_port = if self.secure { ":443" } else { ":80" };
+		if self.address.ends_with(default_port) {
+			self.address[..self.address.len() - default_port.len()].to_string()
+		} else {
+			self.address.clone()
+		}
+	}
+
+	fn connect(&self) -> io::Result<Connection> {
+		let tcp = TcpStream::connect(&self.address[..])?;
+		if !self.secure {
+			return Ok(Connection::Plain(tcp));
+		}
+
+		let domain = match self.address.rfind(':') {
+			Some(i) => self.address[..i].to_string(),
+			None => self.address.clone()
+		};
+		let connector = TlsConnector::builder().and_then(|builder| builder.build())
+			.map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Cannot initialize TLS: {}", e)))?;
+		connector.connect(&domain, tcp)
+			.map(Connection::Tls)
+			.map_err(|e| io::Error::new(io::ErrorKind::Other, format!("TLS handshake with {} failed: {}", self.address, e)))
+	}
+
+	fn get_key(&self, remote_path: &Path) -> String {
+		let path = remote_path.to_str().expect("cannot convert path to utf8 string").replace('\\', "/");
+		let path = path.trim_matches('/');
+		if self.prefix.is_empty() {
+			path.to_string()
+		} else if path.is_empty() {
+			self.prefix.clone()
+		} else {
+			format!("{}/{}", self.prefix, path)
+		}
+	}
+
+	/// Send a signed request, the body is streamed from the reader
+	/// The sha256 of the body is signed, so the server rejects a body corrupted on the way
+	fn request(&self, method: &str, key: &str, query: &[(&str, String)], extra_headers: &[(&str, String)], body: Option<(&mut dyn Read, u64, String)>) -> io::Result<Response> {
+		let amz_date = time::strftime("%Y%m%dT%H%M%SZ", &time::now_utc()).unwrap();
+		let date = amz_date[..8].to_string();
+
+		let uri = if key.is_empty() {
+			format!("/{}", uri_encode(&self.bucket, false))
+		} else {
+			format!("/{}/{}", uri_encode(&self.bucket, false), uri_encode(key, false))
+		};
+
+		let mut sorted_query : Vec<(String, String)> = query.iter().map(|&(k, ref v)| (uri_encode(k, true), uri_encode(v, true))).collect();
+		sorted_query.sort();
+		let canonical_query = sorted_query.iter().map(|&(ref k, ref v)| format!("{}={}", k, v)).collect::<Vec<String>>().join("&");
+
+		let (content_length, payload_hash) = match body {
+			Some((_, length, ref hash)) => (length, hash.clone()),
+			None => (0, sha256_hex(b""))
+		};
+
+		let mut headers : Vec<(String, String)> = vec![
+			("host".to_string(), self.get_host()),
+			("x-amz-content-sha256".to_string(), payload_hash.clone()),
+			("x-amz-date".to_string(), amz_date.clone())];
+		for &(name, ref value) in extra_headers {
+			headers.push((name.to_lowercase(), value.trim().to_string()));
+		}
+		headers.sort();
+
+		let canonical_headers : String = headers.iter().map(|&(ref n, ref v)| format!("{}:{}\n", n, v)).collect();
+		let signed_headers = headers.iter().map(|&(ref n, _)| n.clone()).collect::<Vec<String>>().join(";");
+
+		let canonical_request = format!("{}\n{}\n{}\n{}\n{}\n{}", method, uri, canonical_query, canonical_headers, signed_headers, payload_hash);
+
+		let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+		let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", amz_date, scope, sha256_hex(canonical_request.as_bytes()));
+
+		let signing_key = cipher::hmac_sha256(format!("AWS4{}", self.secret_key).as_bytes(), date.as_bytes());
+		let signing_key = cipher::hmac_sha256(&signing_key, self.region.as_bytes());
+		let signing_key = cipher::hmac_sha256(&signing_key, b"s3");
+		let signing_key = cipher::hmac_sha256(&signing_key, b"aws4_request");
+		let signature = cipher::hmac_sha256(&signing_key, string_to_sign.as_bytes()).to_hex();
+
+		let authorization = format!("AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}", self.access_key, scope, signed_headers, signature);
+
+		let target = if canonical_query.is_empty() { uri } else { format!("{}?{}", uri, canonical_query) };
+
+		let mut connection = self.connect()?;
+		{
+			let mut writer = BufWriter::new(&mut connection);
+			write!(writer, "{} {} HTTP/1.1\r\n", method, target)?;
+			for &(ref name, ref value) in headers.iter() {
+				write!(writer, "{}: {}\r\n", name, value)?;
+			}
+			write!(writer, "authorization: {}\r\n", authorization)?;
+			write!(writer, "content-length: {}\r\n", content_length)?;
+			write!(writer, "connection: close\r\n\r\n")?;
+
+			if let Some((reader, length, _)) = body {
+				throttle::copy(&mut reader.take(length), &mut writer)?;
+			}
+			writer.flush()?;
+		}
+
+		read_response(connection)
+	}
+
+	fn put_single(&self, local_path: &Path, key: &str, conditions: &[(&str, String)]) -> io::Result<Response> {
+		let mut file = File::open(local_path)?;
+		let size = file.metadata()?.len();
+		let hash = sha256_range(&mut file, 0, size)?;
+		self.request("PUT", key, &[], conditions, Some((&mut file as &mut dyn Read, size, hash)))
+	}
+
+	fn put_multipart(&self, local_path: &Path, key: &str, conditions: &[(&str, String)]) -> io::Result<Response> {
+		let response = self.request("POST", key, &[("uploads", String::new())], &[], None)?;
+		if !response.is_success() {
+			return Err(response.into_error());
+		}
+		let upload_id = xml_values(&response.read_body()?, "UploadId").pop()
+			.ok_or(io::Error::new(io::ErrorKind::Other, "No UploadId in S3 response"))?;
+
+		match self.upload_parts(local_path, key, &upload_id) {
+			Ok(etags) => {
+				let mut complete = String::from("<CompleteMultipartUpload>");
+				for (i, etag) in etags.iter().enumerate() {
+					complete.push_str(&format!("<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>", i + 1, etag));
+				}
+				complete.push_str("</CompleteMultipartUpload>");
+
+				let mut complete_reader = complete.as_bytes();
+				let length = complete.len() as u64;
+				let hash = sha256_hex(complete.as_bytes());
+				self.request("POST", key, &[("uploadId", upload_id.clone())], conditions, Some((&mut complete_reader as &mut dyn Read, length, hash)))
+			},
+			Err(e) => {
+				println!("Multipart upload of {} failed, abort it", key);
+				self.request("DELETE", key, &[("uploadId", upload_id.clone())], &[], None)?;
+				Err(e)
+			}
+		}
+	}
+
+	fn upload_parts(&self, local_path: &Path, key: &str, upload_id: &String) -> io::Result<Vec<String>> {
+		let mut file = File::open(local_path)?;
+		let size = file.metadata()?.len();
+
+		let mut etags : Vec<String> = Vec::new();
+		let mut offset = 0;
+		let mut part_number = 1;
+		while offset < size {
+			let length = cmp::min(PART_SIZE, size - offset);
+			println!("Upload part {} of {}", part_number, key);
+
+			let hash = sha256_range(&mut file, offset, length)?;
+			let response = self.request("PUT", key, &[("partNumber", part_number.to_string()), ("uploadId", upload_id.clone())], &[], Some((&mut file as &mut dyn Read, length, hash)))?;
+			if !response.is_success() {
+				return Err(response.into_error());
+			}
+			etags.push(response.get_header("etag").unwrap_or(String::new()));
+
+			offset = offset + length;
+			part_number = part_number + 1;
+		}
+
+		Ok(etags)
+	}
+
+	fn put_with_conditions(&self, local_path: &Path, remote_path: &Path, conditions: &[(&str, String)]) -> io::Result<Response> {
+		let key = self.get_key(remote_path);
+		let size = File::open(local_path)?.metadata()?.len();
+
+		if size > MULTIPART_THRESHOLD {
+			self.put_multipart(local_path, &key, conditions)
+		} else {
+			self.put_single(local_path, &key, conditions)
+		}
+	}
+}
+
+impl Transport for S3Transport {
+	fn list(&self, remote_path: &Path) -> io::Result<Vec<(PathBuf, RemoteStat)>> {
+		let key = self.get_key(remote_path);
+		let prefix = if key.is_empty() { key } else { format!("{}/", key) };
+
+		let mut elements : Vec<(PathBuf, RemoteStat)> = Vec::new();
+		let mut continuation_token : Option<String> = None;
+		loop {
+			let mut query = vec![("list-type", "2".to_string()), ("prefix", prefix.clone()), ("delimiter", "/".to_string())];
+			if let Some(ref token) = continuation_token {
+				query.push(("continuation-token", token.clone()));
+			}
+
+			let response = self.request("GET", "", &query, &[], None)?;
+			if !response.is_success() {
+				return Err(response.into_error());
+			}
+			let xml = response.read_body()?;
+
+			for content in xml_values(&xml, "Contents") {
+				let object_key = xml_values(&content, "Key").pop().unwrap_or(String::new());
+				let size = xml_values(&content, "Size").pop().and_then(|s| s.parse::<u64>().ok()).unwrap_or(0);
+				let name = object_key[prefix.len()..].to_string();
+				if name.is_empty() {
+					continue;
+				}
+				elements.push((remote_path.join(name), RemoteStat::new(size, false)));
+			}
+			for common_prefix in xml_values(&xml, "CommonPrefixes") {
+				let sub_prefix = xml_values(&common_prefix, "Prefix").pop().unwrap_or(String::new());
+				let name = sub_prefix[prefix.len()..].trim_end_matches('/').to_string();
+				elements.push((remote_path.join(name), RemoteStat::new(0, true)));
+			}
+
+			let truncated = xml_values(&xml, "IsTruncated").pop() == Some("true".to_string());
+			continuation_token = xml_values(&xml, "NextContinuationToken").pop();
+			if !truncated || continuation_token.is_none() {
+				break;
+			}
+		}
+
+		Ok(elements)
+	}
+
+	fn get(&self, remote_path: &Path, local_path: &Path) -> io::Result<()> {
+		let response = self.request("GET", &self.get_key(remote_path), &[], &[], None)?;
+		if !response.is_success() {
+			return Err(response.into_error());
+		}
+
+		let mut local_file_writer = BufWriter::new(File::create(&local_path)?);
+		throttle::copy(&mut response.into_body(), &mut local_file_writer)?;
+		local_file_writer.flush()
+	}
+
+	fn put(&self, local_path: &Path, remote_path: &Path) -> io::Result<()> {
+		let response = self.put_with_conditions(local_path, remote_path, &[])?;
+		if !response.is_success() {
+			return Err(response.into_error());
+		}
+		Ok(())
+	}
+
+	fn delete(&self, remote_path: &Path) -> io::Result<()> {
+		let response = self.request("DELETE", &self.get_key(remote_path), &[], &[], None)?;
+		if !response.is_success() {
+			return Err(response.into_error());
+		}
+		Ok(())
+	}
+
+	fn stat(&self, remote_path: &Path) -> io::Result<Option<RemoteStat>> {
+		let response = self.request("HEAD", &self.get_key(remote_path), &[], &[], None)?;
+		match response.status {
+			404 => Ok(None),
+			_ if response.is_success() => Ok(Some(RemoteStat::new(response.get_content_length().unwrap_or(0), false))),
+			_ => Err(io::Error::new(io::ErrorKind::Other, format!("S3 HEAD failed with status {}", response.status)))
+		}
+	}
+
+	/// Server side copy then delete, the destination is replaced atomically but the source disappears afterwards
+	fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+		let source = format!("/{}/{}", self.bucket, uri_encode(&self.get_key(from), false));
+		let response = self.request("PUT", &self.get_key(to), &[], &[("x-amz-copy-source", source)], None)?;
+		if !response.is_success() {
+			return Err(response.into_error());
+		}
+		response.read_body()?;
+		self.delete(from)
+	}
+
+	/// Directories do not exist in S3, they are only key prefixes
+	fn mkdir(&self, _remote_path: &Path) -> io::Result<()> {
+		Ok(())
+	}
+
+	fn has_atomic_put(&self) -> bool {
+		true
+	}
+
+	fn put_exclusive(&self, local_path: &Path, remote_path: &Path) -> io::Result<bool> {
+		self.put_if_version(local_path, remote_path, None)
+	}
+
+	fn get_version(&self, remote_path: &Path) -> io::Result<Option<String>> {
+		let response = self.request("HEAD", &self.get_key(remote_path), &[], &[], None)?;
+		match response.status {
+			404 => Ok(None),
+			_ if response.is_success() => Ok(response.get_header("etag")),
+			_ => Err(io::Error::new(io::ErrorKind::Other, format!("S3 HEAD failed with status {}", response.status)))
+		}
+	}
+
+	fn put_if_version(&self, local_path: &Path, remote_path: &Path, expected_version: Option<String>) -> io::Result<bool> {
+		let condition = match expected_version {
+			Some(etag) => ("If-Match", etag),
+			None => ("If-None-Match", "*".to_string())
+		};
+
+		let response = self.put_with_conditions(local_path, remote_path, &[condition])?;
+		match response.status {
+			412 => Ok(false),
+			_ if response.is_success() => Ok(true),
+			_ => Err(response.into_error())
+		}
+	}
+
+	fn delete_if_version(&self, remote_path: &Path, expected_version: String) -> io::Result<bool> {
+		let response = self.request("DELETE", &self.get_key(remote_path), &[], &[("If-Match", expected_version)], None)?;
+		match response.status {
+			404 | 412 => Ok(false),
+			_ if response.is_success() => Ok(true),
+			_ => Err(response.into_error())
+		}
+	}
+}
+
+fn read_response(connection: Connection) -> io::Result<Response> {
+	let mut reader = BufReader::new(connection);
+
+	let mut status_line = String::new();
+	reader.read_line(&mut status_line)?;
+	let status = status_line.split_whitespace().nth(1).and_then(|s| s.parse::<u32>().ok())
+		.ok_or(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid HTTP status line {}", status_line)))?;
+
+	let mut headers : Vec<(String, String)> = Vec::new();
+	loop {
+		let mut line = String::new();
+		reader.read_line(&mut line)?;
+		let line = line.trim_end();
+		if line.is_empty() {
+			break;
+		}
+		if let Some(i) = line.find(':') {
+			headers.push((line[..i].trim().to_string(), line[i + 1..].trim().to_string()));
+		}
+	}
+
+	Ok(Response {status: status, headers: headers, reader: reader})
+}
+
+/// Percent-encoding as required by the AWS signature (the slash is kept in paths)
+fn uri_encode(value: &str, encode_slash: bool) -> String {
+	let mut encoded = String::new();
+	for byte in value.bytes() {
+		match byte {
+			b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+			b'/' if !encode_slash => encoded.push('/'),
+			_ => encoded.push_str(&format!("%{:02X}", byte))
+		}
+	}
+	encoded
+}
+
+/// Content of all the <tag>...</tag> elements (enough for the flat S3 responses)
+fn xml_values(xml: &str, tag: &str) -> Vec<String> {
+	let open = format!("<{}>", tag);
+	let close = format!("</{}>", tag);
+
+	let mut values : Vec<String> = Vec::new();
+	let mut rest = xml;
+	while let Some(start) = rest.find(&open[..]) {
+		let after = &rest[start + open.len()..];
+		match after.find(&close[..]) {
+			Some(end) => {
+				values.push(xml_unescape(&after[..end]));
+				rest = &after[end + close.len()..];
+			},
+			None => break
+		}
+	}
+	values
+}
+
+fn xml_unescape(value: &str) -> String {
+	value.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&apos;", "'").replace("&amp;", "&")
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+	let mut hasher = Sha256::new();
+	hasher.input(data);
+	hasher.result_str()
+}
+
+/// Hash of a range of the file, the file is left positioned at the start of the range
+fn sha256_range(file: &mut File, offset: u64, length: u64) -> io::Result<String> {
+	file.seek(SeekFrom::Start(offset))?;
+	let mut hasher = Sha256::new();
+	let mut reader = file.take(length);
+	let mut buffer = [0u8; 64 * 1024];
+	let mut read = 0;
+	loop {
+		let count = reader.read(&mut buffer)?;
+		if count == 0 {
+			break;
+		}
+		hasher.input(&buffer[..count]);
+		read = read + count as u64;
+	}
+	if read != length {
+		return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "File changed while it was uploaded"));
+	}
+	let file = reader.into_inner();
+	file.seek(SeekFrom::Start(offset))?;
+	Ok(hasher.result_str())
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+	use std::fs;
+	use std::fs::File;
+	use std::io::BufRead;
+	use std::io::BufReader;
+	use std::io::Read;
+	use std::io::Write;
+	use std::net::TcpListener;
+	use std::net::TcpStream;
+	use std::path::Path;
+	use std::sync::Arc;
+	use std::sync::Mutex;
+	use std::thread;
+
+	use testutil;
+	use transport::Transport;
+	use super::S3Transport;
+	use super::sha256_hex;
+
+	/// Minimal in-memory S3 server: PUT (with If-None-Match), GET (sent in chunks), HEAD and DELETE (with If-Match)
+	/// Like S3, it rejects a body that does not match its signed sha256
+	fn start_server() -> String {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let address = listener.local_addr().unwrap().to_string();
+		let objects : Arc<Mutex<HashMap<String, Vec<u8>>>> = Arc::new(Mutex::new(HashMap::new()));
+
+		thread::spawn(move || {
+			for stream in listener.incoming() {
+				let _ = handle_request(stream.unwrap(), &objects);
+			}
+		});
+		address
+	}
+
+	fn handle_request(stream: TcpStream, objects: &Arc<Mutex<HashMap<String, Vec<u8>>>>) -> ::std::io::Result<()> {
+		let mut reader = BufReader::new(stream.try_clone()?);
+		let mut writer = stream;
+
+		let mut request_line = String::new();
+		reader.read_line(&mut request_line)?;
+		let mut parts = request_line.split_whitespace();
+		let method = parts.next().unwrap_or("").to_string();
+		let path = parts.next().unwrap_or("").split('?').next().unwrap().to_string();
+
+		let mut headers : HashMap<String, String> = HashMap::new();
+		loop {
+			let mut line = String::new();
+			reader.read_line(&mut line)?;
+			let line = line.trim_end();
+			if line.is_empty() {
+				break;
+			}
+			let i = line.find(':').unwrap();
+			headers.insert(line[..i].trim().to_lowercase(), line[i + 1..].trim().to_string());
+		}
+		assert!(headers.contains_key("authorization"));
+
+		let length = headers.get("content-length").and_then(|v| v.parse::<u64>().ok()).unwrap_or(0);
+		let mut body = Vec::new();
+		reader.take(length).read_to_end(&mut body)?;
+		if headers.get("x-amz-content-sha256") != Some(&sha256_hex(&body)) {
+			write!(writer, "HTTP/1.1 400 Bad Request\r\ncontent-length: 0\r\n\r\n")?;
+			return writer.flush();
+		}
+
+		let mut objects = objects.lock().unwrap();
+		match &method[..] {
+			"PUT" => {
+				if headers.get("if-none-match").map(|v| v == "*").unwrap_or(false) && objects.contains_key(&path) {
+					write!(writer, "HTTP/1.1 412 Precondition Failed\r\ncontent-length: 0\r\n\r\n")?;
+				} else {
+					objects.insert(path, body);
+					write!(writer, "HTTP/1.1 200 OK\r\netag: \"1\"\r\ncontent-length: 0\r\n\r\n")?;
+				}
+			},
+			"GET" => match objects.get(&path) {
+				Some(content) => {
+					write!(writer, "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n")?;
+					for chunk in content.chunks(7) {
+						write!(writer, "{:x}\r\n", chunk.len())?;
+						writer.write_all(chunk)?;
+						write!(writer, "\r\n")?;
+					}
+					write!(writer, "0\r\n\r\n")?;
+				},
+				None => write!(writer, "HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\n\r\n")?
+			},
+			"HEAD" => match objects.get(&path) {
+				Some(content) => write!(writer, "HTTP/1.1 200 OK\r\netag: \"1\"\r\ncontent-length: {}\r\n\r\n", content.len())?,
+				None => write!(writer, "HTTP/1.1 404 Not Found\r\n\r\n")?
+			},
+			"DELETE" => {
+				if headers.get("if-match").map(|v| v != "\"1\"" || !objects.contains_key(&path)).unwrap_or(false) {
+					write!(writer, "HTTP/1.1 412 Precondition Failed\r\ncontent-length: 0\r\n\r\n")?;
+				} else {
+					objects.remove(&path);
+					write!(writer, "HTTP/1.1 204 No Content\r\n\r\n")?;
+				}
+			},
+			_ => write!(writer, "HTTP/1.1 501 Not Implemented\r\ncontent-length: 0\r\n\r\n")?
+		};
+		writer.flush()
+	}
+
+	#[test]
+	fn put_get_and_delete_against_a_local_server() {
+		let transport = S3Transport::new(&start_server(), false, Path::new("/bucket/prefix"), "us-east-1", "access", "secret");
+
+		let local_path = testutil::get_test_path("s3-upload");
+		let content : Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+		File::create(&local_path).unwrap().write_all(&content).unwrap();
+
+		let remote_path = Path::new("objects/ab/cd");
+		assert!(transport.stat(remote_path).unwrap().is_none());
+		transport.put(&local_path, remote_path).unwrap();
+		assert_eq!(transport.stat(remote_path).unwrap().unwrap().get_size(), 1000);
+		assert!(!transport.put_if_version(&local_path, remote_path, None).unwrap());
+
+		let downloaded_path = testutil::get_test_path("s3-download");
+		transport.get(remote_path, &downloaded_path).unwrap();
+		let mut downloaded = Vec::new();
+		File::open(&downloaded_path).unwrap().read_to_end(&mut downloaded).unwrap();
+		assert_eq!(downloaded, content);
+
+		assert!(!transport.delete_if_version(remote_path, "\"2\"".to_string()).unwrap());
+		let version = transport.get_version(remote_path).unwrap().unwrap();
+		assert!(transport.delete_if_version(remote_path, version).unwrap());
+		assert!(transport.stat(remote_path).unwrap().is_none());
+
+		transport.put(&local_path, remote_path).unwrap();
+		transport.delete(remote_path).unwrap();
+		assert!(transport.stat(remote_path).unwrap().is_none());
+
+		fs::remove_file(&local_path).unwrap();
+		fs::remove_file(&downloaded_path).unwrap();
+	}
+}
@@ -0,0 +1,129 @@
+use std::net::TcpStream;
+use std::path::Path;
+use std::path::PathBuf;
+use ssh2;
+use ssh2::Session;
+use std::io;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::BufReader;
+use std::io::BufWriter;
+
+use sshengine;
+use sshengine::to_io_error;
+use throttle;
+use transport::RemoteStat;
+use transport::Transport;
+
+/// Remote accessed only with sftp (for servers without scp)
+pub struct SftpTransport {
+	_tcp: TcpStream,
+	sess: Session,
+	root_path: PathBuf
+}
+
+impl SftpTransport {
+	pub fn connect(adress: &str, user: &str, password: &str, root_path: &Path) -> SftpTransport {
+		let (tcp, sess) = sshengine::open_session(adress, user, password);
+		SftpTransport {_tcp: tcp, sess: sess, root_path: root_path.to_path_buf()}
+	}
+}
+
+impl Transport for SftpTransport {
+	fn list(&self, remote_path: &Path) -> io::Result<Vec<(PathBuf, RemoteStat)>> {
+		let sftp = self.sess.sftp().map_err(to_io_error)?;
+		let elements = sftp.readdir(&self.root_path.join(remote_path)).map_err(to_io_error)?;
+
+		Ok(elements.into_iter()
+			.map(|(path, stat)| (remote_path.join(path.file_name().unwrap()), RemoteStat::new(stat.size.unwrap_or(0), stat.is_dir())))
+			.collect())
+	}
+
+	fn get(&self, remote_path: &Path, local_path: &Path) -> io::Result<()> {
+		let sftp = self.sess.sftp().map_err(to_io_error)?;
+		let remote_file = sftp.open(&self.root_path.join(remote_path)).map_err(to_io_error)?;
+		let mut remote_file_reader = BufReader::new(remote_file);
+
+		let mut local_file_writer = BufWriter::new(File::create(&local_path)?);
+
+		throttle::copy(&mut remote_file_reader, &mut local_file_writer)?;
+		Ok(())
+	}
+
+	fn put(&self, local_path: &Path, remote_path: &Path) -> io::Result<()> {
+		let sftp = self.sess.sftp().map_err(to_io_error)?;
+		let mut file_reader = BufReader::new(File::open(&local_path)?);
+
+		let remote_file = sftp.create(&self.root_path.join(remote_path)).map_err(to_io_error)?;
+		let mut remote_file_writer = BufWriter::new(remote_file);
+
+		throttle::copy(&mut file_reader, &mut remote_file_writer)?;
+		Ok(())
+	}
+
+	fn delete(&self, remote_path: &Path) -> io::Result<()> {
+		let sftp = self.sess.sftp().map_err(to_io_error)?;
+		sftp.unlink(&self.root_path.join(remote_path)).map_err(to_io_error)
+	}
+
+	fn stat(&self, remote_path: &Path) -> io::Result<Option<RemoteStat>> {
+		let sftp = self.sess.sftp().map_err(to_io_error)?;
+		sshengine::stat_remote(&sftp, &self.root_path.join(remote_path))
+	}
+
+	fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+		let sftp = self.sess.sftp().map_err(to_io_error)?;
+		sshengine::rename_overwriting(&sftp, &self.root_path.join(from), &self.root_path.join(to))
+	}
+
+	fn mkdir(&self, remote_path: &Path) -> io::Result<()> {
+		let sftp = self.sess.sftp().map_err(to_io_error)?;
+		sftp.mkdir(&self.root_path.join(remote_path), 0o755).map_err(to_io_error)
+	}
+
+	fn get_remote_hash(&self, remote_path: &Path) -> io::Result<Option<String>> {
+		sshengine::remote_sha256(&self.sess, &self.root_path.join(remote_path))
+	}
+
+	fn put_exclusive(&self, local_path: &Path, remote_path: &Path) -> io::Result<bool> {
+		let sftp = self.sess.sftp().map_err(to_io_error)?;
+		sshengine::put_exclusive(&sftp, local_path, &self.root_path.join(remote_path))
+	}
+
+	fn supports_resume(&self) -> bool {
+		true
+	}
+
+	fn put_from(&self, local_path: &Path, remote_path: &Path, offset: u64) -> io::Result<()> {
+		let sftp = self.sess.sftp().map_err(to_io_error)?;
+
+		let mut file = File::open(&local_path)?;
+		file.seek(SeekFrom::Start(offset))?;
+		let mut file_reader = BufReader::new(file);
+
+		let mut remote_file = sftp.open_mode(&self.root_path.join(remote_path), ssh2::WRITE | ssh2::CREATE, 0o644, ssh2::OpenType::File).map_err(to_io_error)?;
+		remote_file.seek(SeekFrom::Start(offset))?;
+		let mut remote_file_writer = BufWriter::new(remote_file);
+
+		throttle::copy(&mut file_reader, &mut remote_file_writer)?;
+		Ok(())
+	}
+
+	fn get_from(&self, remote_path: &Path, local_path: &Path, offset: u64) -> io::Result<()> {
+		let sftp = self.sess.sftp().map_err(to_io_error)?;
+
+		let mut remote_file = sftp.open(&self.root_path.join(remote_path)).map_err(to_io_error)?;
+		remote_file.seek(SeekFrom::Start(offset))?;
+		let mut remote_file_reader = BufReader::new(remote_file);
+
+		let mut local_file = OpenOptions::new().write(true).create(true).open(&local_path)?;
+		local_file.set_len(offset)?;
+		local_file.seek(SeekFrom::Start(offset))?;
+		let mut local_file_writer = BufWriter::new(local_file);
+
+		throttle::copy(&mut remote_file_reader, &mut local_file_writer)?;
+		Ok(())
+	}
+}
@@ -0,0 +1,51 @@
+use std::path::Path;
+use std::path::PathBuf;
+
+use config::Config;
+
+/// Part of the repository materialized in the working directory of this machine
+/// The rules are paths relative to the data path (or source name/path): a file is in the set if it is under an include path
+/// (or if there is no include list, an empty list includes nothing) and under no exclude path
+/// The files outside the set stay in the metadata, they are neither extracted nor committed nor deleted
+pub struct SparseSet {
+	data_path: PathBuf,
+	include: Option<Vec<PathBuf>>,
+	exclude: Vec<PathBuf>
+}
+
+impl SparseSet {
+	pub fn from_config(config: &Config) -> SparseSet {
+		SparseSet::new(config, config.get_sparse_include().as_ref(), &config.get_sparse_exclude())
+	}
+
+	/// Set of other rules than the ones of the config, to compare them before saving them
+	pub fn new(config: &Config, include: Option<&Vec<String>>, exclude: &Vec<String>) -> SparseSet {
+		SparseSet {
+			data_path: config.get_data_path(),
+			include: include.map(|paths| paths.iter().map(|p| PathBuf::from(normalize_rule(p))).collect()),
+			exclude: exclude.iter().map(|p| PathBuf::from(normalize_rule(p))).collect()
+		}
+	}
+
+	/// filename is a file of the metadata or of the working directory, under the data path
+	pub fn contains(&self, filename: &str) -> bool {
+		let relative = self.get_relative_path(filename);
+		self.include.as_ref().map_or(true, |include| include.iter().any(|p| relative.starts_with(p)))
+			&& !self.exclude.iter().any(|p| relative.starts_with(p))
+	}
+
+	/// True if the file is under the rule path
+	pub fn is_under(&self, filename: &str, rule: &str) -> bool {
+		self.get_relative_path(filename).starts_with(normalize_rule(rule))
+	}
+
+	fn get_relative_path<'a>(&self, filename: &'a str) -> &'a Path {
+		let path = Path::new(filename);
+		path.strip_prefix(&self.data_path).unwrap_or(path)
+	}
+}
+
+/// Rules are written like the paths of the working directory: "photos/2019/", "./photos" and "photos/2019" are the same
+pub fn normalize_rule(rule: &str) -> String {
+	rule.trim_start_matches("./").trim_end_matches('/').to_string()
+}
@@ -1,95 +1,165 @@
-
 use std::net::TcpStream;
 use std::path::Path;
 use std::path::PathBuf;
+use ssh2;
 use ssh2::Session;
 use std::io;
-use std::io::Write;
 use std::io::Read;
 use std::fs::File;
 use std::io::BufReader;
 use std::io::BufWriter;
 
+use throttle;
+use transport::RemoteStat;
+use transport::Transport;
 
-pub fn upload_to_remote(adress: &str, user: &str, password: &str, remote_root_path: &Path, files_path: &Vec<PathBuf>) {
-	let tcp = TcpStream::connect(adress).unwrap();
+/// Open an authenticated ssh session
+/// The tcp stream must live as long as the session
+pub fn open_session(adress: &str, user: &str, password: &str) -> (TcpStream, Session) {
+	let tcp = TcpStream::connect(adress).expect("Cannot connect to remote");
 	let mut sess = Session::new().unwrap();
-	sess.handshake(&tcp).unwrap();
-	sess.userauth_password(user, password).unwrap();
-
-	for file_path in files_path {
-		let filename = file_path.file_name().unwrap();
-
-		let mut file = File::open(&file_path).unwrap();
-		let file_size = file.metadata().unwrap().len();
-		let mut file_reader = BufReader::new(file);
-
-		let mut remote_path = remote_root_path.join(filename);
+	sess.handshake(&tcp).expect("Cannot handshake with remote");
+	sess.userauth_password(user, password).expect("Cannot authenticate on remote");
+	(tcp, sess)
+}
 
-		println!("Remote path {:?}", remote_path);
+pub fn to_io_error(error: ssh2::Error) -> io::Error {
+	io::Error::new(io::ErrorKind::Other, error.message().to_string())
+}
 
-		let mut remote_channel = sess.scp_send(&remote_path, 0o644, file_size, None).unwrap();
-		let mut remote_file_writer = BufWriter::new(remote_channel);
+/// SFTP status of a missing file (LIBSSH2_FX_NO_SUCH_FILE)
+const SFTP_NO_SUCH_FILE: i32 = 2;
 
-		io::copy(&mut file_reader, &mut remote_file_writer);
+/// Stat a remote path, None only if it does not exist: any other failure is an error
+pub fn stat_remote(sftp: &ssh2::Sftp, path: &Path) -> io::Result<Option<RemoteStat>> {
+	match sftp.stat(path) {
+		Ok(stat) => Ok(Some(RemoteStat::new(stat.size.unwrap_or(0), stat.is_dir()))),
+		Err(ref e) if e.code() == SFTP_NO_SUCH_FILE => Ok(None),
+		Err(e) => Err(to_io_error(e))
 	}
 }
 
-pub fn download_file_from_remote(adress: &str, user: &str, password: &str, remote_path: &Path, local_path: &Path) {
-	//remote duplicate code
-	let tcp = TcpStream::connect(adress).unwrap();
-	let mut sess = Session::new().unwrap();
-	sess.handshake(&tcp).unwrap();
-	sess.userauth_password(user, password).unwrap();
-
-	let sftp = sess.sftp().expect("Cannot create sftp");
-
-
-	let (mut remote_file, stat) = sess.scp_recv(&remote_path).expect("Cannot open remote file");
+/// Rename replacing the destination
+/// SFTP v3 servers (OpenSSH) ignore the flags and refuse to replace an existing file,
+/// the destination is then removed first: the rename is not atomic there but the destination is never partial
+pub fn rename_overwriting(sftp: &ssh2::Sftp, from: &Path, to: &Path) -> io::Result<()> {
+	match sftp.rename(from, to, Some(ssh2::OVERWRITE | ssh2::ATOMIC | ssh2::NATIVE)) {
+		Ok(()) => Ok(()),
+		Err(e) => {
+			if sftp.stat(to).is_err() {
+				return Err(to_io_error(e));
+			}
+			sftp.unlink(to).map_err(to_io_error)?;
+			sftp.rename(from, to, None).map_err(to_io_error)
+		}
+	}
+}
 
-	let mut remote_file_reader = BufReader::new(remote_file);
+/// Run sha256sum on the remote, None if the command is not available there
+pub fn remote_sha256(sess: &Session, path: &Path) -> io::Result<Option<String>> {
+	let quoted_path = format!("'{}'", path.to_string_lossy().replace("'", "'\\''"));
+	let mut channel = sess.channel_session().map_err(to_io_error)?;
+	channel.exec(&format!("sha256sum -b {}", quoted_path)).map_err(to_io_error)?;
+
+	let mut output = String::new();
+	channel.read_to_string(&mut output)?;
+	channel.wait_close().map_err(to_io_error)?;
+	if channel.exit_status().map_err(to_io_error)? != 0 {
+		return Ok(None);
+	}
 
+	match output.split_whitespace().next() {
+		Some(hash) if hash.len() == 64 && hash.chars().all(|c| c.is_digit(16)) => Ok(Some(format!("sha256:{}", hash.to_lowercase()))),
+		_ => Ok(None)
+	}
+}
 
-	println!("local path is {:?}", local_path);
+/// Create a remote file only if it does not exist (SFTP CREATE|EXCLUDE open), false if it exists
+pub fn put_exclusive(sftp: &ssh2::Sftp, local_path: &Path, remote_path: &Path) -> io::Result<bool> {
+	let mut remote_file = match sftp.open_mode(remote_path, ssh2::WRITE | ssh2::CREATE | ssh2::EXCLUSIVE, 0o644, ssh2::OpenType::File) {
+		Ok(file) => file,
+		//OpenSSH reports an existing file as a generic failure
+		Err(e) => return match stat_remote(sftp, remote_path)? {
+			Some(_) => Ok(false),
+			None => Err(to_io_error(e))
+		}
+	};
+	io::copy(&mut File::open(local_path)?, &mut remote_file)?;
+	Ok(true)
+}
 
-	let mut local_file = File::create(&local_path).expect("Cannot open local file");
-	let mut local_file_writer = BufWriter::new(local_file);
+/// Remote accessed with scp for the file transfers and sftp for the rest
+pub struct SshTransport {
+	_tcp: TcpStream,
+	sess: Session,
+	root_path: PathBuf
+}
 
-	io::copy(&mut remote_file_reader, &mut local_file_writer);
+impl SshTransport {
+	pub fn connect(adress: &str, user: &str, password: &str, root_path: &Path) -> SshTransport {
+		let (tcp, sess) = open_session(adress, user, password);
+		SshTransport {_tcp: tcp, sess: sess, root_path: root_path.to_path_buf()}
+	}
 }
 
+impl Transport for SshTransport {
+	fn list(&self, remote_path: &Path) -> io::Result<Vec<(PathBuf, RemoteStat)>> {
+		let sftp = self.sess.sftp().map_err(to_io_error)?;
+		let elements = sftp.readdir(&self.root_path.join(remote_path)).map_err(to_io_error)?;
 
-pub fn download_folder_from_remote(adress: &str, user: &str, password: &str, remote_root_path: &Path, local_root_path: &Path) {
-	//remote duplicate code
-	let tcp = TcpStream::connect(adress).unwrap();
-	let mut sess = Session::new().unwrap();
-	sess.handshake(&tcp).unwrap();
-	sess.userauth_password(user, password).unwrap();
+		Ok(elements.into_iter()
+			.map(|(path, stat)| (remote_path.join(path.file_name().unwrap()), RemoteStat::new(stat.size.unwrap_or(0), stat.is_dir())))
+			.collect())
+	}
 
-	let sftp = sess.sftp().expect("Cannot create sftp");
-	let elements = sftp.readdir(remote_root_path).expect("Cannot read remote directory");
+	fn get(&self, remote_path: &Path, local_path: &Path) -> io::Result<()> {
+		let (remote_file, _) = self.sess.scp_recv(&self.root_path.join(remote_path)).map_err(to_io_error)?;
+		let mut remote_file_reader = BufReader::new(remote_file);
+		let mut local_file_writer = BufWriter::new(File::create(&local_path)?);
 
-	println!("Read : {}", elements.len());
+		throttle::copy(&mut remote_file_reader, &mut local_file_writer)?;
+		Ok(())
+	}
 
-	for (path, file_stat) in elements {
-		let filename = path.file_name().unwrap();
+	fn put(&self, local_path: &Path, remote_path: &Path) -> io::Result<()> {
+		let file = File::open(&local_path)?;
+		let file_size = file.metadata()?.len();
+		let mut file_reader = BufReader::new(file);
 
-		println!("filename {:?}", filename);
+		let remote_path = self.root_path.join(remote_path);
+		let remote_channel = self.sess.scp_send(&remote_path, 0o644, file_size, None).map_err(to_io_error)?;
+		let mut remote_file_writer = BufWriter::new(remote_channel);
 
+		throttle::copy(&mut file_reader, &mut remote_file_writer)?;
+		Ok(())
+	}
 
-		let (mut remote_file, stat) = sess.scp_recv(&path).expect("Cannot open remote file");
+	fn delete(&self, remote_path: &Path) -> io::Result<()> {
+		let sftp = self.sess.sftp().map_err(to_io_error)?;
+		sftp.unlink(&self.root_path.join(remote_path)).map_err(to_io_error)
+	}
 
-		let mut remote_file_reader = BufReader::new(remote_file);
+	fn stat(&self, remote_path: &Path) -> io::Result<Option<RemoteStat>> {
+		let sftp = self.sess.sftp().map_err(to_io_error)?;
+		stat_remote(&sftp, &self.root_path.join(remote_path))
+	}
 
-		let mut local_path = local_root_path.join(filename);
+	fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+		let sftp = self.sess.sftp().map_err(to_io_error)?;
+		rename_overwriting(&sftp, &self.root_path.join(from), &self.root_path.join(to))
+	}
 
-		println!("local path is {:?}", local_path);
+	fn put_exclusive(&self, local_path: &Path, remote_path: &Path) -> io::Result<bool> {
+		let sftp = self.sess.sftp().map_err(to_io_error)?;
+		put_exclusive(&sftp, local_path, &self.root_path.join(remote_path))
+	}
 
-		let mut local_file = File::create(&local_path).expect("Cannot open local file");
-		let mut local_file_writer = BufWriter::new(local_file);
+	fn mkdir(&self, remote_path: &Path) -> io::Result<()> {
+		let sftp = self.sess.sftp().map_err(to_io_error)?;
+		sftp.mkdir(&self.root_path.join(remote_path), 0o755).map_err(to_io_error)
+	}
 
-		io::copy(&mut remote_file_reader, &mut local_file_writer);
+	fn get_remote_hash(&self, remote_path: &Path) -> io::Result<Option<String>> {
+		remote_sha256(&self.sess, &self.root_path.join(remote_path))
 	}
 }
-	
-
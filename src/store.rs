@@ -7,90 +7,266 @@ use std::path::PathBuf;
 use filetime::FileTime;
 use filetime;
 
-use flate2::Compression;
-use flate2::write::GzEncoder;
-use flate2::read::GzDecoder;
-
 use std::io;
 use std::io::Result;
 use std::io::Read;
 use std::io::Write;
 
 use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
 use crypto::sha2::Sha256;
 
-pub fn store_file(store_path: &Path, source_file: &Path) -> String {
+use rustc_serialize::hex::ToHex;
+
+use cipher;
+use cipher::Key;
+use codec;
+use codec::CodecSettings;
+use codec::CodecWriter;
+use metadata;
+use pack::PackedObjects;
+use workingdirectory;
+
+/// The object is compressed with the codec, then encrypted when there is a key
+/// With a key, it is named by a keyed hash of its content
+/// The object takes its final name only once every layer has been completed and written
+pub fn store_file(store_path: &Path, source_file: &Path, key: Option<&Key>, codec_settings: CodecSettings) -> Result<String> {
 	let tmp_path = store_path.join("tmp");
 
-	let mut file_reader = BufReader::new(File::open(&source_file).unwrap());
-	let mut file_writer = BufWriter::new(File::create(&tmp_path).unwrap());
+	let mut file_reader = BufReader::new(File::open(&source_file)?);
+	let file_writer = BufWriter::new(File::create(&tmp_path)?);
+	let object_writer = cipher::encrypt_writer(file_writer, key)?;
 
-	let mut gz_file_writer = GzEncoder::new(file_writer, Compression::Default);
+	let codec_file_writer = CodecWriter::new(object_writer, codec_settings)?;
 
-	let mut hash_file_write = HashWriter::new(gz_file_writer);
+	let mut hash_file_write = HashWriter::new_keyed(codec_file_writer, key);
 
-	io::copy(&mut file_reader, &mut hash_file_write);
+	io::copy(&mut file_reader, &mut hash_file_write)?;
 
 	let hash = hash_file_write.get_hash();
+	let file_writer = hash_file_write.into_inner().finish()?.finish()?;
+	file_writer.into_inner().map_err(|e| e.into_error())?;
 
-	let final_path = store_path.join(&hash);
-	fs::rename(&tmp_path, &final_path);
+	let final_path = get_object_path(store_path, &hash);
+	fs::create_dir_all(final_path.parent().unwrap())?;
+	fs::rename(&tmp_path, &final_path)?;
 
-	hash
+	Ok(hash)
 }
 
-pub fn extract_file(store_path: &Path, hash: &String, data_path: &Path, filename: &String, timestamp: u64) {
-	let file_in_store = store_path.join(hash);
-	let file_in_wd = Path::new(filename);
-	let tmp_path = data_path.join("tmp");
+/// The content is written next to the file then renamed over it, the sources can be on different file systems
+/// The file is replaced only by a complete content, a failed extraction leaves it untouched
+pub fn extract_file(store_path: &Path, packed_objects: &PackedObjects, key: Option<&Key>, hash: &String, file_in_wd: &Path, timestamp: u64) -> Result<()> {
+	let tmp_path = PathBuf::from(format!("{}{}", file_in_wd.to_str().unwrap(), workingdirectory::EXTRACT_SUFFIX));
 
-	println!("Extract from {} to {} ", file_in_store.to_str().unwrap(), file_in_wd.to_str().unwrap());
+	println!("Extract {} to {} ", hash, file_in_wd.to_str().unwrap());
+
+	let object_reader = open_object(store_path, packed_objects, hash)
+		.ok_or(io::Error::new(io::ErrorKind::NotFound, format!("Object {} not found in the store", hash)))?;
+	let mut content_reader = codec::decoder(cipher::decrypt_reader(object_reader, key)?)?;
+
+	if let Some(parent) = file_in_wd.parent() {
+		fs::create_dir_all(parent)?;
+	}
 
-	let mut file_reader = BufReader::new(File::open(&file_in_store).unwrap());
-	let mut gz_file_reader = GzDecoder::new(file_reader).unwrap();
+	let seconds_since_1970 = FileTime::from_seconds_since_1970(timestamp, 0);
+	let extracted = write_file(&mut content_reader, &tmp_path)
+		.and_then(|_| filetime::set_file_times(&tmp_path, seconds_since_1970, seconds_since_1970));
+	if let Err(e) = extracted {
+		let _ = fs::remove_file(&tmp_path);
+		return Err(e);
+	}
+	fs::rename(&tmp_path, &file_in_wd)
+}
 
-	let mut file_writer = BufWriter::new(File::create(&tmp_path).unwrap());
+fn write_file(reader: &mut dyn Read, path: &Path) -> Result<()> {
+	let mut file_writer = BufWriter::new(File::create(path)?);
+	io::copy(reader, &mut file_writer)?;
+	file_writer.into_inner().map_err(|e| e.into_error())?.sync_all()
+}
 
-	io::copy(&mut gz_file_reader, &mut file_writer);
+/// Name of the file giving the layout version of a store (absent for the first flat layout)
+pub const STORE_VERSION_FILE: &'static str = "store-version";
+/// All the objects directly in the store directory
+pub const FLAT_LAYOUT: u32 = 1;
+/// Objects fanned out in two levels of sub directories: ab/cd/abcdef...
+pub const SHARDED_LAYOUT: u32 = 2;
 
-	fs::rename(&tmp_path, &file_in_wd);	
+pub fn get_store_version(store_path: &Path) -> u32 {
+	let mut version = String::new();
+	match File::open(store_path.join(STORE_VERSION_FILE)) {
+		Ok(mut file) => { file.read_to_string(&mut version).unwrap(); },
+		Err(_) => return FLAT_LAYOUT
+	};
+	version.trim().parse::<u32>().expect("Invalid store version file")
+}
 
-	let seconds_since_1970 = FileTime::from_seconds_since_1970(timestamp, 0);
-	filetime::set_file_times(&file_in_wd, seconds_since_1970, seconds_since_1970);
+pub fn write_store_version(store_path: &Path, version: u32) {
+	metadata::write_atomically(&store_path.join(STORE_VERSION_FILE), version.to_string().as_bytes(), 0).unwrap();
+}
+
+/// Path of an object relative to the root of a store with the given layout
+pub fn get_object_relative_path(version: u32, hash: &str) -> PathBuf {
+	match version {
+		FLAT_LAYOUT => PathBuf::from(hash),
+		SHARDED_LAYOUT => Path::new(&hash[0..2]).join(&hash[2..4]).join(hash),
+		_ => panic!("Unknown store version {}, this store was written by a newer Yabs", version)
+	}
+}
+
+pub fn get_object_path(store_path: &Path, hash: &str) -> PathBuf {
+	store_path.join(get_object_relative_path(get_store_version(store_path), hash))
+}
+
+/// Look for an object in both layouts (a migration may have been interrupted)
+pub fn find_object(store_path: &Path, hash: &str) -> Option<PathBuf> {
+	let object_path = get_object_path(store_path, hash);
+	if object_path.is_file() {
+		return Some(object_path);
+	}
+
+	[FLAT_LAYOUT, SHARDED_LAYOUT].iter()
+		.map(|&version| store_path.join(get_object_relative_path(version, hash)))
+		.find(|path| path.is_file())
+}
+
+/// Reader on the bytes of an object, loose or packed
+pub fn open_object(store_path: &Path, packed_objects: &PackedObjects, hash: &str) -> Option<Box<dyn Read>> {
+	match find_object(store_path, hash) {
+		Some(path) => Some(Box::new(BufReader::new(File::open(&path).unwrap()))),
+		None => packed_objects.get(hash).map(|packed_object| Box::new(packed_object.open().unwrap()) as Box<dyn Read>)
+	}
 }
 
+/// Create the store directory of a new repository, with the sharded layout
+pub fn create_store(store_path: &Path) {
+	fs::create_dir_all(store_path).unwrap();
+	if get_all_filenames(store_path).is_empty() {
+		write_store_version(store_path, SHARDED_LAYOUT);
+	}
+}
+
+/// Move every object of the store to its sharded path, then mark the store as sharded
+/// Can be run again if it has been interrupted
+pub fn migrate_store(store_path: &Path) {
+	let mut nb_moved = 0;
+	for path in get_all_filenames(store_path) {
+		let hash = path.file_name().unwrap().to_str().unwrap().to_string();
+		if !is_object_name(&hash) {
+			continue;
+		}
+
+		let sharded_path = store_path.join(get_object_relative_path(SHARDED_LAYOUT, &hash));
+		if path != sharded_path {
+			fs::create_dir_all(sharded_path.parent().unwrap()).unwrap();
+			fs::rename(&path, &sharded_path).unwrap();
+			nb_moved = nb_moved + 1;
+		}
+	}
+
+	write_store_version(store_path, SHARDED_LAYOUT);
+	println!("{} objects moved to the sharded layout", nb_moved);
+}
+
+/// All the files of the store, including the ones in sub directories
 pub fn get_all_filenames(store_path: &Path) -> Vec<PathBuf> {
 	let mut paths : Vec<PathBuf> = Vec::new();
+	read_store_folder_rec(store_path, &mut paths);
+	paths
+}
 
-	let dir_entries = fs::read_dir(store_path).unwrap();
+fn read_store_folder_rec(folder_path: &Path, paths: &mut Vec<PathBuf>) {
+	let dir_entries = fs::read_dir(folder_path).unwrap();
 
 	for entry in dir_entries {
-		//let mut filename = entry.unwrap().file_name().into_string().unwrap();
-		let mut path = entry.unwrap().path();
-		paths.push(path);
-    }
+		let path = entry.unwrap().path();
+		if path.is_dir() {
+			read_store_folder_rec(&path, paths);
+		} else {
+			paths.push(path);
+		}
+	}
+}
+
+/// Objects are named by the sha256 of their content (or its HMAC-SHA256 in an encrypted store)
+pub fn is_object_name(name: &str) -> bool {
+	name.len() == 64 && name.chars().all(|c| c.is_digit(16))
+}
+
+/// Check that an object decompresses to content matching its hash
+pub fn check_file(file_in_store: &Path, key: Option<&Key>, hash: &str) -> bool {
+	match File::open(&file_in_store) {
+		Ok(f) => check_object(BufReader::new(f), key, hash),
+		Err(_) => false
+	}
+}
+
+/// Size of the content of an object, once decrypted and decompressed
+pub fn read_content_size(store_path: &Path, packed_objects: &PackedObjects, key: Option<&Key>, hash: &str) -> Result<u64> {
+	let object_reader = open_object(store_path, packed_objects, hash).ok_or(io::Error::new(io::ErrorKind::NotFound, "Object not found"))?;
+	let mut content_reader = codec::decoder(cipher::decrypt_reader(object_reader, key)?)?;
+	io::copy(&mut content_reader, &mut io::sink())
+}
 
-    paths    
+/// Check that the object bytes decrypt and decompress (whatever the codec) to content matching the hash
+pub fn check_object<R: Read>(object_reader: R, key: Option<&Key>, hash: &str) -> bool {
+	let object_reader = match cipher::decrypt_reader(object_reader, key) {
+		Ok(r) => r,
+		Err(_) => return false
+	};
+	let mut content_reader = match codec::decoder(object_reader) {
+		Ok(r) => r,
+		Err(_) => return false
+	};
+
+	let mut hash_writer = HashWriter::new_keyed(io::sink(), key);
+	match io::copy(&mut content_reader, &mut hash_writer) {
+		Ok(_) => hash_writer.get_hash() == hash,
+		Err(_) => false
+	}
+}
+
+enum Hasher {
+	Plain(Sha256),
+	Keyed(Hmac<Sha256>)
 }
 
-struct HashWriter<W: Write> {
-	hasher: Sha256,
+pub struct HashWriter<W: Write> {
+	hasher: Hasher,
 	writer: W
 }
 
 impl <W: Write> HashWriter<W>  {
-	fn new(inner: W) -> HashWriter<W> {
-		HashWriter {hasher: Sha256::new(), writer: inner}
+	pub fn new(inner: W) -> HashWriter<W> {
+		HashWriter {hasher: Hasher::Plain(Sha256::new()), writer: inner}
+	}
+	/// Hash keyed with the name key of the repository when there is one
+	pub fn new_keyed(inner: W, key: Option<&Key>) -> HashWriter<W> {
+		match key {
+			Some(key) => HashWriter {hasher: Hasher::Keyed(Hmac::new(Sha256::new(), key.get_name_key())), writer: inner},
+			None => HashWriter::new(inner)
+		}
+	}
+	pub fn get_hash(&mut self) -> String {
+		match self.hasher {
+			Hasher::Plain(ref mut sha256) => sha256.result_str(),
+			Hasher::Keyed(ref mut hmac) => hmac.result().code().to_hex()
+		}
 	}
-	fn get_hash(&mut self) -> String {
-		self.hasher.result_str()
+	pub fn into_inner(self) -> W {
+		self.writer
 	}
 }
 
 impl <W: Write> Write for HashWriter<W>  {
 	fn write(&mut self, buf: &[u8]) -> Result<usize> {
-		self.hasher.input(&buf);
-		self.writer.write(buf)
+		let length = self.writer.write(buf)?;
+		match self.hasher {
+			Hasher::Plain(ref mut sha256) => sha256.input(&buf[..length]),
+			Hasher::Keyed(ref mut hmac) => hmac.input(&buf[..length])
+		}
+		Ok(length)
 	}
 
 	fn flush(&mut self) -> Result<()> {
@@ -0,0 +1,17 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+
+/// Path in the temporary directory, the name must be unique among the tests run by this process
+pub fn get_test_path(name: &str) -> PathBuf {
+	env::temp_dir().join(format!("yabs-test-{}-{}", process::id(), name))
+}
+
+/// Empty directory for a test
+pub fn new_test_directory(name: &str) -> PathBuf {
+	let path = get_test_path(name);
+	let _ = fs::remove_dir_all(&path);
+	fs::create_dir_all(&path).unwrap();
+	path
+}
@@ -0,0 +1,117 @@
+use std::cmp;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use time;
+
+use config::RateWindow;
+
+/// Global bandwidth cap in bytes per second shared by all the transfers, 0 means unlimited
+static LIMIT: AtomicUsize = AtomicUsize::new(0);
+static ACTIVE_TRANSFERS: AtomicUsize = AtomicUsize::new(0);
+/// Default cap and windows of the bandwidth schedule, used instead of LIMIT when set
+static SCHEDULE: Mutex<Option<(usize, Vec<(RateWindow, usize)>)>> = Mutex::new(None);
+
+pub fn set_limit(bytes_per_second: usize) {
+	*SCHEDULE.lock().unwrap() = None;
+	LIMIT.store(bytes_per_second, Ordering::SeqCst);
+}
+
+/// Follow the bandwidth schedule: the cap is evaluated again at each wait,
+/// so a long running process (daemon, watch) changes its rate with the hour
+pub fn set_schedule(default_bytes_per_second: usize, windows: Vec<(RateWindow, usize)>) {
+	*SCHEDULE.lock().unwrap() = Some((default_bytes_per_second, windows));
+}
+
+/// Current cap in bytes per second: the one of the first window containing the current local hour,
+/// else the default one, 0 means unlimited
+pub fn get_limit() -> usize {
+	match *SCHEDULE.lock().unwrap() {
+		Some((default_limit, ref windows)) => {
+			let hour = time::now().tm_hour as u32;
+			windows.iter().find(|&&(ref window, _)| window.contains_hour(hour)).map(|&(_, limit)| limit).unwrap_or(default_limit)
+		},
+		None => LIMIT.load(Ordering::SeqCst)
+	}
+}
+
+/// Parse a rate like 500K, 2M or 1G (bytes per second), 0 means unlimited
+pub fn parse_rate(rate: &str) -> Option<usize> {
+	let rate = rate.trim();
+	if rate.is_empty() {
+		return None;
+	}
+
+	let (number, multiplier) = match rate.chars().last().unwrap().to_ascii_uppercase() {
+		'K' => (&rate[..rate.len() - 1], 1024),
+		'M' => (&rate[..rate.len() - 1], 1024 * 1024),
+		'G' => (&rate[..rate.len() - 1], 1024 * 1024 * 1024),
+		_ => (rate, 1)
+	};
+
+	number.parse::<usize>().ok().map(|n| n * multiplier)
+}
+
+/// Same as io::copy but slowed down to respect the global bandwidth cap
+/// The cap is shared equally between the running transfers
+/// When the scheduled cap changes, the pace is measured again from that moment
+pub fn copy<R: Read + ?Sized, W: Write + ?Sized>(reader: &mut R, writer: &mut W) -> io::Result<u64> {
+	let _guard = ActiveTransfer::new();
+
+	let mut start = Instant::now();
+	let mut buffer = [0; 64 * 1024];
+	let mut written: u64 = 0;
+	let mut written_at_limit: u64 = 0;
+	let mut current_limit = get_limit();
+
+	loop {
+		let len = match reader.read(&mut buffer) {
+			Ok(0) => return Ok(written),
+			Ok(len) => len,
+			Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+			Err(e) => return Err(e)
+		};
+		writer.write_all(&buffer[..len])?;
+		written = written + len as u64;
+
+		let limit = get_limit();
+		if limit != current_limit {
+			current_limit = limit;
+			start = Instant::now();
+			written_at_limit = 0;
+		} else {
+			written_at_limit = written_at_limit + len as u64;
+		}
+
+		if limit > 0 {
+			let share = cmp::max(limit / cmp::max(ACTIVE_TRANSFERS.load(Ordering::SeqCst), 1), 1) as u64;
+			let expected = Duration::from_millis(written_at_limit * 1000 / share);
+			let elapsed = start.elapsed();
+			if expected > elapsed {
+				thread::sleep(expected - elapsed);
+			}
+		}
+	}
+}
+
+struct ActiveTransfer;
+
+impl ActiveTransfer {
+	fn new() -> ActiveTransfer {
+		ACTIVE_TRANSFERS.fetch_add(1, Ordering::SeqCst);
+		ActiveTransfer
+	}
+}
+
+impl Drop for ActiveTransfer {
+	fn drop(&mut self) {
+		ACTIVE_TRANSFERS.fetch_sub(1, Ordering::SeqCst);
+	}
+}
@@ -0,0 +1,328 @@
+use std::cmp;
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+
+use cipher::Key;
+use config::Config;
+use pack;
+use store;
+use transport;
+use transport::Transport;
+
+/// Upload a store object under a temporary name, check its size and hash then rename it
+/// An interrupted upload is resumed from the last byte when the remote allows it
+pub fn upload_object(transport: &dyn Transport, local_path: &Path, remote_path: &Path) -> io::Result<()> {
+	if transport.has_atomic_put() {
+		return transport.put(local_path, remote_path);
+	}
+
+	let part_path = transport::get_part_path(remote_path);
+	let local_size = fs::metadata(local_path)?.len();
+
+	let offset = if transport.supports_resume() {
+		match transport.stat(&part_path)? {
+			Some(ref stat) if !stat.is_dir() && stat.get_size() <= local_size => stat.get_size(),
+			_ => 0
+		}
+	} else {
+		0
+	};
+
+	if offset > 0 {
+		println!("- Resume upload at byte {} of {}", offset, local_size);
+		transport.put_from(local_path, &part_path, offset)?;
+	} else {
+		transport.put(local_path, &part_path)?;
+	}
+
+	match transport.stat(&part_path)? {
+		Some(ref stat) if stat.get_size() == local_size => (),
+		_ => return Err(io::Error::new(io::ErrorKind::Other, format!("Upload of {:?} is truncated", local_path)))
+	};
+
+	if !check_uploaded(transport, local_path, &part_path)? {
+		transport.delete(&part_path)?;
+		return Err(io::Error::new(io::ErrorKind::Other, format!("Upload of {:?} is corrupted", local_path)));
+	}
+	transport.rename(&part_path, remote_path)
+}
+
+/// Compare the hash of an uploaded file with the local file
+/// The hash is computed by the remote when it can, else the file is read back
+fn check_uploaded(transport: &dyn Transport, local_path: &Path, remote_path: &Path) -> io::Result<bool> {
+	if let Some(remote_hash) = transport.get_remote_hash(remote_path)? {
+		return Ok(remote_hash == get_content_version(local_path)?);
+	}
+
+	let name = local_path.file_name().unwrap().to_str().unwrap();
+	let check_path = get_tmp_path(&format!("check-{}", name));
+	transport.get(remote_path, &check_path)?;
+	let remote_version = get_content_version(&check_path);
+	fs::remove_file(&check_path)?;
+	Ok(remote_version? == get_content_version(local_path)?)
+}
+
+/// Download a store object under a temporary name, check its hash and rename it
+/// An interrupted download is resumed from the last byte when the remote allows it
+/// Return false if the downloaded object does not match its hash (it is removed)
+pub fn download_object(transport: &dyn Transport, remote_path: &Path, local_path: &Path, key: Option<&Key>, hash: &str) -> io::Result<bool> {
+	download_verified(transport, remote_path, local_path, &|part_path| store::check_file(part_path, key, hash))
+}
+
+/// Download an immutable file under a temporary name, check it and rename it
+fn download_verified(transport: &dyn Transport, remote_path: &Path, local_path: &Path, check: &dyn Fn(&Path) -> bool) -> io::Result<bool> {
+	let part_path = transport::get_part_path(local_path);
+
+	let offset = match fs::metadata(&part_path) {
+		Ok(ref info) if transport.supports_resume() => info.len(),
+		_ => 0
+	};
+
+	if offset > 0 {
+		println!("- Resume download at byte {}", offset);
+		transport.get_from(remote_path, &part_path, offset)?;
+	} else {
+		transport.get(remote_path, &part_path)?;
+	}
+
+	if check(&part_path) {
+		fs::rename(&part_path, local_path)?;
+		Ok(true)
+	} else {
+		fs::remove_file(&part_path)?;
+		Ok(false)
+	}
+}
+
+/// Download a mutable file (metadata...) under a temporary name and rename it
+/// Such a file can change between two runs, so the download is never resumed
+pub fn download_file(transport: &dyn Transport, remote_path: &Path, local_path: &Path) -> io::Result<()> {
+	let part_path = transport::get_part_path(local_path);
+	transport.get(remote_path, &part_path)?;
+	fs::rename(&part_path, local_path)
+}
+
+/// Upload a mutable file under a temporary name and rename it
+pub fn upload_file(transport: &dyn Transport, local_path: &Path, remote_path: &Path) -> io::Result<()> {
+	if transport.has_atomic_put() {
+		return transport.put(local_path, remote_path);
+	}
+
+	let part_path = transport::get_part_path(remote_path);
+	transport.put(local_path, &part_path)?;
+	transport.rename(&part_path, remote_path)
+}
+
+/// Version of a local file for the remotes without versions: the sha256 of its content
+pub fn get_content_version(path: &Path) -> io::Result<String> {
+	let mut hash_writer = store::HashWriter::new(io::sink());
+	io::copy(&mut File::open(path)?, &mut hash_writer)?;
+	Ok(format!("sha256:{}", hash_writer.get_hash()))
+}
+
+/// On the remotes without versions, the sha256 of a mutable file is kept next to it
+pub fn get_version_sidecar_path(remote_path: &Path) -> PathBuf {
+	let mut filename = remote_path.file_name().unwrap().to_os_string();
+	filename.push(".version");
+	remote_path.with_file_name(filename)
+}
+
+/// Write the sha256 of a file before uploading it to a remote without versions
+/// After a crash between the two uploads the sidecar matches no downloaded copy, so it is checked against the content
+pub fn upload_version_sidecar(transport: &dyn Transport, local_path: &Path, remote_path: &Path) -> io::Result<()> {
+	if transport.get_version(remote_path)?.is_some() {
+		return Ok(());
+	}
+	let sidecar_path = get_tmp_path("version-sidecar");
+	File::create(&sidecar_path)?.write_all(get_content_version(local_path)?.as_bytes())?;
+	let uploaded = upload_file(transport, &sidecar_path, &get_version_sidecar_path(remote_path));
+	fs::remove_file(&sidecar_path)?;
+	uploaded
+}
+
+/// Version of a remote mutable file: its etag when the remote has versions, else the sha256 of its content
+/// The sha256 of the sidecar is trusted when it is the expected version,
+/// the file is downloaded and hashed only when it has changed or has no sidecar
+/// None if the file does not exist
+pub fn get_remote_version(transport: &dyn Transport, remote_path: &Path, expected_version: &Option<String>) -> io::Result<Option<String>> {
+	if transport.stat(remote_path)?.is_none() {
+		return Ok(None);
+	}
+	if let Some(version) = transport.get_version(remote_path)? {
+		return Ok(Some(version));
+	}
+
+	let sidecar_path = get_version_sidecar_path(remote_path);
+	if transport.stat(&sidecar_path)?.is_some() {
+		let local_sidecar_path = get_tmp_path("version-sidecar");
+		transport.get(&sidecar_path, &local_sidecar_path)?;
+		let mut sidecar_version = String::new();
+		File::open(&local_sidecar_path)?.read_to_string(&mut sidecar_version)?;
+		fs::remove_file(&local_sidecar_path)?;
+		if Some(sidecar_version.trim().to_string()) == *expected_version {
+			return Ok(expected_version.clone());
+		}
+	}
+
+	let local_path = get_tmp_path("remote-version");
+	transport.get(remote_path, &local_path)?;
+	let version = get_content_version(&local_path);
+	fs::remove_file(&local_path)?;
+	version.map(Some)
+}
+
+/// Upload a pack then its index, so a remote index always refers to a complete pack
+pub fn upload_pack(transport: &dyn Transport, local_pack_path: &Path, remote_pack_path: &Path) -> io::Result<()> {
+	upload_object(transport, local_pack_path, remote_pack_path)?;
+	upload_file(transport, &pack::get_index_path(local_pack_path), &pack::get_index_path(remote_pack_path))
+}
+
+/// Download a pack, check it against its name, then download its index
+/// Return false if the downloaded pack is corrupted (it is removed)
+pub fn download_pack(transport: &dyn Transport, remote_pack_path: &Path, local_pack_path: &Path) -> io::Result<bool> {
+	let pack_name = local_pack_path.file_name().unwrap().to_str().unwrap().to_string();
+	if !download_verified(transport, remote_pack_path, local_pack_path, &|part_path| pack::check_pack(part_path, &pack_name))? {
+		return Ok(false);
+	}
+	download_file(transport, &pack::get_index_path(remote_pack_path), &pack::get_index_path(local_pack_path))?;
+	Ok(true)
+}
+
+/// Upload the objects (local path, remote path), return the number of objects uploaded and failed
+pub fn upload_objects(config: &Config, objects: Vec<(PathBuf, PathBuf)>) -> (usize, usize) {
+	run_transfers(config, objects, upload_job)
+}
+
+fn upload_job(transport: &dyn Transport, job: &(PathBuf, PathBuf)) -> io::Result<bool> {
+	let &(ref local_path, ref remote_path) = job;
+	println!("- Upload {:?}", remote_path);
+	upload_object(transport, local_path, remote_path)?;
+	Ok(true)
+}
+
+/// Download the objects (remote path, local path, hash), return the number of objects downloaded and failed
+/// The key of an encrypted repository is needed to check the objects
+pub fn download_objects(config: &Config, key: Option<&Key>, objects: Vec<(PathBuf, PathBuf, String)>) -> (usize, usize) {
+	let jobs = objects.into_iter()
+		.map(|(remote_path, local_path, hash)| (remote_path, local_path, hash, key.cloned()))
+		.collect();
+	run_transfers(config, jobs, download_job)
+}
+
+fn download_job(transport: &dyn Transport, job: &(PathBuf, PathBuf, String, Option<Key>)) -> io::Result<bool> {
+	let &(ref remote_path, ref local_path, ref hash, ref key) = job;
+	println!("- Download {:?}", remote_path);
+	let downloaded = download_object(transport, remote_path, local_path, key.as_ref(), hash)?;
+	if !downloaded {
+		println!("- CORRUPTED object downloaded, removed {}", hash);
+	}
+	Ok(downloaded)
+}
+
+/// Run the jobs on config.get_nb_transfers() threads, each thread opens its own connection to the remote
+/// Return the number of jobs which succeeded and failed
+fn run_transfers<J: Send + 'static>(config: &Config, jobs: Vec<J>, work: fn(&dyn Transport, &J) -> io::Result<bool>) -> (usize, usize) {
+	if jobs.is_empty() {
+		return (0, 0);
+	}
+
+	let nb_workers = cmp::max(1, cmp::min(config.get_nb_transfers(), jobs.len()));
+	let queue = Arc::new(Mutex::new(jobs));
+
+	let mut workers = Vec::new();
+	for _ in 0..nb_workers {
+		let queue = queue.clone();
+		let config = config.clone();
+		workers.push(thread::spawn(move || {
+			let transport = transport::open_transport(&config);
+			let mut nb_succeeded : usize = 0;
+			let mut nb_failed : usize = 0;
+			loop {
+				let job = queue.lock().unwrap().pop();
+				match job {
+					Some(job) => match work(&*transport, &job) {
+						Ok(true) => nb_succeeded = nb_succeeded + 1,
+						Ok(false) => nb_failed = nb_failed + 1,
+						Err(e) => {
+							println!("- Transfer failed: {}", e);
+							nb_failed = nb_failed + 1
+						}
+					},
+					None => break
+				}
+			}
+			(nb_succeeded, nb_failed)
+		}));
+	}
+
+	workers.into_iter()
+		.map(|worker| worker.join().expect("A transfer thread has panicked"))
+		.fold((0, 0), |(s1, f1), (s2, f2)| (s1 + s2, f1 + f2))
+}
+
+/// Layout version of the remote store, None if the remote has no version file
+pub fn read_remote_store_version(transport: &dyn Transport) -> io::Result<Option<u32>> {
+	let remote_path = Path::new(store::STORE_VERSION_FILE);
+	if transport.stat(remote_path)?.is_none() {
+		return Ok(None);
+	}
+
+	let local_path = get_tmp_path(store::STORE_VERSION_FILE);
+	transport.get(remote_path, &local_path)?;
+	let mut version = String::new();
+	File::open(&local_path)?.read_to_string(&mut version)?;
+	fs::remove_file(&local_path)?;
+
+	version.trim().parse::<u32>().map(Some).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid remote store version file"))
+}
+
+pub fn write_remote_store_version(transport: &dyn Transport, version: u32) -> io::Result<()> {
+	let local_path = get_tmp_path(store::STORE_VERSION_FILE);
+	File::create(&local_path)?.write_all(version.to_string().as_bytes())?;
+	upload_file(transport, &local_path, Path::new(store::STORE_VERSION_FILE))?;
+	fs::remove_file(&local_path)
+}
+
+/// Move every remote object to its sharded path, then mark the remote as sharded
+/// Can be run again if it has been interrupted
+pub fn migrate_remote_store(transport: &dyn Transport) -> io::Result<()> {
+	let mut created_dirs : HashSet<PathBuf> = HashSet::new();
+	let mut nb_moved = 0;
+
+	for (path, _) in transport::list_remote_files(transport, Path::new(""))? {
+		let hash = path.file_name().unwrap().to_str().unwrap().to_string();
+		if !store::is_object_name(&hash) {
+			continue;
+		}
+
+		let sharded_path = store::get_object_relative_path(store::SHARDED_LAYOUT, &hash);
+		if path != sharded_path {
+			let parent = sharded_path.parent().unwrap().to_path_buf();
+			if !created_dirs.contains(&parent) {
+				transport::create_remote_dirs(transport, &parent)?;
+				created_dirs.insert(parent);
+			}
+			transport.rename(&path, &sharded_path)?;
+			nb_moved = nb_moved + 1;
+		}
+	}
+
+	write_remote_store_version(transport, store::SHARDED_LAYOUT)?;
+	println!("{} remote objects moved to the sharded layout", nb_moved);
+	Ok(())
+}
+
+pub fn get_tmp_path(name: &str) -> PathBuf {
+	env::temp_dir().join(format!("yabs-{}-{}", process::id(), name))
+}
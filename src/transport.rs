@@ -0,0 +1,208 @@
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+use config::Config;
+use localengine::LocalTransport;
+use s3engine::S3Transport;
+use sftpengine::SftpTransport;
+use sshengine::SshTransport;
+
+/// Access to a remote repository
+/// All the remote paths are relative to the root of the remote
+pub trait Transport {
+	/// List the entries of a remote directory
+	fn list(&self, remote_path: &Path) -> io::Result<Vec<(PathBuf, RemoteStat)>>;
+	/// Download a remote file to a local file
+	fn get(&self, remote_path: &Path, local_path: &Path) -> io::Result<()>;
+	/// Upload a local file to a remote file
+	fn put(&self, local_path: &Path, remote_path: &Path) -> io::Result<()>;
+	fn delete(&self, remote_path: &Path) -> io::Result<()>;
+	/// None if the remote file does not exist
+	fn stat(&self, remote_path: &Path) -> io::Result<Option<RemoteStat>>;
+	/// Atomically replace the destination by the source
+	fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+	/// Create a remote directory (its parent must exist)
+	fn mkdir(&self, remote_path: &Path) -> io::Result<()>;
+
+	/// sha256 of a remote file computed on the remote side ("sha256:<hex>"), None if the backend cannot compute it
+	fn get_remote_hash(&self, _remote_path: &Path) -> io::Result<Option<String>> {
+		Ok(None)
+	}
+	/// Opaque version of a remote file (etag...), None if the file does not exist or the backend cannot tell
+	fn get_version(&self, _remote_path: &Path) -> io::Result<Option<String>> {
+		Ok(None)
+	}
+	/// Upload only if the remote file is still at the expected version (None: the file must not exist yet)
+	/// Return false if the remote file has changed. Backends without conditional writes always upload (through a temporary file)
+	fn put_if_version(&self, local_path: &Path, remote_path: &Path, _expected_version: Option<String>) -> io::Result<bool> {
+		if self.has_atomic_put() {
+			self.put(local_path, remote_path)?;
+		} else {
+			let part_path = get_part_path(remote_path);
+			self.put(local_path, &part_path)?;
+			self.rename(&part_path, remote_path)?;
+		}
+		Ok(true)
+	}
+
+	/// Delete the remote file only if it is still at the expected version (given by get_version), false if it has changed
+	fn delete_if_version(&self, _remote_path: &Path, _expected_version: String) -> io::Result<bool> {
+		Err(io::Error::new(io::ErrorKind::Other, "This remote cannot delete a file conditionally"))
+	}
+
+	/// Create the remote file only if it does not exist yet, atomically: two writers cannot both succeed
+	/// Return false if the file already exists
+	fn put_exclusive(&self, _local_path: &Path, _remote_path: &Path) -> io::Result<bool> {
+		Err(io::Error::new(io::ErrorKind::Other, "This remote cannot create a file exclusively"))
+	}
+
+	/// True if a put never leaves a partial file under the final name (no need of a temporary file)
+	fn has_atomic_put(&self) -> bool {
+		false
+	}
+	/// True if put_from and get_from can continue an interrupted transfer
+	fn supports_resume(&self) -> bool {
+		false
+	}
+	/// Upload the local file from offset, the remote file already contains the first offset bytes
+	fn put_from(&self, local_path: &Path, remote_path: &Path, offset: u64) -> io::Result<()> {
+		if offset == 0 {
+			self.put(local_path, remote_path)
+		} else {
+			Err(io::Error::new(io::ErrorKind::Other, "This remote cannot resume uploads"))
+		}
+	}
+	/// Download the remote file from offset, the local file already contains the first offset bytes
+	fn get_from(&self, remote_path: &Path, local_path: &Path, offset: u64) -> io::Result<()> {
+		if offset == 0 {
+			self.get(remote_path, local_path)
+		} else {
+			Err(io::Error::new(io::ErrorKind::Other, "This remote cannot resume downloads"))
+		}
+	}
+}
+
+/// Create the remote directory and its missing parents
+pub fn create_remote_dirs(transport: &dyn Transport, remote_path: &Path) -> io::Result<()> {
+	if remote_path.as_os_str().is_empty() {
+		return Ok(());
+	}
+
+	match transport.stat(remote_path)? {
+		Some(ref stat) if stat.is_dir() => Ok(()),
+		Some(_) => Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("Remote {:?} is a file", remote_path))),
+		None => {
+			if let Some(parent) = remote_path.parent() {
+				create_remote_dirs(transport, parent)?;
+			}
+			transport.mkdir(remote_path)
+		}
+	}
+}
+
+/// List all the files under a remote directory (recursively)
+pub fn list_remote_files(transport: &dyn Transport, remote_path: &Path) -> io::Result<Vec<(PathBuf, RemoteStat)>> {
+	let mut files : Vec<(PathBuf, RemoteStat)> = Vec::new();
+
+	for (path, stat) in transport.list(remote_path)? {
+		if stat.is_dir() {
+			files.extend(list_remote_files(transport, &path)?);
+		} else {
+			files.push((path, stat));
+		}
+	}
+
+	Ok(files)
+}
+
+/// Temporary name used while a file is transferred
+pub fn get_part_path(path: &Path) -> PathBuf {
+	let mut filename = path.file_name().unwrap().to_os_string();
+	filename.push(".part");
+	path.with_file_name(filename)
+}
+
+#[derive(Debug, Clone)]
+pub struct RemoteStat {
+	size: u64,
+	is_dir: bool
+}
+
+impl RemoteStat {
+	pub fn new(size: u64, is_dir: bool) -> RemoteStat {
+		RemoteStat {size: size, is_dir: is_dir}
+	}
+	pub fn get_size(&self) -> u64 {
+		self.size
+	}
+	pub fn is_dir(&self) -> bool {
+		self.is_dir
+	}
+}
+
+/// scheme://[user@]host[:port]/path
+/// For s3 the path is /bucket/prefix, s3 uses https and s3+http plain http (local test servers)
+#[derive(Debug)]
+pub struct RemoteUrl {
+	scheme: String,
+	user: Option<String>,
+	address: String,
+	path: PathBuf
+}
+
+impl RemoteUrl {
+	pub fn parse(url: &str) -> RemoteUrl {
+		let separator = url.find("://").expect("Remote url must look like scheme://host/path");
+		let scheme = url[..separator].to_string();
+		let rest = &url[separator + 3..];
+
+		let (authority, path) = match rest.find('/') {
+			Some(i) => (&rest[..i], &rest[i..]),
+			None => (rest, "/")
+		};
+
+		let (user, host) = match authority.rfind('@') {
+			Some(i) => (Some(authority[..i].to_string()), &authority[i + 1..]),
+			None => (None, authority)
+		};
+
+		let address = if host.is_empty() || host.contains(':') {
+			host.to_string()
+		} else if scheme == "s3" {
+			format!("{}:443", host)
+		} else if scheme == "s3+http" {
+			format!("{}:80", host)
+		} else {
+			format!("{}:22", host)
+		};
+
+		RemoteUrl {scheme: scheme, user: user, address: address, path: PathBuf::from(path)}
+	}
+	pub fn get_scheme(&self) -> &str {
+		&self.scheme
+	}
+	pub fn get_user(&self) -> Option<String> {
+		self.user.clone()
+	}
+	pub fn get_address(&self) -> String {
+		self.address.clone()
+	}
+	pub fn get_path(&self) -> PathBuf {
+		self.path.clone()
+	}
+}
+
+/// Open the remote selected by the url of the config
+pub fn open_transport(config: &Config) -> Box<dyn Transport> {
+	let url = RemoteUrl::parse(&config.get_remote_url());
+	let user = url.get_user().unwrap_or(config.get_ssh_user());
+
+	match url.get_scheme() {
+		"file" => Box::new(LocalTransport::new(&url.get_path())),
+		"sftp" => Box::new(SftpTransport::connect(&url.get_address(), &user, &config.get_ssh_password(), &url.get_path())),
+		"s3" | "s3+http" => Box::new(S3Transport::new(&url.get_address(), url.get_scheme() == "s3", &url.get_path(), &config.get_s3_region(), &config.get_s3_access_key(), &config.get_s3_secret_key())),
+		"ssh" | "scp" => Box::new(SshTransport::connect(&url.get_address(), &user, &config.get_ssh_password(), &url.get_path())),
+		scheme => panic!("Unknown remote scheme {}", scheme)
+	}
+}
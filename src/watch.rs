@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+use inotify::EventMask;
+use inotify::Inotify;
+use inotify::WatchDescriptor;
+use inotify::WatchMask;
+
+use config::Source;
+use workingdirectory;
+
+/// Events of the files to commit: written and closed, moved in, attributes (touch changes the timestamp)
+/// A deleted file is not an event, the metadata keeps the files which are not in the working directory
+fn get_watch_mask() -> WatchMask {
+	WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::CREATE | WatchMask::ATTRIB
+}
+
+/// inotify watches of every directory of the sources, a watch is added to each new directory
+pub struct Watcher {
+	inotify: Inotify,
+	/// Directory of each watch, with the index of its source
+	directories: HashMap<WatchDescriptor, (usize, PathBuf)>,
+	sources: Vec<Source>,
+	buffer: Vec<u8>
+}
+
+impl Watcher {
+	pub fn new(sources: Vec<Source>) -> io::Result<Watcher> {
+		let mut watcher = Watcher {inotify: Inotify::init()?, directories: HashMap::new(), sources: sources, buffer: vec![0; 64 * 1024]};
+		for i in 0..watcher.sources.len() {
+			let root = watcher.sources[i].get_root();
+			if root.is_dir() {
+				//the files already there are committed by the first full scan
+				let mut existing = HashSet::new();
+				watcher.add_directory(i, &root, &mut existing)?;
+			}
+		}
+		println!("Watching {} directories", watcher.directories.len());
+		Ok(watcher)
+	}
+
+	/// Watch a directory and its subdirectories, the files already in them are added to changed
+	/// (a directory created or moved in is seen after its files)
+	/// A directory removed before it is watched is already gone, it is skipped
+	fn add_directory(&mut self, source: usize, directory: &Path, changed: &mut HashSet<String>) -> io::Result<()> {
+		let root = self.sources[source].get_root();
+		if directory != root.as_path() && self.sources[source].is_ignored(directory.strip_prefix(&root).unwrap()) {
+			return Ok(());
+		}
+
+		let wd = match self.inotify.add_watch(directory, get_watch_mask()) {
+			Ok(wd) => wd,
+			Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+			Err(e) => return Err(e)
+		};
+		self.directories.insert(wd, (source, directory.to_path_buf()));
+
+		let entries = match fs::read_dir(directory) {
+			Ok(entries) => entries,
+			Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+			Err(e) => return Err(e)
+		};
+		for entry in entries {
+			let path = match entry {
+				Ok(entry) => entry.path(),
+				Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+				Err(e) => return Err(e)
+			};
+			if path.is_dir() {
+				self.add_directory(source, &path, changed)?;
+			} else if let Some(filename) = get_filename(&self.sources[source], &path) {
+				changed.insert(filename);
+			}
+		}
+		Ok(())
+	}
+
+	/// Add the files changed since the last call to changed, without waiting
+	/// Return true if events were lost (queue overflow), only a full rescan can find the changes
+	pub fn read_changes(&mut self, changed: &mut HashSet<String>) -> io::Result<bool> {
+		let mut overflow = false;
+		let mut new_directories : Vec<(usize, PathBuf)> = Vec::new();
+		{
+			let events = self.inotify.read_events(&mut self.buffer)?;
+			for event in events {
+				if event.mask.contains(EventMask::Q_OVERFLOW) {
+					overflow = true;
+					continue;
+				}
+				if event.mask.contains(EventMask::IGNORED) {
+					//the directory has been deleted or moved away
+					self.directories.remove(&event.wd);
+					continue;
+				}
+				let (source, directory) = match self.directories.get(&event.wd) {
+					Some(&(source, ref directory)) => (source, directory.clone()),
+					None => continue
+				};
+				let path = match event.name {
+					Some(name) => directory.join(name),
+					None => continue
+				};
+
+				if event.mask.contains(EventMask::ISDIR) {
+					if event.mask.intersects(EventMask::CREATE | EventMask::MOVED_TO) {
+						new_directories.push((source, path));
+					}
+				} else if let Some(filename) = get_filename(&self.sources[source], &path) {
+					changed.insert(filename);
+				}
+			}
+		}
+
+		for (source, directory) in new_directories {
+			self.add_directory(source, &directory, changed)?;
+		}
+		Ok(overflow)
+	}
+}
+
+/// Name of the file in the metadata, None if it is ignored
+fn get_filename(source: &Source, path: &Path) -> Option<String> {
+	let relative = path.strip_prefix(source.get_root()).ok()?;
+	let relative_str = relative.to_str()?;
+	if source.is_ignored(relative) || relative_str.ends_with(workingdirectory::EXTRACT_SUFFIX) {
+		return None;
+	}
+	Some(format!("{}/{}", source.get_name(), relative_str))
+}
@@ -1,40 +1,96 @@
 use std::collections::HashMap;
+use std::collections::HashSet;
 use filetime::FileTime;
 use std::path;
 use std::path::Path;
+use std::path::PathBuf;
 use std::fs;
 
+use config::Source;
 use model::MetaData;
 
-pub fn read_working_directory(root: &Path) -> HashMap<String, MetaData> {
+/// Suffix of the file written while a file is extracted, renamed over the file once complete
+pub const EXTRACT_SUFFIX: &'static str = ".yabs-tmp";
+
+/// Files of all the sources, named <source name>/<path in the source root>
+pub fn read_sources(sources: &[Source]) -> HashMap<String, MetaData> {
 	let mut hierarchy : HashMap<String, MetaData> = HashMap::new();
-	read_folder_rec(root, &mut hierarchy);
+	for source in sources {
+		let root = source.get_root();
+		if !root.is_dir() {
+			println!("Root {:?} of source {} not found", root, source.get_name());
+			continue;
+		}
+		read_folder_rec(source, &root, &root, &mut hierarchy);
+	}
 	hierarchy
 }
 
-fn read_folder_rec(root: &Path, hierarchy: &mut HashMap<String, MetaData>) {
-	let paths = fs::read_dir(root).unwrap();
+/// Only the given files, without walking the sources (the files found by watch)
+/// The files which do not exist anymore or are ignored are left out
+pub fn read_files(sources: &[Source], filenames: &HashSet<String>) -> HashMap<String, MetaData> {
+	let mut hierarchy : HashMap<String, MetaData> = HashMap::new();
+	for filename in filenames {
+		let source = match get_source(sources, filename) {
+			Some(source) => source,
+			None => continue
+		};
+		let relative = Path::new(&filename[source.get_name().len() + 1..]);
+		if source.is_ignored(relative) || filename.ends_with(EXTRACT_SUFFIX) {
+			continue;
+		}
+		if let Ok(info) = fs::metadata(source.get_root().join(relative)) {
+			if info.is_file() {
+				let last_change = FileTime::from_last_modification_time(&info).seconds_relative_to_1970();
+				hierarchy.insert(filename.clone(), MetaData::new_without_hash(last_change, info.len()));
+			}
+		}
+	}
+	hierarchy
+}
+
+/// Path on the disk of a file of the metadata, None if no source has its prefix
+pub fn get_file_path(sources: &[Source], filename: &str) -> Option<PathBuf> {
+	get_source(sources, filename).map(|s| s.get_root().join(&filename[s.get_name().len() + 1..]))
+}
+
+fn get_source<'a>(sources: &'a [Source], filename: &str) -> Option<&'a Source> {
+	sources.iter()
+		.filter(|s| filename.starts_with(s.get_name()) && filename[s.get_name().len()..].starts_with('/'))
+		.max_by_key(|s| s.get_name().len())
+}
+
+fn read_folder_rec(source: &Source, root: &Path, folder: &Path, hierarchy: &mut HashMap<String, MetaData>) {
+	let paths = fs::read_dir(folder).unwrap();
 
 	for entry in paths {
 		let entry = entry.unwrap();
 		let path_buf: path::PathBuf = entry.path();
 		let path = path_buf.as_path();
+		let relative = path.strip_prefix(root).unwrap();
+
+		if source.is_ignored(relative) {
+			continue;
+		}
 
 		let info = entry.metadata().unwrap();
 
 		if info.is_dir() {
-			read_folder_rec(path, hierarchy);
+			read_folder_rec(source, root, path, hierarchy);
 		} else if info.is_file() {
 
-			let path_str = path.to_str().expect("cannot convert path to utf8 string").to_string();
+			let relative_str = relative.to_str().expect("cannot convert path to utf8 string");
+			if relative_str.ends_with(EXTRACT_SUFFIX) {
+				continue;
+			}
 
 			let last_change = FileTime::from_last_modification_time(&info).seconds_relative_to_1970();
 
 			let metadata = MetaData::new_without_hash(last_change, info.len());
 
-			hierarchy.insert(path_str, metadata);
+			hierarchy.insert(format!("{}/{}", source.get_name(), relative_str), metadata);
 		} else {
 
 		}
     }    
-}
\ No newline at end of file
+}